@@ -1,121 +1,991 @@
 use anyhow::{anyhow, Result};
-use chrono::Utc;
+use chrono::{DateTime, Local, Utc};
 use crossterm::{
     cursor,
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    event::{self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyModifiers},
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen, SetTitle},
 };
 use portable_pty::{native_pty_system, CommandBuilder, PtySize};
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::{Line, Span, Text},
-    widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
+    text::{Line, Span},
+    widgets::{
+        block::{Position, Title},
+        Block, Borders, Clear, List, ListItem, Padding, Paragraph, Wrap,
+    },
     Frame, Terminal,
 };
 use ratatui::backend::CrosstermBackend;
-use rusqlite::{params, Connection};
-use serde::Deserialize;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env,
     fs,
     io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    ops::Range,
     path::{Path, PathBuf},
-    sync::mpsc::{self, Receiver, Sender},
-    sync::{Arc, Mutex},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    sync::{Arc, Condvar, Mutex, OnceLock, Weak},
     thread,
     time::{Duration, Instant},
 };
 use uuid::Uuid;
 
-fn main() -> Result<()> {
-    let mut args: Vec<String> = env::args().collect();
-    let claude_args = if let Some(pos) = args.iter().position(|a| a == "--") {
-        args.split_off(pos + 1)
-    } else {
-        Vec::new()
+/// Debug log file, opened by `init_debug_log` when `CCWB_LOG`/`RUST_LOG`
+/// asks for it. Unset means logging is off and `debug_log!` is a no-op.
+static DEBUG_LOG: OnceLock<Mutex<fs::File>> = OnceLock::new();
+
+/// Appends a timestamped line to the debug log, if one is open.
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        if let Some(log) = DEBUG_LOG.get() {
+            write_debug_line(log, format_args!($($arg)*));
+        }
     };
+}
+
+fn main() -> Result<()> {
+    let cli = CliArgs::parse(env::args().collect())?;
 
-    let workspace = env::current_dir()?;
-    let config = Config::load(&workspace)?;
+    let workspace = match &cli.cwd {
+        Some(dir) => dir
+            .canonicalize()
+            .map_err(|e| anyhow!("--cwd {}: {}", dir.display(), e))?,
+        None => env::current_dir()?,
+    };
+    let mut config = Config::load(&workspace, cli.config.as_deref())?;
+    config.offline |= cli.offline;
+    let claude_args = [config.claude_args_prefix.as_slice(), cli.claude_args.as_slice()].concat();
 
-    let data_dir = workspace.join(".cc-workbench");
+    let data_dir = config
+        .data_dir
+        .clone()
+        .unwrap_or_else(|| workspace.join(".cc-workbench"));
     fs::create_dir_all(&data_dir)?;
 
+    // Every write to the database, snapshots repo or backups needs this
+    // lock, so it comes before any of them. Without it --inspect,
+    // --list-snapshots and --export-json only read.
+    let lock_path = workspace_lock_path(&data_dir, &workspace, config.data_dir.is_some());
+    if let Some(dir) = lock_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let lock = WorkspaceLock::acquire(&lock_path).map_err(|err| anyhow!("{} ({})", err, lock_path.display()));
+    let require_lock = |what: &str| match &lock {
+        Ok(_) => Ok(()),
+        Err(err) => Err(anyhow!("{} needs the workspace: {}; quit it first", what, err)),
+    };
+
     let db_path = data_dir.join("ccwb.sqlite");
-    let mut db = Database::new(&db_path)?;
-    let workspace_id = db.ensure_workspace(&workspace)?;
-    let session_id = db.create_session(&workspace_id)?;
+    let (mut db, workspace_id) = if lock.is_ok() {
+        let mut db = Database::new(&db_path)?;
+        let id = db.ensure_workspace(&workspace)?;
+        (db, id)
+    } else {
+        // The lock holder has set the database up; leave it as it is.
+        let db = Database::open_existing(&db_path)?;
+        let id = db
+            .find_workspace(&workspace)?
+            .ok_or_else(|| anyhow!("{} is not recorded in {} yet", workspace.display(), db_path.display()))?;
+        (db, id)
+    };
+    // A relocated data dir may be shared by several projects, so each one
+    // gets its own snapshots repo and backups keyed by workspace id.
+    let workspace_data_dir = if config.data_dir.is_some() {
+        data_dir.join("workspaces").join(&workspace_id)
+    } else {
+        data_dir.clone()
+    };
+    fs::create_dir_all(&workspace_data_dir)?;
+    if debug_log_requested(env::var("CCWB_LOG").ok().as_deref(), env::var("RUST_LOG").ok().as_deref()) {
+        init_debug_log(&workspace_data_dir.join("debug.log"))?;
+        debug_log!("start workspace={} claude_cmd={}", workspace.display(), config.claude_cmd);
+    }
+    // Without a working git the wrapper still runs, just without snapshots;
+    // `snapshots_off` says why.
+    let (mut snapshot_manager, mut snapshots_off) = if !git_available() {
+        (SnapshotManager::detached(&workspace, &workspace_data_dir), Some("git unavailable".to_string()))
+    } else if lock.is_err() {
+        // Read-only: use the repo as the lock holder made it.
+        let manager = SnapshotManager::detached(&workspace, &workspace_data_dir);
+        let reason = (!manager.git_dir.exists()).then(|| "no snapshots repo yet".to_string());
+        (manager, reason)
+    } else {
+        match SnapshotManager::new(&workspace, &workspace_data_dir) {
+            Ok(manager) => (manager, None),
+            Err(err) => {
+                debug_log!("snapshots off: {:#}", err);
+                let reason = git_error_summary(&err.to_string());
+                (SnapshotManager::detached(&workspace, &workspace_data_dir), Some(reason))
+            }
+        }
+    };
+    let mut snapshots_enabled = snapshots_off.is_none();
+    snapshot_manager.exclude_path(&data_dir);
+    snapshot_manager.set_author(&config.snapshot_author_name, &config.snapshot_author_email);
+    // Printed for the one-shot modes, and shown again once the TUI is up.
+    let mut prune_errors = Vec::new();
+    if let (Some(keep), Ok(_)) = (config.backup_retention, &lock) {
+        prune_errors = snapshot_manager.prune_backups(keep).1;
+        if snapshots_enabled {
+            prune_errors.extend(snapshot_manager.prune_worktrees(keep).1);
+        }
+    }
+    for err in &prune_errors {
+        eprintln!("cc-workbench: {}", err);
+    }
+
+    if let Some(cutoff) = &cli.prune {
+        require_lock("--prune")?;
+        let removed = db.prune_sessions(&workspace_id, cutoff)?;
+        match &snapshots_off {
+            None => {
+                let freed = snapshot_manager.gc(&db.session_ids(&workspace_id)?, &db.snapshot_commits(&workspace_id)?)?;
+                println!("Pruned {} session(s); freed {} in the snapshots repo.", removed, format_size(freed));
+            }
+            Some(reason) => {
+                println!("Pruned {} session(s); snapshots repo left as is ({}).", removed, reason);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(rev) = &cli.restore {
+        if let Some(reason) = &snapshots_off {
+            return Err(anyhow!("--restore needs snapshots, which are off: {}", reason));
+        }
+        require_lock("--restore")?;
+        let commit = snapshot_manager.resolve_commit(rev)?;
+        let plan = snapshot_manager.restore_plan(&commit)?;
+        // Every file the restore touches is copied to the backup before the
+        // checkout; if any copy fails we stop with the workspace untouched.
+        snapshot_manager.apply_restore(&plan)?;
+        if let Some(keep) = config.backup_retention {
+            for err in snapshot_manager.prune_backups(keep).1 {
+                eprintln!("cc-workbench: {}", err);
+            }
+        }
+        println!("Restored {}; backup in {}", commit, plan.backup_dir.display());
+        return Ok(());
+    }
+
+    if cli.list_snapshots {
+        if let Some(reason) = &snapshots_off {
+            return Err(anyhow!("--list-snapshots needs snapshots, which are off: {}", reason));
+        }
+        let rows = db.list_snapshots(&workspace_id)?;
+        print!("{}", snapshot_listing(&rows, &snapshot_manager.list_commits()?));
+        return Ok(());
+    }
+
+    if let Some(path) = &cli.export_json {
+        let session_id = match &cli.session {
+            Some(id) => id.clone(),
+            None => db
+                .latest_session(&workspace_id)?
+                .ok_or_else(|| anyhow!("no sessions recorded for {}", workspace.display()))?,
+        };
+        let transcript = db.load_transcript(&session_id)?;
+        fs::write(path, serde_json::to_string_pretty(&transcript)?)?;
+        println!("Exported {} turn(s) of session {} to {}", transcript.turns.len(), session_id, path.display());
+        return Ok(());
+    }
+
+    let claude_version = if cli.skip_health_check {
+        None
+    } else {
+        let version = probe_claude(&config.claude_cmd, &config.claude_args_prefix, &config.env, HEALTH_CHECK_TIMEOUT)
+            .map_err(|err| anyhow!("{} (set CCWB_CLAUDE_CMD, or pass --skip-health-check)", err))?;
+        Some(version)
+    };
+
+    if cli.headless {
+        require_lock("--headless")?;
+        let until = match &cli.until {
+            Some(source) => Some(Pattern::new(source).map_err(|e| anyhow!("--until: {}", e))?),
+            None => None,
+        };
+        let timeout = cli.timeout.map(Duration::from_secs).unwrap_or(HEADLESS_TIMEOUT);
+        let (output_tx, output_rx) = mpsc::channel::<OutputChunk>();
+        let mut pty = PtyProcess::spawn(
+            &config.claude_cmd,
+            &claude_args,
+            &config.env,
+            &workspace,
+            config.output_encoding,
+            false,
+            output_tx,
+        )?;
+        let mut stdout = io::stdout();
+        let code = run_headless(
+            &mut pty,
+            &output_rx,
+            cli.initial_prompt.clone(),
+            until.as_ref(),
+            timeout,
+            &mut stdout,
+        )?;
+        stdout.flush()?;
+        std::process::exit(code);
+    }
 
-    let snapshot_manager = SnapshotManager::new(&workspace, &data_dir)?;
+    // A second instance may only look: with --inspect it starts read-only
+    // and leaves the lock to the first one.
+    let (_lock, lock_warning) = match lock {
+        Ok(lock) => (Some(lock), None),
+        Err(err) if cli.inspect => (None, Some(format!("{}; read-only until it exits", err))),
+        Err(err) => return Err(anyhow!("{}; quit it first, or pass --inspect to open read-only", err)),
+    };
+
+    // A read-only instance records nothing, not even an empty session that
+    // --export-json would then pick as the latest.
+    let session_id = match &_lock {
+        Some(_) => db.create_session(&workspace_id)?,
+        None => String::new(),
+    };
+    if snapshots_enabled && _lock.is_some() {
+        if let Err(err) = snapshot_manager.start_session(&session_id) {
+            debug_log!("snapshots off: {:#}", err);
+            snapshots_off = Some(git_error_summary(&err.to_string()));
+            snapshots_enabled = false;
+        }
+    }
 
     let (output_tx, output_rx) = mpsc::channel::<OutputChunk>();
     let (snapshot_tx, snapshot_rx) = mpsc::channel::<SnapshotResult>();
     let (snapshot_job_tx, snapshot_job_rx) = mpsc::channel::<SnapshotJob>();
 
-    spawn_snapshot_worker(snapshot_manager.clone(), snapshot_job_rx, snapshot_tx);
+    if snapshots_enabled {
+        spawn_snapshot_worker(snapshot_manager.clone(), snapshot_job_rx, snapshot_tx);
+        spawn_repo_stats_poller(snapshot_manager.clone());
+    }
+
+    let mut pty = PtyProcess::spawn(
+        &config.claude_cmd,
+        &claude_args,
+        &config.env,
+        &workspace,
+        config.output_encoding,
+        config.separate_stderr,
+        output_tx,
+    )?;
 
-    let mut pty = PtyProcess::spawn(&config.claude_cmd, &claude_args, output_tx)?;
+    let metrics = match config.metrics_port {
+        Some(port) => {
+            let listener = TcpListener::bind((config.metrics_bind.as_str(), port))
+                .map_err(|e| anyhow!("metrics endpoint {}:{}: {}", config.metrics_bind, port, e))?;
+            let shared = Arc::new(Mutex::new(MetricsSnapshot::default()));
+            spawn_metrics_server(listener, Arc::clone(&shared));
+            Some(shared)
+        }
+        None => None,
+    };
 
+    let ui_state_path = workspace_data_dir.join("ui_state.json");
     let mut app = App::new(config, session_id, snapshot_manager, snapshot_job_tx);
+    app.apply_ui_state(&UiState::load(&ui_state_path));
+    app.inspect = cli.inspect;
+    if let Some(warning) = lock_warning {
+        app.locked_out = true;
+        app.push_notice(warning, Color::Yellow);
+    }
+    for err in prune_errors {
+        app.push_notice(err, Color::Yellow);
+    }
+    app.config_path = cli
+        .config
+        .clone()
+        .unwrap_or_else(|| workspace.join(".cc-workbench").join("config.json"));
+    app.metrics = metrics;
+    if app.config.separate_stderr && !pty.stderr_separated {
+        app.push_notice(
+            "separate_stderr: no named pipe available here; stderr stays in the output".to_string(),
+            Color::DarkGray,
+        );
+    }
+    if app.config.session_log && !app.locked_out {
+        app.start_session_log(&workspace_data_dir.join("logs"));
+    }
+    app.snapshots_enabled = snapshots_enabled;
+    if let Some(reason) = snapshots_off {
+        app.push_notice(format!("Snapshots off: {}", reason), Color::Yellow);
+        app.snapshots_off = reason;
+    }
+    app.claude_version = claude_version;
+    app.pending_prompt = cli.initial_prompt.clone();
 
-    let mut terminal = setup_terminal()?;
-    let res = run_app(&mut terminal, &mut pty, &mut db, &mut app, output_rx, snapshot_rx);
+    let mut terminal = setup_terminal(&app.config.title)?;
+    let res = run_app(&mut terminal, &mut pty, &mut db, &mut app, output_rx, &snapshot_rx);
+    // Let the worker finish what it was doing before the terminal comes back.
+    let drained = app.drain_snapshots(&mut db, &snapshot_rx, SHUTDOWN_TIMEOUT);
     restore_terminal(&mut terminal)?;
+    drained?;
+    if res.is_ok() && !app.locked_out {
+        if let Err(err) = app.ui_state().save(&ui_state_path) {
+            eprintln!("cc-workbench: could not save {}: {}", ui_state_path.display(), err);
+        }
+    }
+    // The worker has been drained, so no post-turn snapshot may be queued
+    // now: it could be cut off halfway through a commit.
+    app.snapshots_enabled = false;
+    app.finalize_last_turn(&mut db)?;
+    if !app.messages.is_empty() {
+        let (input, output) = db.session_token_totals(&app.session_id)?;
+        eprintln!("cc-workbench: ~{} input / ~{} output tokens this session", input, output);
+    }
     res
 }
 
+/// Flags understood by the wrapper itself. Everything after `--` is passed
+/// through to Claude untouched.
+struct CliArgs {
+    claude_args: Vec<String>,
+    prune: Option<PruneCutoff>,
+    inspect: bool,
+    cwd: Option<PathBuf>,
+    export_json: Option<PathBuf>,
+    /// Session for `--export-json`; the workspace's latest when unset.
+    session: Option<String>,
+    skip_health_check: bool,
+    /// Typed into Claude once it has started; see `prompt_ready`.
+    initial_prompt: Option<String>,
+    /// Run without the TUI, streaming output to stdout; see `run_headless`.
+    headless: bool,
+    until: Option<String>,
+    /// Seconds, for `--headless`.
+    timeout: Option<u64>,
+    /// Replaces the home and workspace config files.
+    config: Option<PathBuf>,
+    list_snapshots: bool,
+    /// Snapshot to restore without starting the TUI.
+    restore: Option<String>,
+    offline: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum PruneCutoff {
+    OlderThanDays(i64),
+    KeepNewest(usize),
+}
+
+impl CliArgs {
+    fn parse(mut args: Vec<String>) -> Result<Self> {
+        let claude_args = if let Some(pos) = args.iter().position(|a| a == "--") {
+            let rest = args.split_off(pos + 1);
+            args.pop();
+            rest
+        } else {
+            Vec::new()
+        };
+
+        let mut prune = false;
+        let mut keep_days = None;
+        let mut keep_sessions = None;
+        let mut inspect = false;
+        let mut cwd = None;
+        let mut export_json = None;
+        let mut session = None;
+        let mut skip_health_check = false;
+        let mut initial_prompt = None;
+        let mut headless = false;
+        let mut until = None;
+        let mut timeout = None;
+        let mut config = None;
+        let mut list_snapshots = false;
+        let mut restore = None;
+        let mut offline = false;
+        let mut iter = args.into_iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--prune" => prune = true,
+                "--inspect" => inspect = true,
+                "--skip-health-check" => skip_health_check = true,
+                "--headless" => headless = true,
+                "--list-snapshots" => list_snapshots = true,
+                "--offline" => offline = true,
+                // Every run already starts a fresh session (History is never
+                // resumed), so this only lets scripts say so explicitly.
+                "--new-session" => {}
+                "--restore" => restore = Some(parse_flag_value::<String>(&arg, iter.next())?),
+                "--until" => until = Some(parse_flag_value::<String>(&arg, iter.next())?),
+                "--timeout" => timeout = Some(parse_flag_value::<u64>(&arg, iter.next())?),
+                "--cwd" => cwd = Some(parse_flag_value::<PathBuf>(&arg, iter.next())?),
+                "--config" => config = Some(parse_flag_value::<PathBuf>(&arg, iter.next())?),
+                "--export-json" => export_json = Some(parse_flag_value::<PathBuf>(&arg, iter.next())?),
+                "--session" => session = Some(parse_flag_value::<String>(&arg, iter.next())?),
+                "--initial-prompt" => initial_prompt = Some(parse_flag_value::<String>(&arg, iter.next())?),
+                "--keep-days" => keep_days = Some(parse_flag_value::<i64>(&arg, iter.next())?),
+                "--keep-sessions" => {
+                    keep_sessions = Some(parse_flag_value::<usize>(&arg, iter.next())?)
+                }
+                _ => {}
+            }
+        }
+
+        let prune = match (prune, keep_days, keep_sessions) {
+            (false, None, None) => None,
+            (false, _, _) => return Err(anyhow!("--keep-days/--keep-sessions require --prune")),
+            (true, Some(days), None) => Some(PruneCutoff::OlderThanDays(days)),
+            (true, None, Some(count)) => Some(PruneCutoff::KeepNewest(count)),
+            (true, None, None) => return Err(anyhow!("--prune needs --keep-days N or --keep-sessions N")),
+            (true, Some(_), Some(_)) => {
+                return Err(anyhow!("--keep-days and --keep-sessions are mutually exclusive"))
+            }
+        };
+        if !headless && (until.is_some() || timeout.is_some()) {
+            return Err(anyhow!("--until/--timeout require --headless"));
+        }
+
+        Ok(Self {
+            claude_args,
+            prune,
+            inspect,
+            cwd,
+            export_json,
+            session,
+            skip_health_check,
+            initial_prompt,
+            headless,
+            until,
+            timeout,
+            config,
+            list_snapshots,
+            restore,
+            offline,
+        })
+    }
+}
+
+/// `CCWB_LOG=debug` (or `trace`) turns the debug log on; so does a
+/// `RUST_LOG` mentioning either level, e.g. `cc_workbench=debug`.
+fn debug_log_requested(ccwb_log: Option<&str>, rust_log: Option<&str>) -> bool {
+    let wants = |value: &str| {
+        let value = value.to_ascii_lowercase();
+        value.contains("debug") || value.contains("trace")
+    };
+    ccwb_log.map(wants).or(rust_log.map(wants)).unwrap_or(false)
+}
+
+/// Opens `path` for appending as the debug log; the screen belongs to the
+/// TUI, so nothing goes to stdout or stderr.
+fn init_debug_log(path: &Path) -> Result<()> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("debug log {}: {}", path.display(), e))?;
+    let _ = DEBUG_LOG.set(Mutex::new(file));
+    Ok(())
+}
+
+fn write_debug_line(log: &Mutex<fs::File>, args: std::fmt::Arguments) {
+    if let Ok(mut file) = log.lock() {
+        let _ = writeln!(file, "{} {}", Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"), args);
+    }
+}
+
+/// A key for the debug log. Plain characters are left out so prompts (and
+/// anything pasted into them) never end up in the file.
+fn key_summary(key: &KeyEvent) -> String {
+    match key.code {
+        KeyCode::Char(_) if !key.modifiers.contains(KeyModifiers::CONTROL) => "Char".to_string(),
+        code if key.modifiers.is_empty() => format!("{:?}", code),
+        code => format!("{:?}+{:?}", key.modifiers, code),
+    }
+}
+
+fn parse_flag_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T> {
+    let value = value.ok_or_else(|| anyhow!("{} needs a value", flag))?;
+    value
+        .parse::<T>()
+        .map_err(|_| anyhow!("invalid value for {}: {}", flag, value))
+}
+
 #[derive(Clone)]
 struct Config {
     claude_cmd: String,
+    /// Arguments put before the ones after `--`, for a `claude_cmd` that is
+    /// a wrapper which needs its own.
+    claude_args_prefix: Vec<String>,
     context_limit: u32,
+    /// Take the context window from the primary provider's reported limit
+    /// when it has one; see `App::context_limit`.
+    auto_context_limit: bool,
     compress_threshold: f32,
+    /// Divisor for the chars-based token estimate; see `estimate_tokens`.
+    chars_per_token: f32,
     usage_poll_seconds: u64,
+    /// Output lines kept before the oldest are dropped.
+    scrollback_lines: usize,
+    /// Longer output lines are hard-wrapped when stored.
+    max_line_chars: usize,
     providers: Vec<ProviderConfig>,
+    show_line_numbers: bool,
+    show_input_line: bool,
+    /// Space inside the border of the output panel and of each workbench
+    /// panel.
+    output_padding: Padding,
+    workbench_padding: Padding,
+    data_dir: Option<PathBuf>,
+    env: HashMap<String, String>,
+    metrics_port: Option<u16>,
+    metrics_bind: String,
+    /// Quitting always asks first, not only while work is in flight.
+    require_quit_confirm: bool,
+    /// Letter that quits together with Ctrl; see `parse_quit_key`.
+    quit_key: char,
+    notify_threshold: Option<f64>,
+    history_capture: HistoryCapture,
+    /// Workbench panels to show, top to bottom.
+    panels: Vec<Panel>,
+    output_encoding: OutputEncoding,
+    compact_command: String,
+    /// Provider listed first in the Usage panel, whatever its position.
+    primary_provider: Option<String>,
+    /// Quiet time after which the current turn counts as finished.
+    assistant_idle: Duration,
+    title: String,
+    collapse_blank_lines: bool,
+    /// Run Claude with stderr on its own pipe, shown as `[err]` lines.
+    separate_stderr: bool,
+    render_markdown: bool,
+    /// Mirror cleaned output to `logs/<session_id>.log`.
+    session_log: bool,
+    /// Style fenced code blocks even without `render_markdown`.
+    highlight_code_blocks: bool,
+    /// Never poll `httpjson` providers; also `--offline`.
+    offline: bool,
+    backup_retention: Option<usize>,
+    /// Prompts are stored in the database cut to this many characters.
+    max_stored_content: Option<usize>,
+    compaction_marker: Option<Pattern>,
+    /// Prompts shorter than this (in characters, trimmed) get no snapshot.
+    snapshot_min_chars: usize,
+    /// Prompts matching any of these get no snapshot.
+    snapshot_skip_patterns: Vec<Pattern>,
+    snapshot_author_name: String,
+    snapshot_author_email: String,
+    /// Also snapshot when a turn ends, so `d` shows what the turn changed.
+    snapshot_after_turn: bool,
+}
+
+/// How bytes from Claude's PTY become text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+enum OutputEncoding {
+    /// Invalid UTF-8 becomes `�`.
+    #[serde(rename = "utf8-lossy")]
+    Utf8Lossy,
+    /// Invalid UTF-8 is dropped.
+    #[serde(rename = "utf8-strict-drop")]
+    Utf8StrictDrop,
+    /// Every byte is its own char (ISO-8859-1).
+    #[serde(rename = "latin1")]
+    Latin1,
+}
+
+/// Turns PTY reads into text. A UTF-8 sequence split across two reads is
+/// carried over to the next one instead of being treated as invalid.
+struct OutputDecoder {
+    encoding: OutputEncoding,
+    carry: Vec<u8>,
+}
+
+impl OutputDecoder {
+    fn new(encoding: OutputEncoding) -> Self {
+        Self {
+            encoding,
+            carry: Vec::new(),
+        }
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> String {
+        if self.encoding == OutputEncoding::Latin1 {
+            return bytes.iter().map(|&b| b as char).collect();
+        }
+        let mut input = std::mem::take(&mut self.carry);
+        input.extend_from_slice(bytes);
+        let mut out = String::with_capacity(input.len());
+        let mut rest = &input[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => {
+                    out.push_str(text);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, tail) = rest.split_at(err.valid_up_to());
+                    out.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match err.error_len() {
+                        Some(len) => {
+                            if self.encoding == OutputEncoding::Utf8Lossy {
+                                out.push('\u{FFFD}');
+                            }
+                            rest = &tail[len..];
+                        }
+                        // Incomplete sequence at the end: wait for more bytes.
+                        None => {
+                            self.carry = tail.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Whatever is still carried when the stream ends is invalid.
+    fn finish(&mut self) -> String {
+        let carried = std::mem::take(&mut self.carry);
+        match self.encoding {
+            OutputEncoding::Utf8Lossy if !carried.is_empty() => "\u{FFFD}".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// A workbench panel, for `panels`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Panel {
+    Usage,
+    Context,
+    History,
+}
+
+/// Where the History entry for a submitted prompt comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HistoryCapture {
+    /// The line we tracked from keystrokes and pastes.
+    Input,
+    /// The last non-empty output line before Enter (Claude's echo of the prompt).
+    Output,
 }
 
 impl Config {
-    fn load(workspace: &Path) -> Result<Self> {
-        let claude_cmd = match env::var("CCWB_CLAUDE_CMD") {
+    /// Reads the home and workspace config files, or only `explicit` when
+    /// `--config` names one, then applies the `CCWB_*` overrides.
+    fn load(workspace: &Path, explicit: Option<&Path>) -> Result<Self> {
+        let file = match explicit {
+            Some(path) => Some(read_explicit_config_file(path)?),
+            None => load_config_file(workspace),
+        };
+        let mut config = Self::from_file(workspace, file)?;
+        config.claude_cmd = match env::var("CCWB_CLAUDE_CMD") {
             Ok(val) => val,
             Err(_) => detect_claude_cmd().unwrap_or_else(|| "claude".to_string()),
         };
+        if let Ok(val) = env::var("CCWB_DATA_DIR") {
+            if !val.is_empty() {
+                config.data_dir = Some(resolve_path(workspace, &val));
+            }
+        }
+        Ok(config)
+    }
+
+    /// The settings in `file` over the defaults; `None` gives the defaults.
+    /// Reads nothing from disk or the environment.
+    fn from_file(workspace: &Path, file: Option<ConfigFile>) -> Result<Self> {
+        let claude_cmd = "claude".to_string();
         let mut context_limit = 200_000;
+        let mut auto_context_limit = false;
         let mut compress_threshold = 0.85;
+        let mut chars_per_token = 4.0;
         let mut providers: Vec<ProviderConfig> = Vec::new();
         let mut usage_poll_seconds = 30;
+        let mut scrollback_lines = 5000;
+        let mut max_line_chars = 10_000;
+        let mut show_line_numbers = false;
+        let mut show_input_line = false;
+        let mut output_padding = Padding::zero();
+        let mut workbench_padding = Padding::zero();
+        let mut claude_args_prefix = Vec::new();
+        let mut data_dir = None;
+        let mut extra_env = HashMap::new();
+        let mut metrics_port = None;
+        let mut metrics_bind = "127.0.0.1".to_string();
+        let mut require_quit_confirm = false;
+        let mut quit_key = 'q';
+        let mut notify_threshold = None;
+        let mut history_capture = HistoryCapture::Input;
+        let mut panels = vec![Panel::Usage, Panel::Context, Panel::History];
+        let mut output_encoding = OutputEncoding::Utf8Lossy;
+        let mut compact_command = "/compact".to_string();
+        let mut primary_provider = None;
+        let mut assistant_idle = Duration::from_millis(3000);
+        let mut collapse_blank_lines = false;
+        let mut separate_stderr = false;
+        let mut render_markdown = false;
+        let mut session_log = false;
+        let mut highlight_code_blocks = false;
+        let mut offline = false;
+        let mut backup_retention = None;
+        let mut max_stored_content = None;
+        let mut compaction_marker = None;
+        let mut snapshot_min_chars = 0;
+        let mut snapshot_skip_patterns = Vec::new();
+        let mut snapshot_author_name = "ccwb".to_string();
+        let mut snapshot_author_email = "ccwb@local".to_string();
+        let mut snapshot_after_turn = false;
+        let mut title = workspace
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| workspace.display().to_string());
 
-        if let Some(file) = load_config_file(workspace) {
+        if let Some(file) = file {
             if let Some(val) = file.context_limit {
                 context_limit = val;
             }
+            if let Some(val) = file.auto_context_limit {
+                auto_context_limit = val;
+            }
             if let Some(val) = file.compress_threshold {
                 compress_threshold = val;
             }
+            if let Some(val) = file.chars_per_token {
+                if !val.is_finite() || val <= 0.0 {
+                    return Err(anyhow!("chars_per_token must be positive, got {}", val));
+                }
+                chars_per_token = val;
+            }
             if let Some(list) = file.providers {
                 providers = list;
             }
             if let Some(val) = file.usage_poll_seconds {
                 usage_poll_seconds = val;
             }
+            if let Some(val) = file.scrollback_lines {
+                scrollback_lines = val.max(MIN_SCROLLBACK_LINES);
+            }
+            if let Some(val) = file.max_line_chars {
+                max_line_chars = val.max(MIN_LINE_CHARS);
+            }
+            if let Some(val) = file.show_line_numbers {
+                show_line_numbers = val;
+            }
+            if let Some(val) = file.claude_args_prefix {
+                claude_args_prefix = val;
+            }
+            if let Some(val) = file.show_input_line {
+                show_input_line = val;
+            }
+            if let Some([x, y]) = file.output_padding {
+                output_padding = Padding { left: x, right: x, top: y, bottom: y };
+            }
+            if let Some([x, y]) = file.workbench_padding {
+                workbench_padding = Padding { left: x, right: x, top: y, bottom: y };
+            }
+            if let Some(val) = file.data_dir {
+                data_dir = Some(resolve_path(workspace, &val));
+            }
+            if let Some(val) = file.env {
+                extra_env = val;
+            }
+            if let Some(val) = file.metrics_port {
+                metrics_port = Some(val);
+            }
+            if let Some(val) = file.metrics_bind {
+                metrics_bind = val;
+            }
+            if let Some(val) = file.require_quit_confirm {
+                require_quit_confirm = val;
+            }
+            if let Some(val) = file.quit_key {
+                quit_key = parse_quit_key(&val).map_err(|e| anyhow!("quit_key: {}", e))?;
+            }
+            if let Some(val) = file.notify_threshold {
+                notify_threshold = Some(val.clamp(0.0, 1.0));
+            }
+            if let Some(val) = file.history_capture {
+                history_capture = val;
+            }
+            if let Some(val) = file.panels {
+                if val.is_empty() {
+                    return Err(anyhow!("panels must list at least one of usage, context, history"));
+                }
+                panels.clear();
+                for panel in val {
+                    if !panels.contains(&panel) {
+                        panels.push(panel);
+                    }
+                }
+            }
+            if let Some(val) = file.output_encoding {
+                output_encoding = val;
+            }
+            if let Some(val) = file.assistant_idle_ms {
+                assistant_idle = Duration::from_millis(val.max(100));
+            }
+            if let Some(val) = file.compact_command {
+                compact_command = val;
+            }
+            if let Some(val) = file.primary_provider {
+                primary_provider = Some(val);
+            }
+            if let Some(val) = file.title {
+                title = val;
+            }
+            if let Some(val) = file.collapse_blank_lines {
+                collapse_blank_lines = val;
+            }
+            if let Some(val) = file.separate_stderr {
+                separate_stderr = val;
+            }
+            if let Some(val) = file.offline {
+                offline = val;
+            }
+            if let Some(val) = file.render_markdown {
+                render_markdown = val;
+            }
+            if let Some(val) = file.session_log {
+                session_log = val;
+            }
+            if let Some(val) = file.highlight_code_blocks {
+                highlight_code_blocks = val;
+            }
+            if let Some(val) = file.backup_retention {
+                if val == 0 {
+                    return Err(anyhow!("backup_retention must be at least 1: a restore always keeps its own backup"));
+                }
+                backup_retention = Some(val);
+            }
+            if let Some(val) = file.max_stored_content {
+                max_stored_content = Some(val);
+            }
+            if let Some(val) = file.snapshot_author_name {
+                snapshot_author_name = val;
+            }
+            if let Some(val) = file.snapshot_author_email {
+                snapshot_author_email = val;
+            }
+            if let Some(val) = file.snapshot_after_turn {
+                snapshot_after_turn = val;
+            }
+            if let Some(val) = file.compaction_marker {
+                compaction_marker =
+                    Some(Pattern::new(&val).map_err(|e| anyhow!("compaction_marker: {}", e))?);
+            }
+            if let Some(val) = file.snapshot_min_chars {
+                snapshot_min_chars = val;
+            }
+            if let Some(val) = file.snapshot_skip_patterns {
+                snapshot_skip_patterns = val
+                    .iter()
+                    .map(|source| Pattern::new(source).map_err(|e| anyhow!("snapshot_skip_patterns: {}", e)))
+                    .collect::<Result<_>>()?;
+            }
         }
 
+        for provider in &mut providers {
+            if let ProviderConfig::File { path, .. } = provider {
+                *path = resolve_path(workspace, &path.to_string_lossy());
+            }
+        }
         if providers.is_empty() {
             providers.push(ProviderConfig::Local {
                 name: Some("local-estimate".to_string()),
                 limit_tokens: Some(context_limit as u64),
+                compress_threshold: None,
             });
         }
         Ok(Self {
             claude_cmd,
+            claude_args_prefix,
             context_limit,
+            auto_context_limit,
             compress_threshold,
+            chars_per_token,
             usage_poll_seconds,
+            scrollback_lines,
+            max_line_chars,
             providers,
+            show_line_numbers,
+            show_input_line,
+            output_padding,
+            workbench_padding,
+            data_dir,
+            env: extra_env,
+            metrics_port,
+            metrics_bind,
+            require_quit_confirm,
+            quit_key,
+            notify_threshold,
+            history_capture,
+            panels,
+            output_encoding,
+            compact_command,
+            primary_provider,
+            assistant_idle,
+            title,
+            collapse_blank_lines,
+            separate_stderr,
+            render_markdown,
+            session_log,
+            highlight_code_blocks,
+            offline,
+            backup_retention,
+            max_stored_content,
+            compaction_marker,
+            snapshot_min_chars,
+            snapshot_skip_patterns,
+            snapshot_author_name,
+            snapshot_author_email,
+            snapshot_after_turn,
         })
     }
+
+    /// Threshold for the context warning: the first Local provider that sets
+    /// its own `compress_threshold` wins, otherwise the global value applies.
+    fn effective_compress_threshold(&self) -> f32 {
+        self.providers
+            .iter()
+            .find_map(|p| match p {
+                ProviderConfig::Local {
+                    compress_threshold: Some(val),
+                    ..
+                } => Some(*val),
+                _ => None,
+            })
+            .unwrap_or(self.compress_threshold)
+    }
+
+    /// Whether a prompt is too trivial to snapshot (e.g. "yes").
+    fn skips_snapshot(&self, prompt: &str) -> bool {
+        let prompt = prompt.trim();
+        prompt.chars().count() < self.snapshot_min_chars
+            || self.snapshot_skip_patterns.iter().any(|p| p.is_match(prompt))
+    }
+}
+
+/// Expands a leading `~/` and anchors relative paths at the workspace.
+fn resolve_path(workspace: &Path, value: &str) -> PathBuf {
+    if let Some(rest) = value.strip_prefix("~/") {
+        if let Ok(home) = env::var("HOME") {
+            return Path::new(&home).join(rest);
+        }
+    }
+    workspace.join(value)
+}
+
+/// Ctrl letters the quit key can't take: our own bindings, Claude's
+/// interrupt, and those terminals send as Backspace, Tab or Enter.
+const RESERVED_CTRL_KEYS: &str = "cdhijkmpu";
+
+/// Parses `quit_key`, written like `"ctrl+x"` (any case).
+fn parse_quit_key(value: &str) -> Result<char, String> {
+    let lower = value.trim().to_ascii_lowercase();
+    let mut letters = lower.strip_prefix("ctrl+").unwrap_or_default().chars();
+    match (letters.next(), letters.next()) {
+        (Some(c), None) if c.is_ascii_lowercase() && !RESERVED_CTRL_KEYS.contains(c) => Ok(c),
+        (Some(c), None) if c.is_ascii_lowercase() => Err(format!("{} is already taken", value)),
+        _ => Err(format!("expected Ctrl plus a letter, like \"ctrl+x\", got {:?}", value)),
+    }
 }
 
 fn detect_claude_cmd() -> Option<String> {
@@ -130,12 +1000,116 @@ fn detect_claude_cmd() -> Option<String> {
     None
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 struct ConfigFile {
     context_limit: Option<u32>,
+    auto_context_limit: Option<bool>,
     compress_threshold: Option<f32>,
+    chars_per_token: Option<f32>,
     usage_poll_seconds: Option<u64>,
+    scrollback_lines: Option<usize>,
+    max_line_chars: Option<usize>,
     providers: Option<Vec<ProviderConfig>>,
+    show_line_numbers: Option<bool>,
+    show_input_line: Option<bool>,
+    output_padding: Option<[u16; 2]>,
+    workbench_padding: Option<[u16; 2]>,
+    claude_args_prefix: Option<Vec<String>>,
+    data_dir: Option<String>,
+    env: Option<HashMap<String, String>>,
+    metrics_port: Option<u16>,
+    metrics_bind: Option<String>,
+    require_quit_confirm: Option<bool>,
+    quit_key: Option<String>,
+    notify_threshold: Option<f64>,
+    history_capture: Option<HistoryCapture>,
+    panels: Option<Vec<Panel>>,
+    output_encoding: Option<OutputEncoding>,
+    compact_command: Option<String>,
+    primary_provider: Option<String>,
+    assistant_idle_ms: Option<u64>,
+    title: Option<String>,
+    collapse_blank_lines: Option<bool>,
+    separate_stderr: Option<bool>,
+    render_markdown: Option<bool>,
+    session_log: Option<bool>,
+    highlight_code_blocks: Option<bool>,
+    offline: Option<bool>,
+    backup_retention: Option<usize>,
+    max_stored_content: Option<usize>,
+    compaction_marker: Option<String>,
+    snapshot_min_chars: Option<usize>,
+    snapshot_skip_patterns: Option<Vec<String>>,
+    snapshot_author_name: Option<String>,
+    snapshot_author_email: Option<String>,
+    snapshot_after_turn: Option<bool>,
+    /// How a workspace file's `providers` combine with the home file's.
+    providers_mode: Option<ProvidersMode>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ProvidersMode {
+    Replace,
+    Append,
+}
+
+impl ConfigFile {
+    /// Layers `over` on top of `self`: every field `over` sets wins, except
+    /// `providers`, which are appended instead when `over` asks for it.
+    fn overlay(self, over: ConfigFile) -> ConfigFile {
+        let providers = match (over.providers_mode, self.providers, over.providers) {
+            (Some(ProvidersMode::Append), Some(mut base), Some(extra)) => {
+                base.extend(extra);
+                Some(base)
+            }
+            (_, base, extra) => extra.or(base),
+        };
+        ConfigFile {
+            context_limit: over.context_limit.or(self.context_limit),
+            auto_context_limit: over.auto_context_limit.or(self.auto_context_limit),
+            compress_threshold: over.compress_threshold.or(self.compress_threshold),
+            chars_per_token: over.chars_per_token.or(self.chars_per_token),
+            usage_poll_seconds: over.usage_poll_seconds.or(self.usage_poll_seconds),
+            scrollback_lines: over.scrollback_lines.or(self.scrollback_lines),
+            max_line_chars: over.max_line_chars.or(self.max_line_chars),
+            providers,
+            show_line_numbers: over.show_line_numbers.or(self.show_line_numbers),
+            show_input_line: over.show_input_line.or(self.show_input_line),
+            output_padding: over.output_padding.or(self.output_padding),
+            workbench_padding: over.workbench_padding.or(self.workbench_padding),
+            claude_args_prefix: over.claude_args_prefix.or(self.claude_args_prefix),
+            data_dir: over.data_dir.or(self.data_dir),
+            env: over.env.or(self.env),
+            metrics_port: over.metrics_port.or(self.metrics_port),
+            metrics_bind: over.metrics_bind.or(self.metrics_bind),
+            require_quit_confirm: over.require_quit_confirm.or(self.require_quit_confirm),
+            quit_key: over.quit_key.or(self.quit_key),
+            notify_threshold: over.notify_threshold.or(self.notify_threshold),
+            history_capture: over.history_capture.or(self.history_capture),
+            panels: over.panels.or(self.panels),
+            output_encoding: over.output_encoding.or(self.output_encoding),
+            compact_command: over.compact_command.or(self.compact_command),
+            primary_provider: over.primary_provider.or(self.primary_provider),
+            assistant_idle_ms: over.assistant_idle_ms.or(self.assistant_idle_ms),
+            title: over.title.or(self.title),
+            collapse_blank_lines: over.collapse_blank_lines.or(self.collapse_blank_lines),
+            separate_stderr: over.separate_stderr.or(self.separate_stderr),
+            render_markdown: over.render_markdown.or(self.render_markdown),
+            session_log: over.session_log.or(self.session_log),
+            highlight_code_blocks: over.highlight_code_blocks.or(self.highlight_code_blocks),
+            offline: over.offline.or(self.offline),
+            backup_retention: over.backup_retention.or(self.backup_retention),
+            max_stored_content: over.max_stored_content.or(self.max_stored_content),
+            compaction_marker: over.compaction_marker.or(self.compaction_marker),
+            snapshot_min_chars: over.snapshot_min_chars.or(self.snapshot_min_chars),
+            snapshot_skip_patterns: over.snapshot_skip_patterns.or(self.snapshot_skip_patterns),
+            snapshot_author_name: over.snapshot_author_name.or(self.snapshot_author_name),
+            snapshot_author_email: over.snapshot_author_email.or(self.snapshot_author_email),
+            snapshot_after_turn: over.snapshot_after_turn.or(self.snapshot_after_turn),
+            providers_mode: None,
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -144,6 +1118,8 @@ enum ProviderConfig {
     Local {
         name: Option<String>,
         limit_tokens: Option<u64>,
+        /// Overrides the global `compress_threshold` for this model's window.
+        compress_threshold: Option<f32>,
     },
     Manual {
         name: String,
@@ -158,130 +1134,469 @@ enum ProviderConfig {
         body: Option<serde_json::Value>,
         used_pointer: String,
         limit_pointer: String,
+        /// Overrides `usage_poll_seconds` for this provider.
+        poll_seconds: Option<u64>,
+    },
+    /// A JSON file kept up to date by something else, e.g. a cron job.
+    File {
+        name: String,
+        /// Relative to the workspace; resolved in `Config::from_file`.
+        path: PathBuf,
+        used_pointer: String,
+        limit_pointer: String,
+        /// Overrides `usage_poll_seconds` for this provider.
+        poll_seconds: Option<u64>,
     },
 }
 
+/// Loads `~/.cc-workbench/config.json` and overlays the workspace's
+/// `.cc-workbench/config.json` on top of it (see `ConfigFile::overlay`).
 fn load_config_file(workspace: &Path) -> Option<ConfigFile> {
-    let workspace_path = workspace.join(".cc-workbench").join("config.json");
-    if let Ok(contents) = fs::read_to_string(&workspace_path) {
-        if let Ok(parsed) = serde_json::from_str::<ConfigFile>(&contents) {
-            return Some(parsed);
-        }
+    let home = env::var("HOME")
+        .ok()
+        .and_then(|home| read_config_file(&Path::new(&home).join(".cc-workbench").join("config.json")));
+    let local = read_config_file(&workspace.join(".cc-workbench").join("config.json"));
+    match (home, local) {
+        (Some(home), Some(local)) => Some(home.overlay(local)),
+        (home, local) => local.or(home),
     }
-    if let Ok(home) = env::var("HOME") {
-        let home_path = Path::new(&home).join(".cc-workbench").join("config.json");
-        if let Ok(contents) = fs::read_to_string(&home_path) {
-            if let Ok(parsed) = serde_json::from_str::<ConfigFile>(&contents) {
-                return Some(parsed);
-            }
+}
+
+fn read_config_file(path: &Path) -> Option<ConfigFile> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// Unlike the probed locations, a file named on the command line has to
+/// exist and parse.
+fn read_explicit_config_file(path: &Path) -> Result<ConfigFile> {
+    let contents = fs::read_to_string(path).map_err(|e| anyhow!("--config {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| anyhow!("--config {}: {}", path.display(), e))
+}
+
+/// How `SnapshotManager` runs git, so tests can stand in for it.
+trait GitRunner: Send + Sync {
+    /// Runs git against `git_dir`, with `work_tree` as the working tree for
+    /// commands that read or write the workspace. Returns stdout.
+    fn run(&self, git_dir: &Path, work_tree: Option<&Path>, args: &[&str]) -> Result<String>;
+}
+
+/// The real `git` on `PATH`.
+struct ProcessGit;
+
+impl GitRunner for ProcessGit {
+    fn run(&self, git_dir: &Path, work_tree: Option<&Path>, args: &[&str]) -> Result<String> {
+        match work_tree {
+            Some(workspace) => run_git(workspace, git_dir, args, None),
+            None => run_git_bare(git_dir, args, None),
         }
     }
-    None
 }
 
 #[derive(Clone)]
 struct SnapshotManager {
+    /// Shared with clones, e.g. the snapshot worker's.
+    git: Arc<dyn GitRunner>,
     workspace: PathBuf,
     git_dir: PathBuf,
     backup_dir: PathBuf,
+    diffs_dir: PathBuf,
+    worktrees_dir: PathBuf,
+    excludes: Vec<String>,
+    /// Identity recorded on snapshot commits.
+    author_name: String,
+    author_email: String,
+    /// Last `repo_stats` result and when it was taken.
+    stats: Arc<Mutex<Option<(Instant, CachedRepoStats)>>>,
+    /// Latest snapshot of the sessions before this one, which the first
+    /// snapshot of this session is compared against.
+    base: Option<String>,
 }
 
+/// Snapshot commit count and repo size in bytes.
+type RepoStats = (usize, u64);
+
+/// `None` when the count failed.
+type CachedRepoStats = Option<RepoStats>;
+
+/// How long `repo_stats` reuses its last count.
+const REPO_STATS_TTL: Duration = Duration::from_secs(5);
+
 impl SnapshotManager {
     fn new(workspace: &Path, data_dir: &Path) -> Result<Self> {
-        let git_dir = data_dir.join("snapshots.git");
-        let backup_dir = data_dir.join("backup");
-        fs::create_dir_all(&backup_dir)?;
-        if !git_dir.exists() {
-            run_git_bare(&git_dir, &["init", "--bare"], None)?;
+        let manager = Self::detached(workspace, data_dir);
+        fs::create_dir_all(&manager.backup_dir)?;
+        if !manager.git_dir.exists() {
+            manager.git_bare(&["init", "--bare"])?;
         }
-        Ok(Self {
+        Ok(manager)
+    }
+
+    /// A manager that only knows its paths and never touches disk, for when
+    /// git is unusable and snapshots are disabled.
+    fn detached(workspace: &Path, data_dir: &Path) -> Self {
+        let mut manager = Self {
+            git: Arc::new(ProcessGit),
             workspace: workspace.to_path_buf(),
-            git_dir,
-            backup_dir,
-        })
+            git_dir: data_dir.join("snapshots.git"),
+            backup_dir: data_dir.join("backup"),
+            diffs_dir: data_dir.join("diffs"),
+            worktrees_dir: data_dir.join("worktrees"),
+            excludes: vec![":(exclude).cc-workbench".to_string()],
+            author_name: "ccwb".to_string(),
+            author_email: "ccwb@local".to_string(),
+            stats: Arc::new(Mutex::new(None)),
+            base: None,
+        };
+        manager.exclude_path(data_dir);
+        manager
     }
 
-    fn snapshot(&self, message_idx: i64) -> Result<String> {
-        run_git(
-            &self.workspace,
-            &self.git_dir,
-            &["add", "-A", "--", ".", ":(exclude).cc-workbench"],
-            None,
-        )?;
-        let msg = format!("snapshot {}", message_idx);
-        run_git(
-            &self.workspace,
-            &self.git_dir,
-            &["-c", "user.name=ccwb", "-c", "user.email=ccwb@local", "commit", "-m", &msg, "--allow-empty"],
-            None,
-        )?;
-        let commit = run_git(
-            &self.workspace,
-            &self.git_dir,
-            &["rev-parse", "HEAD"],
-            None,
-        )?;
-        Ok(commit.trim().to_string())
+    fn git(&self, args: &[&str]) -> Result<String> {
+        self.git.run(&self.git_dir, Some(&self.workspace), args)
     }
 
-    fn diff_preview(&self, commit: &str) -> Result<String> {
-        let diff = run_git(
-            &self.workspace,
-            &self.git_dir,
-            &["diff", commit, "--"],
-            None,
-        )?;
-        Ok(diff)
+    fn git_bare(&self, args: &[&str]) -> Result<String> {
+        self.git.run(&self.git_dir, None, args)
     }
 
-    fn diff_name_status(&self, commit: &str) -> Result<String> {
-        let diff = run_git(
-            &self.workspace,
-            &self.git_dir,
-            &["diff", "--name-status", commit, "--"],
-            None,
-        )?;
-        Ok(diff)
+    fn set_author(&mut self, name: &str, email: &str) {
+        self.author_name = name.to_string();
+        self.author_email = email.to_string();
     }
 
-    fn restore(&self, commit: &str) -> Result<()> {
-        let status = self.diff_name_status(commit)?;
-        let files = parse_name_status(&status);
-        let backup_dir = self.backup_dir.join(Utc::now().format("%Y%m%dT%H%M%S").to_string());
-        fs::create_dir_all(&backup_dir)?;
-        for entry in &files {
-            let src = self.workspace.join(&entry.path);
-            if src.exists() {
-                let dst = backup_dir.join(&entry.path);
-                if let Some(parent) = dst.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-                fs::copy(&src, &dst)?;
+    /// Keeps `path` out of snapshots if it lives inside the workspace.
+    fn exclude_path(&mut self, path: &Path) {
+        if let Ok(rel) = path.strip_prefix(&self.workspace) {
+            if !rel.starts_with(".cc-workbench") && !rel.as_os_str().is_empty() {
+                self.excludes.push(format!(":(exclude){}", rel.display()));
             }
         }
+    }
 
-        run_git(
-            &self.workspace,
-            &self.git_dir,
-            &["checkout", commit, "--", "."],
-            None,
-        )?;
+    /// Points HEAD at a new `sessions/<id>` branch, so this session's
+    /// snapshots form a chain of their own that `gc` can drop with it.
+    fn start_session(&mut self, session_id: &str) -> Result<()> {
+        self.base = self.resolve_commit("HEAD").ok();
+        let branch = format!("refs/heads/sessions/{}", session_id);
+        self.git_bare(&["symbolic-ref", "HEAD", &branch])?;
+        Ok(())
+    }
 
-        for entry in &files {
-            if entry.status == 'A' {
-                let target = self.workspace.join(&entry.path);
-                if target.exists() {
-                    let _ = fs::remove_file(&target);
-                }
+    /// Deletes the branches of sessions not in `keep_sessions`, and older
+    /// branches (from before snapshots were kept per session) holding none
+    /// of `keep_commits`, then lets git drop what is no longer reachable.
+    /// Returns how many bytes the repo shrank by.
+    fn gc(&self, keep_sessions: &HashSet<String>, keep_commits: &HashSet<String>) -> Result<u64> {
+        let before = dir_size(&self.git_dir);
+        let refs = self.git_bare(&["for-each-ref", "--format=%(refname)", "refs/heads/"])?;
+        for name in refs.lines() {
+            let doomed = match name.strip_prefix("refs/heads/sessions/") {
+                Some(session) => !keep_sessions.contains(session),
+                None => !self.git_bare(&["rev-list", name, "--"])?.lines().any(|c| keep_commits.contains(c)),
+            };
+            if doomed {
+                self.git_bare(&["update-ref", "-d", name])?;
             }
         }
-        Ok(())
+        self.git_bare(&["reflog", "expire", "--expire=now", "--all"])?;
+        self.git_bare(&["gc", "--prune=now", "--quiet"])?;
+        Ok(before.saturating_sub(dir_size(&self.git_dir)))
     }
-}
 
-#[derive(Clone)]
-struct NameStatusEntry {
-    status: char,
+    fn snapshot(&self, message_idx: i64) -> Result<String> {
+        let mut add_args = vec!["add", "-A", "--", "."];
+        add_args.extend(self.excludes.iter().map(String::as_str));
+        self.git(&add_args)?;
+        let msg = format!("snapshot {}", message_idx);
+        let name = format!("user.name={}", self.author_name);
+        let email = format!("user.email={}", self.author_email);
+        self.git(&["-c", &name, "-c", &email, "commit", "-m", &msg, "--allow-empty"])?;
+        let commit = self.git(&["rev-parse", "HEAD"])?;
+        Ok(commit.trim().to_string())
+    }
+
+    /// The workspace against `commit`, or with `until` the changes between
+    /// the two commits.
+    fn diff_preview(&self, commit: &str, until: Option<&str>) -> Result<String> {
+        let diff = match until {
+            Some(until) => self.git(&["diff", commit, until, "--"])?,
+            None => self.git(&["diff", commit, "--"])?,
+        };
+        Ok(diff)
+    }
+
+    /// Writes the raw `git diff` shown by `diff_preview` to
+    /// `diffs/<commit>.patch` (`<commit>..<until>.patch` for a range) so it
+    /// can be archived or fed to `git apply`.
+    fn export_patch(&self, commit: &str, until: Option<&str>) -> Result<PathBuf> {
+        let diff = self.diff_preview(commit, until)?;
+        fs::create_dir_all(&self.diffs_dir)?;
+        let name = match until {
+            Some(until) => format!("{}..{}.patch", commit, until),
+            None => format!("{}.patch", commit),
+        };
+        let path = self.diffs_dir.join(name);
+        fs::write(&path, diff)?;
+        Ok(path)
+    }
+
+    /// Lines added and removed between two snapshots, from
+    /// `git diff --shortstat`.
+    fn diff_shortstat(&self, from: &str, to: &str) -> Result<(usize, usize)> {
+        let out = self.git_bare(&["diff", "--shortstat", from, to, "--"])?;
+        Ok(parse_shortstat(&out))
+    }
+
+    fn diff_name_status(&self, commit: &str) -> Result<String> {
+        let diff = self.git(&["diff", "--name-status", "--no-renames", commit, "--"])?;
+        Ok(diff)
+    }
+
+    /// Short hash, subject (`snapshot N`) and commit time of `commit`.
+    fn commit_info(&self, commit: &str) -> Result<(String, String, DateTime<Utc>)> {
+        let out = self.git_bare(&["log", "-1", "--format=%h%x00%s%x00%cI", commit, "--"])?;
+        let mut fields = out.trim_end().splitn(3, '\0').map(str::to_string);
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(short), Some(subject), Some(time)) => {
+                let time = DateTime::parse_from_rfc3339(&time)
+                    .map_err(|e| anyhow!("bad commit time {:?}: {}", time, e))?
+                    .with_timezone(&Utc);
+                Ok((short, subject, time))
+            }
+            _ => Err(anyhow!("unexpected git log output for {}", commit)),
+        }
+    }
+
+    /// The snapshot before `commit`: its parent, or `base` for the first
+    /// one of a session. The very first snapshot gets the empty tree.
+    fn previous_snapshot(&self, commit: &str) -> String {
+        self.resolve_commit(&format!("{}^", commit))
+            .ok()
+            .or_else(|| self.base.clone())
+            .unwrap_or_else(|| EMPTY_TREE.to_string())
+    }
+
+    /// Name-status of what `commit` changed relative to the previous
+    /// snapshot; the first snapshot lists every file.
+    fn commit_name_status(&self, commit: &str) -> Result<String> {
+        let previous = self.previous_snapshot(commit);
+        self.git_bare(&["diff", "--name-status", "--no-renames", &previous, commit, "--"])
+    }
+
+    /// How many files `commit` changed and the lines it added and removed,
+    /// relative to the previous snapshot; `None` where git failed.
+    fn commit_stats(&self, commit: &str) -> (Option<usize>, Option<(usize, usize)>) {
+        let changed_files = self
+            .commit_name_status(commit)
+            .ok()
+            .map(|status| parse_name_status(&status).len());
+        let previous = self.previous_snapshot(commit);
+        (changed_files, self.diff_shortstat(&previous, commit).ok())
+    }
+
+    /// Full hash of the snapshot `rev` names (a hash or unique prefix), or
+    /// an error if the snapshots repo has no such commit.
+    fn resolve_commit(&self, rev: &str) -> Result<String> {
+        let spec = format!("{}^{{commit}}", rev);
+        self.git_bare(&["rev-parse", "--verify", "--quiet", &spec])
+            .map(|out| out.trim().to_string())
+            .map_err(|_| anyhow!("no snapshot commit {}", rev))
+    }
+
+    /// Every commit in the snapshots repo.
+    fn list_commits(&self) -> Result<HashSet<String>> {
+        let out = self.git_bare(&["rev-list", "--all"])?;
+        Ok(out.lines().map(str::to_string).collect())
+    }
+
+    /// Number of snapshot commits and the on-disk size of the snapshots
+    /// repo in bytes, recomputed at most every `REPO_STATS_TTL`; a failure
+    /// is kept just as long. The lock isn't held while git runs, so
+    /// `cached_repo_stats` never waits on it.
+    fn repo_stats(&self) -> Option<RepoStats> {
+        if let Some((at, stats)) = *self.stats.lock().unwrap() {
+            if at.elapsed() < REPO_STATS_TTL {
+                return stats;
+            }
+        }
+        let stats = match self.git_bare(&["rev-list", "--all", "--count"]) {
+            Ok(out) => Some((out.trim().parse().unwrap_or(0), dir_size(&self.git_dir))),
+            Err(err) => {
+                debug_log!("repo stats failed: {}", err);
+                None
+            }
+        };
+        *self.stats.lock().unwrap() = Some((Instant::now(), stats));
+        stats
+    }
+
+    /// The last `repo_stats` result, without running anything.
+    fn cached_repo_stats(&self) -> Option<RepoStats> {
+        self.stats.lock().ok().and_then(|cached| cached.and_then(|(_, stats)| stats))
+    }
+
+    /// Works out what `restore` would do without touching the workspace.
+    fn restore_plan(&self, commit: &str) -> Result<RestorePlan> {
+        let status = self.diff_name_status(commit)?;
+        let entries = parse_name_status(&status)
+            .into_iter()
+            .map(|entry| {
+                let action = match entry.status {
+                    'A' => RestoreAction::Delete,
+                    'D' => RestoreAction::Recreate,
+                    _ => RestoreAction::Overwrite,
+                };
+                (action, entry.path)
+            })
+            .collect();
+        Ok(RestorePlan {
+            commit: commit.to_string(),
+            backup_dir: self.backup_dir.join(Utc::now().format("%Y%m%dT%H%M%S").to_string()),
+            entries,
+        })
+    }
+
+    /// Checks `commit` out into a fresh git worktree under `worktrees/`
+    /// instead of overwriting the workspace, so it can be compared and merged
+    /// by hand. Returns the worktree's path.
+    fn restore_to_worktree(&self, commit: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.worktrees_dir)?;
+        let short: String = commit.chars().take(12).collect();
+        let path = self
+            .worktrees_dir
+            .join(format!("{}-{}", short, Utc::now().format("%Y%m%dT%H%M%S")));
+        let path_arg = path.display().to_string();
+        self.git_bare(&["worktree", "add", "--detach", &path_arg, commit])?;
+        Ok(path)
+    }
+
+    fn apply_restore(&self, plan: &RestorePlan) -> Result<()> {
+        fs::create_dir_all(&plan.backup_dir)?;
+        for (_, path) in &plan.entries {
+            let src = self.workspace.join(path);
+            // symlink_metadata so dangling links are backed up too.
+            if fs::symlink_metadata(&src).is_ok() {
+                let dst = plan.backup_dir.join(path);
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                copy_preserving_symlink(&src, &dst)?;
+            }
+        }
+
+        self.git(&["checkout", &plan.commit, "--", "."])?;
+
+        for (action, path) in &plan.entries {
+            if *action == RestoreAction::Delete {
+                let target = self.workspace.join(path);
+                if fs::symlink_metadata(&target).is_ok() {
+                    let _ = fs::remove_file(&target);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes all but the newest `keep` restore backups (at least one is
+    /// always kept, so the one a restore just wrote survives). Backup dirs
+    /// are named by timestamp, so name order is age order. Best effort: a
+    /// dir that can't be removed is skipped and reported, not fatal. Returns
+    /// how many went and the failures.
+    fn prune_backups(&self, keep: usize) -> (usize, Vec<String>) {
+        let mut dirs: Vec<PathBuf> = match fs::read_dir(&self.backup_dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+                .map(|e| e.path())
+                .collect(),
+            Err(_) => return (0, Vec::new()),
+        };
+        dirs.sort();
+        let excess = dirs.len().saturating_sub(keep.max(1));
+        let mut errors = Vec::new();
+        for dir in &dirs[..excess] {
+            if let Err(err) = fs::remove_dir_all(dir) {
+                errors.push(format!("could not prune backup {}: {}", dir.display(), err));
+            }
+        }
+        (excess - errors.len(), errors)
+    }
+
+    /// `prune_backups` for the worktrees `restore_to_worktree` adds, oldest
+    /// first by the timestamp ending each name. Each is removed through git,
+    /// then `git worktree prune` forgets any whose dir is already gone.
+    fn prune_worktrees(&self, keep: usize) -> (usize, Vec<String>) {
+        let mut dirs: Vec<(String, PathBuf)> = match fs::read_dir(&self.worktrees_dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+                .map(|e| {
+                    let name = e.file_name().to_string_lossy().into_owned();
+                    let stamp = name.rsplit('-').next().unwrap_or_default().to_string();
+                    (stamp, e.path())
+                })
+                .collect(),
+            Err(_) => return (0, Vec::new()),
+        };
+        dirs.sort();
+        let excess = dirs.len().saturating_sub(keep.max(1));
+        let (mut removed, mut errors) = (0, Vec::new());
+        for (_, dir) in &dirs[..excess] {
+            let path_arg = dir.display().to_string();
+            match self.git_bare(&["worktree", "remove", "--force", &path_arg]) {
+                Ok(_) => removed += 1,
+                Err(err) => errors.push(format!("could not prune worktree {}: {}", dir.display(), err)),
+            }
+        }
+        if let Err(err) = self.git_bare(&["worktree", "prune"]) {
+            errors.push(format!("git worktree prune failed: {}", err));
+        }
+        (removed, errors)
+    }
+}
+
+/// Copies `src` to `dst`, recreating symlinks as links instead of copying the
+/// file they point to.
+fn copy_preserving_symlink(src: &Path, dst: &Path) -> Result<()> {
+    if fs::symlink_metadata(src)?.file_type().is_symlink() {
+        let target = fs::read_link(src)?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&target, dst)?;
+        #[cfg(not(unix))]
+        fs::copy(src, dst)?;
+    } else {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RestoreAction {
+    /// Modified since the snapshot; the snapshot version replaces it.
+    Overwrite,
+    /// Created after the snapshot; removed by the restore.
+    Delete,
+    /// Deleted since the snapshot; brought back.
+    Recreate,
+}
+
+#[derive(Clone)]
+struct RestorePlan {
+    commit: String,
+    backup_dir: PathBuf,
+    entries: Vec<(RestoreAction, String)>,
+}
+
+impl RestorePlan {
+    fn count(&self, action: RestoreAction) -> usize {
+        self.entries.iter().filter(|(a, _)| *a == action).count()
+    }
+}
+
+#[derive(Clone)]
+struct NameStatusEntry {
+    status: char,
     path: String,
 }
 
@@ -297,9 +1612,37 @@ fn parse_name_status(input: &str) -> Vec<NameStatusEntry> {
         .collect()
 }
 
+/// Git's empty tree, the base to diff the first snapshot against.
+const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+/// Insertions and deletions from a `--shortstat` summary such as
+/// ` 3 files changed, 42 insertions(+), 7 deletions(-)`. Git leaves out a
+/// count that is zero, and prints nothing for an empty diff.
+fn parse_shortstat(input: &str) -> (usize, usize) {
+    let mut stat = (0, 0);
+    for part in input.trim().split(',') {
+        let mut words = part.split_whitespace();
+        let (Some(count), Some(kind)) = (words.next(), words.next()) else {
+            continue;
+        };
+        let Ok(count) = count.parse() else {
+            continue;
+        };
+        if kind.starts_with("insertion") {
+            stat.0 = count;
+        } else if kind.starts_with("deletion") {
+            stat.1 = count;
+        }
+    }
+    stat
+}
+
 fn run_git(workspace: &Path, git_dir: &Path, args: &[&str], input: Option<&[u8]>) -> Result<String> {
     let mut cmd = std::process::Command::new("git");
-    cmd.arg(format!("--work-tree={}", workspace.display()))
+    // Run from the workspace too, so relative pathspecs resolve against the
+    // same tree Claude is editing.
+    cmd.current_dir(workspace)
+        .arg(format!("--work-tree={}", workspace.display()))
         .arg(format!("--git-dir={}", git_dir.display()))
         .args(args)
         .stdout(std::process::Stdio::piped())
@@ -323,6 +1666,31 @@ fn run_git(workspace: &Path, git_dir: &Path, args: &[&str], input: Option<&[u8]>
     Ok(String::from_utf8_lossy(&output.stdout).to_string())
 }
 
+/// The line of a `run_git` error worth showing: git often leads with
+/// warnings (e.g. about line endings) before the `error:`/`fatal:` line that
+/// says what actually went wrong.
+fn git_error_summary(err: &str) -> String {
+    let detail = err.strip_prefix("git failed: ").unwrap_or(err);
+    let lines: Vec<&str> = detail.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    let line = lines
+        .iter()
+        .find(|l| l.starts_with("fatal:") || l.starts_with("error:"))
+        .or(lines.first())
+        .copied()
+        .unwrap_or("snapshot failed");
+    format!("git: {}", line)
+}
+
+fn git_available() -> bool {
+    std::process::Command::new("git")
+        .arg("--version")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
 fn run_git_bare(git_dir: &Path, args: &[&str], input: Option<&[u8]>) -> Result<String> {
     let mut cmd = std::process::Command::new("git");
     cmd.arg(format!("--git-dir={}", git_dir.display()))
@@ -348,12 +1716,20 @@ fn run_git_bare(git_dir: &Path, args: &[&str], input: Option<&[u8]>) -> Result<S
     Ok(String::from_utf8_lossy(&output.stdout).to_string())
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 struct UsageEntry {
     name: String,
     used: Option<u64>,
     limit: Option<u64>,
     status: Option<String>,
+    /// Split of `used` into prompts and replies, for the local estimate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_tokens: Option<u64>,
+    /// When `used`/`limit` were read.
+    #[serde(skip)]
+    fetched_at: Option<Instant>,
 }
 
 #[derive(Clone)]
@@ -361,12 +1737,25 @@ enum ProviderState {
     Local { name: String, limit: u64 },
     Manual { name: String, used: u64, limit: u64 },
     HttpJson {
-        config: HttpJsonConfig,
-        last: Option<UsageEntry>,
-        last_error: Option<String>,
+        config: Box<HttpJsonConfig>,
+        /// Written only by this provider's own poller thread.
+        status: Arc<Mutex<HttpJsonStatus>>,
+    },
+    File {
+        config: FileUsageConfig,
+        /// Re-read by this provider's own poller thread.
+        entry: Arc<Mutex<UsageEntry>>,
     },
 }
 
+#[derive(Default)]
+struct HttpJsonStatus {
+    last: Option<UsageEntry>,
+    last_error: Option<String>,
+    /// `refresh_now` asked for a fetch that hasn't finished yet.
+    refresh: bool,
+}
+
 #[derive(Clone)]
 struct HttpJsonConfig {
     name: String,
@@ -376,11 +1765,62 @@ struct HttpJsonConfig {
     body: Option<serde_json::Value>,
     used_pointer: String,
     limit_pointer: String,
+    poll_seconds: u64,
+}
+
+#[derive(Clone)]
+struct FileUsageConfig {
+    name: String,
+    path: PathBuf,
+    used_pointer: String,
+    limit_pointer: String,
+    poll_seconds: u64,
 }
 
 struct UsageManager {
     state: Arc<Mutex<Vec<ProviderState>>>,
-    poll_seconds: u64,
+    paused: Arc<AtomicBool>,
+    wake: Arc<PollWake>,
+    /// Last seen health of each `httpjson` provider, once it has succeeded.
+    healthy: HashMap<String, bool>,
+    /// No `httpjson` poller is running; those providers show `offline`.
+    offline: bool,
+    /// Bumped to retire the running pollers: each thread exits once this
+    /// no longer matches the value it was started with.
+    poll_epoch: Arc<AtomicU64>,
+    /// `primary_provider`: moved to the front of `entries`.
+    primary: Option<String>,
+}
+
+/// Lets the poller threads sleep out their interval while still being woken
+/// early (e.g. on resume).
+struct PollWake {
+    generation: Mutex<u64>,
+    cvar: Condvar,
+}
+
+impl PollWake {
+    fn new() -> Self {
+        Self {
+            generation: Mutex::new(0),
+            cvar: Condvar::new(),
+        }
+    }
+
+    fn notify(&self) {
+        if let Ok(mut generation) = self.generation.lock() {
+            *generation += 1;
+        }
+        self.cvar.notify_all();
+    }
+
+    fn wait(&self, seen: &mut u64, timeout: Duration) {
+        if let Ok(guard) = self.generation.lock() {
+            if let Ok((guard, _)) = self.cvar.wait_timeout_while(guard, timeout, |g| *g == *seen) {
+                *seen = *guard;
+            }
+        }
+    }
 }
 
 impl UsageManager {
@@ -388,7 +1828,7 @@ impl UsageManager {
         let mut providers: Vec<ProviderState> = Vec::new();
         for cfg in &config.providers {
             match cfg {
-                ProviderConfig::Local { name, limit_tokens } => {
+                ProviderConfig::Local { name, limit_tokens, .. } => {
                     providers.push(ProviderState::Local {
                         name: name.clone().unwrap_or_else(|| "local-estimate".to_string()),
                         limit: limit_tokens.unwrap_or(config.context_limit as u64),
@@ -409,9 +1849,10 @@ impl UsageManager {
                     body,
                     used_pointer,
                     limit_pointer,
+                    poll_seconds,
                 } => {
                     providers.push(ProviderState::HttpJson {
-                        config: HttpJsonConfig {
+                        config: Box::new(HttpJsonConfig {
                             name: name.clone(),
                             url: url.clone(),
                             method: method.clone().unwrap_or_else(|| "GET".to_string()),
@@ -419,101 +1860,436 @@ impl UsageManager {
                             body: body.clone(),
                             used_pointer: used_pointer.clone(),
                             limit_pointer: limit_pointer.clone(),
-                        },
-                        last: None,
-                        last_error: None,
+                            poll_seconds: poll_seconds.unwrap_or(config.usage_poll_seconds).max(5),
+                        }),
+                        status: Arc::new(Mutex::new(HttpJsonStatus::default())),
                     });
                 }
+                ProviderConfig::File { name, path, used_pointer, limit_pointer, poll_seconds } => {
+                    let config = FileUsageConfig {
+                        name: name.clone(),
+                        path: path.clone(),
+                        used_pointer: used_pointer.clone(),
+                        limit_pointer: limit_pointer.clone(),
+                        poll_seconds: poll_seconds.unwrap_or(config.usage_poll_seconds).max(1),
+                    };
+                    let entry = Arc::new(Mutex::new(read_file_usage(&config)));
+                    providers.push(ProviderState::File { config, entry });
+                }
             }
         }
         let state = Arc::new(Mutex::new(providers));
         let manager = Self {
             state: Arc::clone(&state),
-            poll_seconds: config.usage_poll_seconds,
+            paused: Arc::new(AtomicBool::new(false)),
+            wake: Arc::new(PollWake::new()),
+            healthy: HashMap::new(),
+            offline: config.offline,
+            poll_epoch: Arc::new(AtomicU64::new(0)),
+            primary: config.primary_provider.clone(),
         };
-        manager.spawn_pollers();
+        if !manager.offline {
+            manager.spawn_pollers();
+        }
+        manager.spawn_file_pollers();
         manager
     }
 
-    fn spawn_pollers(&self) {
-        let state = Arc::clone(&self.state);
-        let poll = self.poll_seconds.max(5);
-        thread::spawn(move || {
-            loop {
-                let configs = {
-                    let guard = state.lock().ok();
-                    guard
-                        .map(|g| {
-                            g.iter()
-                                .enumerate()
-                                .filter_map(|(idx, p)| match p {
-                                    ProviderState::HttpJson { config, .. } => Some((idx, config.clone())),
-                                    _ => None,
-                                })
-                                .collect::<Vec<_>>()
-                        })
-                        .unwrap_or_default()
-                };
+    fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Going offline retires the pollers (a fetch already under way is
+    /// discarded); going back online starts a fresh set, which fetch right
+    /// away.
+    fn toggle_offline(&mut self) -> bool {
+        self.offline = !self.offline;
+        if self.offline {
+            self.poll_epoch.fetch_add(1, Ordering::Relaxed);
+            self.wake.notify();
+        } else {
+            self.spawn_pollers();
+        }
+        self.offline
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Flips the paused flag and returns the new value. Resuming wakes the
+    /// pollers so they fetch right away instead of finishing their sleep.
+    fn toggle_paused(&self) -> bool {
+        let paused = !self.paused.fetch_xor(true, Ordering::Relaxed);
+        if !paused {
+            self.wake.notify();
+        }
+        paused
+    }
+
+    /// Wakes the `httpjson` pollers to fetch now instead of at the end of
+    /// their interval. Returns false when none is polling: offline, paused,
+    /// or no such provider configured.
+    fn refresh_now(&self) -> bool {
+        if self.offline || self.is_paused() {
+            return false;
+        }
+        let mut any = false;
+        if let Ok(guard) = self.state.lock() {
+            for provider in guard.iter() {
+                if let ProviderState::HttpJson { status, .. } = provider {
+                    if let Ok(mut status) = status.lock() {
+                        status.refresh = true;
+                        any = true;
+                    }
+                }
+            }
+        }
+        if any {
+            self.wake.notify();
+        }
+        any
+    }
 
-                for (idx, cfg) in configs {
-                    let result = fetch_http_usage(&cfg);
-                    if let Ok(mut guard) = state.lock() {
-                        if let Some(state_entry) = guard.get_mut(idx) {
-                            if let ProviderState::HttpJson { last, last_error, .. } = state_entry {
-                                match result {
-                                    Ok(entry) => {
-                                        *last = Some(entry);
-                                        *last_error = None;
-                                    }
-                                    Err(err) => {
-                                        *last_error = Some(err);
-                                    }
+    /// A `refresh_now` fetch is still under way.
+    fn is_refreshing(&self) -> bool {
+        self.state.lock().is_ok_and(|guard| {
+            guard.iter().any(|p| match p {
+                ProviderState::HttpJson { status, .. } => status.lock().is_ok_and(|s| s.refresh),
+                _ => false,
+            })
+        })
+    }
+
+    /// One thread per HttpJson provider, each on its own interval, so a slow
+    /// endpoint only delays itself. Threads hold their provider's status
+    /// slot directly rather than an index into `state`.
+    fn spawn_pollers(&self) {
+        let pollers: Vec<(HttpJsonConfig, Arc<Mutex<HttpJsonStatus>>)> = self
+            .state
+            .lock()
+            .map(|guard| {
+                guard
+                    .iter()
+                    .filter_map(|p| match p {
+                        ProviderState::HttpJson { config, status } => Some(((**config).clone(), Arc::clone(status))),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let epoch = self.poll_epoch.load(Ordering::Relaxed);
+        for (cfg, status) in pollers {
+            let paused = Arc::clone(&self.paused);
+            let wake = Arc::clone(&self.wake);
+            let current = Arc::clone(&self.poll_epoch);
+            thread::spawn(move || {
+                let interval = Duration::from_secs(cfg.poll_seconds);
+                let retired = || current.load(Ordering::Relaxed) != epoch;
+                let mut seen = 0;
+                while !retired() {
+                    if !paused.load(Ordering::Relaxed) {
+                        // A refresh asked for mid-fetch stays pending until
+                        // the fetch the wake-up starts.
+                        let refreshing = status.lock().is_ok_and(|s| s.refresh);
+                        let result = fetch_http_usage(&cfg);
+                        match &result {
+                            Ok(entry) => debug_log!("poll {} used={:?} limit={:?}", cfg.name, entry.used, entry.limit),
+                            Err(err) => debug_log!("poll {} failed: {}", cfg.name, err),
+                        }
+                        if retired() {
+                            break;
+                        }
+                        if let Ok(mut status) = status.lock() {
+                            match result {
+                                Ok(entry) => {
+                                    status.last = Some(entry);
+                                    status.last_error = None;
                                 }
+                                Err(err) => status.last_error = Some(err),
+                            }
+                            if refreshing {
+                                status.refresh = false;
                             }
                         }
                     }
+                    wake.wait(&mut seen, interval);
                 }
-                thread::sleep(Duration::from_secs(poll));
-            }
-        });
+            });
+        }
+    }
+
+    /// One thread per `file` provider, re-reading it on its interval or when
+    /// woken, so `entries` never touches the disk. Offline mode leaves them
+    /// running; each exits once the manager, and with it its slot, is gone.
+    fn spawn_file_pollers(&self) {
+        let pollers: Vec<(FileUsageConfig, Weak<Mutex<UsageEntry>>)> = self
+            .state
+            .lock()
+            .map(|guard| {
+                guard
+                    .iter()
+                    .filter_map(|p| match p {
+                        ProviderState::File { config, entry } => Some((config.clone(), Arc::downgrade(entry))),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        for (cfg, slot) in pollers {
+            let paused = Arc::clone(&self.paused);
+            let wake = Arc::clone(&self.wake);
+            thread::spawn(move || {
+                let interval = Duration::from_secs(cfg.poll_seconds);
+                let mut seen = 0;
+                loop {
+                    wake.wait(&mut seen, interval);
+                    let Some(slot) = slot.upgrade() else { break };
+                    if paused.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    let entry = read_file_usage(&cfg);
+                    let Ok(mut current) = slot.lock() else { break };
+                    *current = entry;
+                }
+            });
+        }
+    }
+
+    /// `httpjson` providers that started failing or recovered since the
+    /// last call.
+    fn health_changes(&mut self) -> Vec<ProviderHealth> {
+        if self.offline {
+            return Vec::new();
+        }
+        let current: Vec<(String, Option<Result<(), String>>)> = match self.state.lock() {
+            Ok(guard) => guard
+                .iter()
+                .filter_map(|p| match p {
+                    ProviderState::HttpJson { config, status } => {
+                        let status = status.lock().ok()?;
+                        let state = match (&status.last_error, &status.last) {
+                            (Some(err), _) => Some(Err(err.clone())),
+                            (None, Some(_)) => Some(Ok(())),
+                            (None, None) => None,
+                        };
+                        Some((config.name.clone(), state))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            Err(_) => return Vec::new(),
+        };
+        health_transitions(current, &mut self.healthy)
     }
 
-    fn entries(&self, context_tokens: u64) -> Vec<UsageEntry> {
+    /// Current usage per provider; the local estimate is passed in split
+    /// into input and output tokens.
+    fn entries(&self, input_tokens: u64, output_tokens: u64) -> Vec<UsageEntry> {
         let mut out = Vec::new();
         if let Ok(guard) = self.state.lock() {
             for provider in guard.iter() {
                 match provider {
                     ProviderState::Local { name, limit } => out.push(UsageEntry {
                         name: name.clone(),
-                        used: Some(context_tokens),
+                        used: Some(input_tokens + output_tokens),
                         limit: Some(*limit),
                         status: None,
+                        input_tokens: Some(input_tokens),
+                        output_tokens: Some(output_tokens),
+                        fetched_at: Some(Instant::now()),
                     }),
                     ProviderState::Manual { name, used, limit } => out.push(UsageEntry {
                         name: name.clone(),
                         used: Some(*used),
                         limit: Some(*limit),
                         status: None,
+                        input_tokens: None,
+                        output_tokens: None,
+                        fetched_at: Some(Instant::now()),
                     }),
-                    ProviderState::HttpJson { config, last, last_error } => {
-                        if let Some(entry) = last.clone() {
-                            out.push(entry);
-                        } else {
-                            out.push(UsageEntry {
+                    ProviderState::File { entry, .. } => {
+                        if let Ok(entry) = entry.lock() {
+                            out.push(entry.clone());
+                        }
+                    }
+                    ProviderState::HttpJson { config, .. } if self.offline => out.push(UsageEntry {
+                        name: config.name.clone(),
+                        used: None,
+                        limit: None,
+                        status: Some("offline".to_string()),
+                        input_tokens: None,
+                        output_tokens: None,
+                        fetched_at: None,
+                    }),
+                    ProviderState::HttpJson { config, status } => {
+                        let status = status.lock().ok();
+                        let last = status.as_ref().and_then(|s| s.last.clone());
+                        let last_error = status.as_ref().and_then(|s| s.last_error.clone());
+                        // A failing provider keeps its last good numbers and
+                        // carries the error, so the panel can say how stale
+                        // they are.
+                        out.push(match last {
+                            Some(last) => UsageEntry {
+                                status: last_error,
+                                ..last
+                            },
+                            None => UsageEntry {
                                 name: config.name.clone(),
                                 used: None,
                                 limit: None,
-                                status: last_error.clone().or_else(|| Some("loading".to_string())),
-                            });
-                        }
+                                status: last_error.or_else(|| Some("loading".to_string())),
+                                input_tokens: None,
+                                output_tokens: None,
+                                fetched_at: None,
+                            },
+                        });
                     }
                 }
             }
         }
+        // Only an unambiguous name is pinned; otherwise config order stands.
+        if let Some(primary) = &self.primary {
+            let mut hits = out.iter().enumerate().filter(|(_, e)| &e.name == primary).map(|(i, _)| i);
+            if let (Some(pos), None) = (hits.next(), hits.next()) {
+                let entry = out.remove(pos);
+                out.insert(0, entry);
+            }
+        }
         out
     }
 }
 
+fn usage_ratio(used: u64, limit: u64) -> f64 {
+    if limit == 0 {
+        0.0
+    } else {
+        used as f64 / limit as f64
+    }
+}
+
+/// Returns the entries that are at or above `threshold` and weren't already
+/// reported, updating `notified` so each crossing is reported once.
+fn crossed_threshold<'a>(
+    entries: &'a [UsageEntry],
+    threshold: f64,
+    notified: &mut HashSet<String>,
+) -> Vec<&'a UsageEntry> {
+    let mut crossed = Vec::new();
+    for entry in entries {
+        let (Some(used), Some(limit)) = (entry.used, entry.limit) else {
+            continue;
+        };
+        if usage_ratio(used, limit) >= threshold {
+            if notified.insert(entry.name.clone()) {
+                crossed.push(entry);
+            }
+        } else {
+            notified.remove(&entry.name);
+        }
+    }
+    crossed
+}
+
+#[derive(Debug, PartialEq)]
+enum ProviderHealth {
+    Failing { name: String, error: String },
+    Recovered { name: String },
+}
+
+/// Compares each provider's latest poll (`None` while still loading) with
+/// `healthy`. Only a provider that has worked at least once can start
+/// failing, so a bad config at startup just shows `unavailable` as before.
+fn health_transitions(
+    current: Vec<(String, Option<Result<(), String>>)>,
+    healthy: &mut HashMap<String, bool>,
+) -> Vec<ProviderHealth> {
+    let mut changes = Vec::new();
+    for (name, state) in current {
+        match (healthy.get(&name).copied(), state) {
+            (Some(true), Some(Err(error))) => {
+                healthy.insert(name.clone(), false);
+                changes.push(ProviderHealth::Failing { name, error });
+            }
+            (Some(false), Some(Ok(()))) => {
+                healthy.insert(name.clone(), true);
+                changes.push(ProviderHealth::Recovered { name });
+            }
+            (None, Some(Ok(()))) => {
+                healthy.insert(name, true);
+            }
+            _ => {}
+        }
+    }
+    changes
+}
+
+/// Pipes `text` into the first clipboard tool that works: `pbcopy` on
+/// macOS, otherwise `wl-copy`, `xclip` or `xsel`. Returns false if none did.
+fn copy_to_clipboard(text: &str) -> bool {
+    let tools: &[&[&str]] = if cfg!(target_os = "macos") {
+        &[&["pbcopy"]]
+    } else {
+        &[&["wl-copy"], &["xclip", "-selection", "clipboard"], &["xsel", "--clipboard", "--input"]]
+    };
+    tools.iter().any(|tool| {
+        let child = std::process::Command::new(tool[0])
+            .args(&tool[1..])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn();
+        let Ok(mut child) = child else {
+            return false;
+        };
+        let written = child.stdin.take().map(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+        matches!(child.wait(), Ok(status) if status.success()) && written == Some(true)
+    })
+}
+
+/// Best effort: uses `osascript` on macOS and `notify-send` elsewhere, and
+/// silently does nothing if neither is available. Runs on its own thread,
+/// which waits for the tool so it doesn't linger as a zombie.
+fn send_desktop_notification(title: &str, body: &str) {
+    let mut cmd = if cfg!(target_os = "macos") {
+        let script = format!(
+            "display notification {} with title {}",
+            applescript_string(body),
+            applescript_string(title)
+        );
+        let mut cmd = std::process::Command::new("osascript");
+        cmd.arg("-e").arg(script);
+        cmd
+    } else {
+        let mut cmd = std::process::Command::new("notify-send");
+        cmd.arg(title).arg(body);
+        cmd
+    };
+    cmd.stdout(std::process::Stdio::null()).stderr(std::process::Stdio::null());
+    thread::spawn(move || {
+        let _ = cmd.status();
+    });
+}
+
+/// Quotes `text` as an AppleScript string literal, which only knows `\\`
+/// and `\"` escapes; control characters become spaces.
+fn applescript_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '\\' | '"' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push(' '),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 fn fetch_http_usage(cfg: &HttpJsonConfig) -> Result<UsageEntry, String> {
     let mut cmd = std::process::Command::new("curl");
     cmd.arg("-sS").arg("-f").arg("-X").arg(&cfg.method).arg(&cfg.url);
@@ -530,13 +2306,46 @@ fn fetch_http_usage(cfg: &HttpJsonConfig) -> Result<UsageEntry, String> {
     }
     let json: serde_json::Value =
         serde_json::from_slice(&output.stdout).map_err(|e| e.to_string())?;
-    let used = extract_u64(&json, &cfg.used_pointer)?;
-    let limit = extract_u64(&json, &cfg.limit_pointer)?;
+    usage_from_json(&cfg.name, &json, &cfg.used_pointer, &cfg.limit_pointer)
+}
+
+fn usage_from_json(
+    name: &str,
+    json: &serde_json::Value,
+    used_pointer: &str,
+    limit_pointer: &str,
+) -> Result<UsageEntry, String> {
+    let used = extract_u64(json, used_pointer)?;
+    let limit = extract_u64(json, limit_pointer)?;
     Ok(UsageEntry {
-        name: cfg.name.clone(),
+        name: name.to_string(),
         used: Some(used),
         limit: Some(limit),
         status: None,
+        input_tokens: None,
+        output_tokens: None,
+        fetched_at: Some(Instant::now()),
+    })
+}
+
+/// A `file` provider's entry: its numbers, or `unavailable` while the file
+/// is missing, or the read/parse error.
+fn read_file_usage(cfg: &FileUsageConfig) -> UsageEntry {
+    let result = match fs::read_to_string(&cfg.path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err("unavailable".to_string()),
+        Err(err) => Err(err.to_string()),
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| format!("bad JSON: {}", e))
+            .and_then(|json| usage_from_json(&cfg.name, &json, &cfg.used_pointer, &cfg.limit_pointer)),
+    };
+    result.unwrap_or_else(|err| UsageEntry {
+        name: cfg.name.clone(),
+        used: None,
+        limit: None,
+        status: Some(err),
+        input_tokens: None,
+        output_tokens: None,
+        fetched_at: None,
     })
 }
 
@@ -551,16 +2360,115 @@ fn extract_u64(value: &serde_json::Value, pointer: &str) -> Result<u64, String>
     }
 }
 
+/// What the Usage panel last rendered, shared with the metrics endpoint.
+#[derive(Clone, Default, Serialize)]
+struct MetricsSnapshot {
+    context_tokens: u64,
+    context_limit: u64,
+    providers: Vec<UsageEntry>,
+}
+
+impl MetricsSnapshot {
+    fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP ccwb_context_tokens Estimated tokens in the current context.\n");
+        out.push_str("# TYPE ccwb_context_tokens gauge\n");
+        out.push_str(&format!("ccwb_context_tokens {}\n", self.context_tokens));
+        out.push_str("# HELP ccwb_context_limit_tokens Context window the estimate is measured against.\n");
+        out.push_str("# TYPE ccwb_context_limit_tokens gauge\n");
+        out.push_str(&format!("ccwb_context_limit_tokens {}\n", self.context_limit));
+        out.push_str("# HELP ccwb_provider_used_tokens Tokens used as reported by each provider.\n");
+        out.push_str("# TYPE ccwb_provider_used_tokens gauge\n");
+        for entry in &self.providers {
+            if let Some(used) = entry.used {
+                out.push_str(&format!(
+                    "ccwb_provider_used_tokens{{provider=\"{}\"}} {}\n",
+                    prometheus_label(&entry.name),
+                    used
+                ));
+            }
+        }
+        out.push_str("# HELP ccwb_provider_limit_tokens Token limit as reported by each provider.\n");
+        out.push_str("# TYPE ccwb_provider_limit_tokens gauge\n");
+        for entry in &self.providers {
+            if let Some(limit) = entry.limit {
+                out.push_str(&format!(
+                    "ccwb_provider_limit_tokens{{provider=\"{}\"}} {}\n",
+                    prometheus_label(&entry.name),
+                    limit
+                ));
+            }
+        }
+        out
+    }
+}
+
+fn prometheus_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves `/metrics` (Prometheus text) and `/` or `/json` (JSON) from the
+/// shared snapshot. Connections are handled one at a time; this is meant for
+/// an occasional local scraper, not general traffic.
+fn spawn_metrics_server(listener: TcpListener, shared: Arc<Mutex<MetricsSnapshot>>) {
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = serve_metrics(stream, &shared);
+        }
+    });
+}
+
+fn serve_metrics(mut stream: TcpStream, shared: &Mutex<MetricsSnapshot>) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while head.len() < 8192 && !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&head);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    let snapshot = shared.lock().map(|s| s.clone()).unwrap_or_default();
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", snapshot.to_prometheus()),
+        "/" | "/json" => ("200 OK", "application/json", serde_json::to_string(&snapshot)?),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
 #[derive(Clone)]
 struct SnapshotJob {
     message_id: String,
     message_idx: i64,
+    /// Taken when the turn ended rather than when the prompt was sent.
+    after_turn: bool,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 struct SnapshotResult {
     message_id: String,
+    after_turn: bool,
     commit: Option<String>,
+    error: Option<String>,
+    /// `SnapshotManager::commit_stats` of `commit`, worked out by the worker
+    /// so the UI thread runs no git.
+    changed_files: Option<usize>,
+    diff_stat: Option<(usize, usize)>,
 }
 
 fn spawn_snapshot_worker(
@@ -570,60 +2478,304 @@ fn spawn_snapshot_worker(
 ) {
     thread::spawn(move || {
         while let Ok(job) = rx.recv() {
-            let result = manager.snapshot(job.message_idx);
-            let res = match result {
-                Ok(commit) => SnapshotResult {
-                    message_id: job.message_id,
-                    commit: Some(commit),
-                },
-                Err(_err) => SnapshotResult {
-                    message_id: job.message_id,
-                    commit: None,
-                },
+            // Only the latest tree state matters, so jobs that piled up while
+            // the previous snapshot ran share a single commit.
+            let mut jobs = vec![job];
+            jobs.extend(rx.try_iter());
+            let latest_idx = jobs.iter().map(|j| j.message_idx).max().unwrap_or_default();
+            let started = Instant::now();
+            let (commit, error) = match manager.snapshot(latest_idx) {
+                Ok(commit) => (Some(commit), None),
+                Err(err) => (None, Some(err.to_string())),
             };
-            let _ = tx.send(res);
+            // Only the prompt-time snapshot shows its stats in History.
+            let (changed_files, diff_stat) = match &commit {
+                Some(commit) if jobs.iter().any(|j| !j.after_turn) => manager.commit_stats(commit),
+                _ => (None, None),
+            };
+            debug_log!(
+                "snapshot done idx={} jobs={} in {:?} commit={:?} error={:?}",
+                latest_idx,
+                jobs.len(),
+                started.elapsed(),
+                commit,
+                error
+            );
+            for job in jobs {
+                let _ = tx.send(SnapshotResult {
+                    message_id: job.message_id,
+                    after_turn: job.after_turn,
+                    commit: commit.clone(),
+                    error: error.clone(),
+                    changed_files,
+                    diff_stat,
+                });
+            }
         }
     });
 }
 
+/// Keeps `repo_stats` fresh for the Context panel, which only reads the
+/// cached value, so git and the directory walk stay off the UI thread.
+fn spawn_repo_stats_poller(manager: SnapshotManager) {
+    thread::spawn(move || loop {
+        manager.repo_stats();
+        thread::sleep(REPO_STATS_TTL);
+    });
+}
+
+/// How History and the diff overlay show timestamps; `t` switches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum TimeMode {
+    #[default]
+    Relative,
+    Absolute,
+}
+
+impl TimeMode {
+    fn format(self, ts: DateTime<Utc>) -> String {
+        match self {
+            TimeMode::Relative => format_age(Utc::now() - ts),
+            TimeMode::Absolute => ts.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string(),
+        }
+    }
+}
+
+/// Usage panel state that outlives a single draw.
+#[derive(Debug, Default)]
+struct UsageView {
+    /// Index into the provider entries of the one drawn first, with a bar.
+    selected: usize,
+    mode: UsageMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum UsageMode {
+    /// `used / limit tokens`.
+    #[default]
+    Tokens,
+    /// Share of the limit.
+    Percent,
+}
+
+impl UsageView {
+    fn select_next(&mut self, providers: usize) {
+        self.selected = if providers == 0 { 0 } else { (self.selected + 1) % providers };
+    }
+
+    fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            UsageMode::Tokens => UsageMode::Percent,
+            UsageMode::Percent => UsageMode::Tokens,
+        };
+    }
+
+    /// `entries` with the selected provider moved to the front.
+    fn order<'a>(&self, entries: &'a [UsageEntry]) -> Vec<&'a UsageEntry> {
+        let selected = self.selected.min(entries.len().saturating_sub(1));
+        let mut ordered: Vec<&UsageEntry> = entries.iter().collect();
+        if !ordered.is_empty() {
+            let first = ordered.remove(selected);
+            ordered.insert(0, first);
+        }
+        ordered
+    }
+
+    fn amount(&self, name: &str, used: u64, limit: u64) -> String {
+        match self.mode {
+            UsageMode::Tokens => format!("{}: {} / {} tokens", name, used, limit),
+            UsageMode::Percent => format!("{}: {:.1}%", name, usage_ratio(used, limit) * 100.0),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct MessageEntry {
     id: String,
     idx: i64,
     content: String,
     output_line: usize,
+    /// First output line after the reply, set when the turn goes quiet.
+    /// What follows, up to the next prompt, is the next prompt being typed.
+    output_end: Option<usize>,
     assistant_text: String,
     snapshot_commit: Option<String>,
+    /// Snapshot from when the turn ended, with `snapshot_after_turn`.
+    snapshot_commit_after: Option<String>,
+    changed_files: Option<usize>,
+    /// Lines added and removed since the previous snapshot.
+    diff_stat: Option<(usize, usize)>,
+    snapshot_error: Option<String>,
+    /// No snapshot by design: the prompt matched a snapshot skip rule.
+    snapshot_skipped: bool,
+    created_at: DateTime<Utc>,
+}
+
+/// A compaction Claude reported, shown in History between prompts. Not a
+/// prompt: no database row or snapshot.
+struct CompactionMarker {
+    /// Prompts before it, i.e. where it sits in History.
+    after: usize,
+    output_line: usize,
+    /// Context estimate the compaction reclaimed.
+    reclaimed: u32,
 }
 
 struct App {
     config: Config,
     session_id: String,
     messages: Vec<MessageEntry>,
+    compactions: Vec<CompactionMarker>,
     output_lines: Vec<String>,
+    output_cursor: usize,
     output_scroll: usize,
+    /// Rows of the output panel, kept up to date by `run_app` for paging.
+    output_height: usize,
     follow_output: bool,
-    input_buffer: String,
+    /// Output panel hides the echoed prompts between replies (`F6`).
+    assistant_only: bool,
+    input: InputLine,
     focus: Focus,
     selected_message: usize,
     diff_preview: Option<DiffPreview>,
+    /// File `o` in the diff preview asked to open; `run_app` hands the
+    /// terminal to `$EDITOR` for it.
+    pending_edit: Option<PathBuf>,
+    palette: Option<CommandPalette>,
+    /// The `:` command line, open while typing an app command.
+    command_line: Option<InputLine>,
+    settings: Option<SettingsEditor>,
+    /// Where the settings editor saves: the `--config` file, or the
+    /// workspace's `.cc-workbench/config.json`.
+    config_path: PathBuf,
+    /// Quit was requested while work was in flight; waiting for y/n.
+    confirm_quit: bool,
+    time_display: TimeMode,
+    usage_view: UsageView,
     usage_manager: UsageManager,
     snapshot_job_tx: Sender<SnapshotJob>,
+    /// Jobs handed to the snapshot worker whose result hasn't come back.
+    in_flight_snapshots: usize,
+    /// Message whose after-turn snapshot `take_snapshot` was asked to
+    /// replace; asking again for it goes ahead.
+    confirm_snapshot: Option<String>,
     snapshot_manager: SnapshotManager,
+    snapshots_enabled: bool,
+    /// Why `snapshots_enabled` is false, for the History panel.
+    snapshots_off: String,
     dirty: bool,
+    last_output: Option<Instant>,
+    /// When the latest prompt was sent, until its turn is finalized.
+    turn_started: Option<Instant>,
+    spinner_frame: usize,
+    spinner_active: bool,
+    fullscreen_output: bool,
+    split_percent: u16,
+    inspect: bool,
+    /// Another instance holds the workspace lock, so inspect mode can't be
+    /// turned off.
+    locked_out: bool,
+    /// First word of `claude --version`, when the startup probe ran.
+    claude_version: Option<String>,
+    /// `--initial-prompt`, until it has been sent.
+    pending_prompt: Option<String>,
+    /// Input and output estimates already accounted for by Claude's last
+    /// compaction.
+    context_baseline: (u32, u32),
+    /// Output lines below this index have been checked for `compaction_marker`.
+    marker_scanned: usize,
+    metrics: Option<Arc<Mutex<MetricsSnapshot>>>,
+    notified_providers: HashSet<String>,
+    /// Output lines written by the workbench itself, and their color.
+    notices: HashMap<usize, Color>,
+    session_log: Option<SessionLog>,
+    /// Stderr text after its last newline, shown once the line completes.
+    stderr_partial: String,
 }
 
-#[derive(Clone, Copy)]
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const ACTIVITY_WINDOW: Duration = Duration::from_secs(1);
+/// How often buffered session log writes reach the file.
+const SESSION_LOG_FLUSH: Duration = Duration::from_secs(1);
+
+/// Plain-text copy of a session's output, for grepping after the fact.
+struct SessionLog {
+    path: PathBuf,
+    writer: io::BufWriter<fs::File>,
+    flushed: Instant,
+}
+
+impl SessionLog {
+    /// Opens `dir/<session_id>.log` for appending.
+    fn open(dir: &Path, session_id: &str) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.log", session_id));
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            writer: io::BufWriter::new(file),
+            flushed: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.writer.write_all(text.as_bytes())?;
+        self.flush_if_due()
+    }
+
+    fn flush_if_due(&mut self) -> io::Result<()> {
+        if self.flushed.elapsed() >= SESSION_LOG_FLUSH {
+            self.writer.flush()?;
+            self.flushed = Instant::now();
+        }
+        Ok(())
+    }
+}
+/// Longest gap between frames while Claude is producing output, even when
+/// the output itself (e.g. pure escape sequences) didn't change any line.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// How long quitting waits for in-flight snapshots.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const PROMPT_IDLE: Duration = Duration::from_millis(500);
+const PROMPT_MAX_WAIT: Duration = Duration::from_secs(10);
+/// Default `--timeout` for `--headless`.
+const HEADLESS_TIMEOUT: Duration = Duration::from_secs(600);
+/// Exit status when `--headless` runs out of time, as with timeout(1).
+const HEADLESS_TIMEOUT_EXIT: i32 = 124;
+/// Most output `run_app` takes from the PTY per loop before drawing and
+/// reading keys again, so a flood of output can't starve the UI.
+const OUTPUT_DRAIN_BUDGET: usize = 256 * 1024;
+const MIN_SCROLLBACK_LINES: usize = 100;
+const MIN_LINE_CHARS: usize = 80;
+
+#[derive(Debug, Clone, Copy)]
 enum Focus {
     Output,
     History,
 }
 
 struct DiffPreview {
+    /// Everything but the commit time, which follows `App.time_display`.
     title: String,
+    committed_at: Option<DateTime<Utc>>,
+    commit: String,
+    /// End of the range for a turn diff; otherwise `commit` is compared
+    /// with the workspace.
+    until: Option<String>,
     lines: Vec<String>,
+    /// First visible line; lines are not wrapped, so this is also a row.
     scroll: usize,
-    pending_restore: Option<String>,
+    /// Columns scrolled off to the left.
+    hscroll: usize,
+    /// Text searched for with `/`; matching lines are highlighted.
+    query: String,
+    /// `scroll` when `/` was pressed, while the query is still being typed.
+    search_from: Option<usize>,
+    /// Line of the match `n`/`N` last moved to.
+    current_match: Option<usize>,
+    pending_restore: Option<RestorePlan>,
+    status: Option<String>,
 }
 
 impl App {
@@ -638,27 +2790,235 @@ impl App {
             config,
             session_id,
             messages: Vec::new(),
+            compactions: Vec::new(),
             output_lines: vec![String::new()],
+            output_cursor: 0,
             output_scroll: 0,
+            output_height: 0,
             follow_output: true,
-            input_buffer: String::new(),
+            assistant_only: false,
+            input: InputLine::default(),
             focus: Focus::Output,
             selected_message: 0,
             diff_preview: None,
+            pending_edit: None,
+            palette: None,
+            command_line: None,
+            settings: None,
+            config_path: PathBuf::from(".cc-workbench").join("config.json"),
+            confirm_quit: false,
+            time_display: TimeMode::default(),
+            usage_view: UsageView::default(),
             snapshot_job_tx,
+            in_flight_snapshots: 0,
+            confirm_snapshot: None,
             snapshot_manager,
+            snapshots_enabled: true,
+            snapshots_off: "git unavailable".to_string(),
             dirty: true,
+            last_output: None,
+            turn_started: None,
+            spinner_frame: 0,
+            spinner_active: false,
+            fullscreen_output: false,
+            split_percent: UiState::default().split_percent,
+            inspect: false,
+            locked_out: false,
+            claude_version: None,
+            pending_prompt: None,
+            context_baseline: (0, 0),
+            marker_scanned: 0,
+            metrics: None,
+            notified_providers: HashSet::new(),
+            notices: HashMap::new(),
+            session_log: None,
+            stderr_partial: String::new(),
+        }
+    }
+
+    fn apply_ui_state(&mut self, state: &UiState) {
+        self.fullscreen_output = state.fullscreen_output;
+        self.split_percent = state.split_percent;
+    }
+
+    fn ui_state(&self) -> UiState {
+        UiState {
+            fullscreen_output: self.fullscreen_output,
+            split_percent: self.split_percent,
+        }
+    }
+
+    fn is_generating(&self) -> bool {
+        self.last_output
+            .map(|t| t.elapsed() < ACTIVITY_WINDOW)
+            .unwrap_or(false)
+    }
+
+    /// Advances the spinner while output is flowing; repaints once more when
+    /// it goes idle so the idle marker replaces the last frame.
+    fn tick(&mut self) {
+        if let Some(log) = self.session_log.as_mut() {
+            if let Err(err) = log.flush_if_due() {
+                self.drop_session_log(err);
+            }
+        }
+        let active = self.is_generating();
+        if active {
+            self.spinner_frame = (self.spinner_frame + 1) % SPINNER_FRAMES.len();
+            self.dirty = true;
+        } else if self.spinner_active {
+            self.dirty = true;
+        }
+        self.spinner_active = active;
+    }
+
+    /// Refreshes the snapshot served by the metrics endpoint from the same
+    /// values the Usage and Context panels render.
+    fn publish_metrics(&self) {
+        if let Some(metrics) = &self.metrics {
+            let context_tokens = self.estimate_context_tokens() as u64;
+            let providers = self.usage_entries();
+            if let Ok(mut snapshot) = metrics.lock() {
+                *snapshot = MetricsSnapshot {
+                    context_tokens,
+                    context_limit: self.context_limit(&providers),
+                    providers,
+                };
+            }
+        }
+    }
+
+    /// Fires a desktop notification the first time a provider crosses
+    /// `notify_threshold`; it re-arms once usage drops back below.
+    fn check_usage_notifications(&mut self) {
+        let Some(threshold) = self.config.notify_threshold else {
+            return;
+        };
+        let entries = self.usage_entries();
+        for entry in crossed_threshold(&entries, threshold, &mut self.notified_providers) {
+            let (used, limit) = (entry.used.unwrap_or(0), entry.limit.unwrap_or(0));
+            let body = format!(
+                "{} at {:.0}% ({} / {} tokens)",
+                entry.name,
+                usage_ratio(used, limit) * 100.0,
+                used,
+                limit
+            );
+            send_desktop_notification("cc-workbench usage", &body);
+        }
+    }
+
+    /// Puts a banner in the output panel with a terminal bell when a usage
+    /// provider starts failing, and a quieter note when it recovers.
+    fn check_provider_health(&mut self) {
+        for change in self.usage_manager.health_changes() {
+            match change {
+                ProviderHealth::Failing { name, error } => {
+                    let _ = io::stdout().write_all(b"\x07").and_then(|_| io::stdout().flush());
+                    self.push_notice(format!("⚠ usage provider {} failing: {}", name, error), Color::Red);
+                }
+                ProviderHealth::Recovered { name } => {
+                    self.push_notice(format!("✓ usage provider {} recovered", name), Color::DarkGray);
+                }
+            }
+        }
+    }
+
+    /// Copies the selected message's snapshot commit with `copy`, falling
+    /// back to a notice with the hash when there is no clipboard.
+    fn copy_selected_commit(&mut self, copy: impl FnOnce(&str) -> bool) {
+        let Some(msg) = self.messages.get(self.selected_message) else {
+            return;
+        };
+        let note = match &msg.snapshot_commit {
+            None => format!("No snapshot for message #{} yet", msg.idx),
+            Some(commit) if copy(commit) => format!("Copied snapshot {} to the clipboard", commit),
+            Some(commit) => format!("Snapshot for message #{}: {}", msg.idx, commit),
+        };
+        self.push_notice(note, Color::DarkGray);
+    }
+
+    /// Inserts a workbench line into the output, just above the line Claude
+    /// is still writing so its cursor stays valid.
+    fn push_notice(&mut self, text: String, color: Color) {
+        let at = self.output_lines.len().saturating_sub(1);
+        self.output_lines.insert(at, text);
+        for msg in &mut self.messages {
+            if msg.output_line >= at {
+                msg.output_line += 1;
+            }
+            if let Some(end) = msg.output_end.as_mut().filter(|end| **end >= at) {
+                *end += 1;
+            }
+        }
+        for marker in &mut self.compactions {
+            if marker.output_line >= at {
+                marker.output_line += 1;
+            }
+        }
+        if self.marker_scanned >= at {
+            self.marker_scanned += 1;
+        }
+        self.notices.insert(at, color);
+        if self.follow_output {
+            self.output_scroll = self.output_lines.len().saturating_sub(1);
+        }
+        self.dirty = true;
+    }
+
+    /// Moves what points into the output by line index after the lines in
+    /// `range` were removed from it. Anything inside the range moves to its
+    /// start.
+    fn lines_drained(&mut self, range: Range<usize>) {
+        let shift = |idx: usize| if idx >= range.end { idx - range.len() } else { idx.min(range.start) };
+        self.notices = self
+            .notices
+            .drain()
+            .filter(|(idx, _)| !range.contains(idx))
+            .map(|(idx, color)| (shift(idx), color))
+            .collect();
+        for msg in &mut self.messages {
+            msg.output_line = shift(msg.output_line);
+            msg.output_end = msg.output_end.map(shift);
+        }
+        for marker in &mut self.compactions {
+            marker.output_line = shift(marker.output_line);
+        }
+        self.marker_scanned = shift(self.marker_scanned);
+        if !self.follow_output {
+            self.output_scroll = shift(self.output_scroll);
         }
     }
 
     fn handle_output(&mut self, chunk: OutputChunk) {
+        self.last_output = Some(Instant::now());
         let cleaned = strip_ansi(&chunk.text);
+        if chunk.stream == OutputStream::Stderr {
+            self.handle_stderr(&cleaned);
+            return;
+        }
         // Only mark as dirty if there's actual content
         if !cleaned.is_empty() {
-            append_output_lines(&mut self.output_lines, &cleaned);
-            if let Some(last) = self.messages.last_mut() {
+            let drained = append_output_lines(
+                &mut self.output_lines,
+                &mut self.output_cursor,
+                &cleaned,
+                self.config.collapse_blank_lines,
+                self.config.scrollback_lines,
+                self.config.max_line_chars,
+            );
+            for range in drained {
+                self.lines_drained(range);
+            }
+            if let Some(last) = self.last_turn_mut() {
                 last.assistant_text.push_str(&cleaned);
             }
+            if let Some(log) = self.session_log.as_mut() {
+                if let Err(err) = log.write(&cleaned) {
+                    self.drop_session_log(err);
+                }
+            }
+            self.scan_compaction_marker();
             if self.follow_output {
                 let total_lines = self.output_lines.len();
                 self.output_scroll = total_lines.saturating_sub(1);
@@ -667,101 +3027,583 @@ impl App {
         }
     }
 
+    /// Shows each complete stderr line as an `[err]` notice above the line
+    /// Claude is drawing, so its own cursor position is left alone.
+    fn handle_stderr(&mut self, text: &str) {
+        self.stderr_partial.push_str(text);
+        while let Some(end) = self.stderr_partial.find('\n') {
+            let line: String = self.stderr_partial.drain(..=end).collect();
+            let line = format!("[err] {}", line.trim_end_matches(['\r', '\n']));
+            if let Some(log) = self.session_log.as_mut() {
+                if let Err(err) = log.write(&format!("{}\n", line)) {
+                    self.drop_session_log(err);
+                }
+            }
+            self.push_notice(line, Color::LightRed);
+        }
+    }
+
+    /// Opens the session log in `dir`; if that fails the session runs
+    /// without one, as after `drop_session_log`.
+    fn start_session_log(&mut self, dir: &Path) {
+        match SessionLog::open(dir, &self.session_id) {
+            Ok(log) => self.session_log = Some(log),
+            Err(err) => {
+                self.push_notice(format!("Session log in {} not started: {}", dir.display(), err), Color::Red)
+            }
+        }
+    }
+
+    /// Stops logging after a write fails, with a note in the output.
+    fn drop_session_log(&mut self, err: io::Error) {
+        if let Some(log) = self.session_log.take() {
+            self.push_notice(format!("Session log {} stopped: {}", log.path.display(), err), Color::Red);
+        }
+    }
+
+    /// Full text of the latest prompt, for re-sending it.
+    fn last_prompt(&self) -> Option<String> {
+        self.messages.last().map(|m| m.content.clone())
+    }
+
+    fn last_turn_mut(&mut self) -> Option<&mut MessageEntry> {
+        self.messages.last_mut()
+    }
+
+    /// Checks newly completed output lines against `compaction_marker`; a
+    /// match adds a History marker and restarts the context estimate there.
+    fn scan_compaction_marker(&mut self) {
+        let complete = self.output_lines.len().saturating_sub(1);
+        // `lines_drained` moves this down as old lines are trimmed.
+        let from = self.marker_scanned.min(complete);
+        self.marker_scanned = complete;
+        let Some(marker) = &self.config.compaction_marker else {
+            return;
+        };
+        let Some(hit) = (from..complete).find(|&i| marker.is_match(&self.output_lines[i])) else {
+            return;
+        };
+        let reclaimed = self.estimate_context_tokens();
+        self.context_baseline = self.raw_token_split();
+        self.compactions.push(CompactionMarker {
+            after: self.messages.len(),
+            output_line: hit,
+            reclaimed,
+        });
+    }
+
+    /// Selects the first or last History entry; the list scrolls to keep
+    /// the selection visible when drawn.
+    fn select_history_edge(&mut self, last: bool) {
+        self.selected_message = if last { self.messages.len().saturating_sub(1) } else { 0 };
+    }
+
+    fn toggle_time_display(&mut self) {
+        self.time_display = match self.time_display {
+            TimeMode::Relative => TimeMode::Absolute,
+            TimeMode::Absolute => TimeMode::Relative,
+        };
+    }
+
+    /// Empties the output panel, leaving a marker line. History and the
+    /// database are untouched; entries from before the clear jump to the marker.
+    fn clear_output(&mut self) {
+        self.output_lines = vec!["── cleared ──".to_string(), String::new()];
+        self.output_cursor = 0;
+        self.output_scroll = 1;
+        self.follow_output = true;
+        self.marker_scanned = 1;
+        self.notices.clear();
+        for marker in &mut self.compactions {
+            marker.output_line = 0;
+        }
+        for msg in &mut self.messages {
+            msg.output_line = 0;
+            if msg.output_end.is_some() {
+                msg.output_end = Some(1);
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Freezes the view where it is, or lets new output move it again;
+    /// `output_scroll` is left alone either way.
+    fn toggle_follow(&mut self) {
+        self.follow_output = !self.follow_output;
+        self.dirty = true;
+    }
+
+    fn toggle_assistant_only(&mut self) {
+        self.assistant_only = !self.assistant_only;
+        self.dirty = true;
+    }
+
+    /// Whether output line `idx` is a prompt echoed back while it was typed:
+    /// a prompt's own line, or anything between the end of a reply and the
+    /// next prompt. Lines before the first prompt and workbench notices are
+    /// never hidden. A heuristic, since Claude draws both into one stream.
+    fn is_prompt_echo(&self, idx: usize) -> bool {
+        if self.notices.contains_key(&idx) {
+            return false;
+        }
+        if self.messages.iter().any(|m| m.output_line == idx) {
+            return true;
+        }
+        self.messages
+            .iter()
+            .rfind(|m| m.output_line < idx)
+            .and_then(|m| m.output_end)
+            .is_some_and(|end| idx >= end)
+    }
+
+    /// Moves the output view by `delta` lines (negative is up). Following
+    /// resumes only once the view is back at the last line.
+    fn scroll_output(&mut self, delta: isize) {
+        self.output_scroll = scroll_target(self.output_scroll, delta, self.output_height, self.output_lines.len());
+        self.follow_output = self.output_scroll + 1 >= self.output_lines.len();
+    }
+
+    /// Output line where the turn containing `idx` was submitted, if `idx`
+    /// is part of an assistant reply (i.e. after some message's prompt line).
+    fn turn_start(&self, idx: usize) -> Option<usize> {
+        self.messages
+            .iter()
+            .rev()
+            .map(|m| m.output_line)
+            .find(|&line| line < idx)
+    }
+
+    /// Whether a fenced code block is open just before output line `idx`.
+    fn code_block_open_at(&self, idx: usize) -> bool {
+        let Some(turn) = self.turn_start(idx) else {
+            return false;
+        };
+        let end = idx.min(self.output_lines.len());
+        self.output_lines[(turn + 1).min(end)..end]
+            .iter()
+            .filter(|l| l.trim_start().starts_with("```"))
+            .count()
+            % 2
+            == 1
+    }
+
+    /// Width of the line-number gutter (digits plus a separating space), or
+    /// zero when line numbers are off.
+    fn line_number_gutter(&self) -> usize {
+        if !self.config.show_line_numbers {
+            return 0;
+        }
+        self.output_lines.len().max(1).to_string().len() + 1
+    }
+
+    /// Rows at the bottom of the output panel taken by `show_input_line`.
+    fn input_line_height(&self) -> u16 {
+        u16::from(self.config.show_input_line)
+    }
+
+    fn estimate_tokens(&self, text: &str) -> u32 {
+        estimate_tokens(text, self.config.chars_per_token)
+    }
+
+    /// Context estimate since Claude last compacted.
     fn estimate_context_tokens(&self) -> u32 {
-        let mut total = 0u32;
+        self.estimate_input_tokens() + self.estimate_output_tokens()
+    }
+
+    /// Prompt side of `estimate_context_tokens`.
+    fn estimate_input_tokens(&self) -> u32 {
+        self.raw_token_split().0.saturating_sub(self.context_baseline.0)
+    }
+
+    /// Reply side of `estimate_context_tokens`.
+    fn estimate_output_tokens(&self) -> u32 {
+        self.raw_token_split().1.saturating_sub(self.context_baseline.1)
+    }
+
+    /// Input and output estimates over the whole session.
+    fn raw_token_split(&self) -> (u32, u32) {
+        let (mut input, mut output) = (0u32, 0u32);
         for msg in &self.messages {
-            total += estimate_tokens(&msg.content);
-            total += estimate_tokens(&msg.assistant_text);
+            input += self.estimate_tokens(&msg.content);
+            output += self.estimate_tokens(&msg.assistant_text);
         }
-        total
+        (input, output)
+    }
+
+    fn usage_entries(&self) -> Vec<UsageEntry> {
+        self.usage_manager
+            .entries(self.estimate_input_tokens() as u64, self.estimate_output_tokens() as u64)
+    }
+
+    /// Context window the panel measures against. With `auto_context_limit`
+    /// this is the limit the primary provider (first in `entries`) last
+    /// reported; while it is loading, failing or reports no limit,
+    /// `context_limit` from the config is used instead.
+    fn context_limit(&self, entries: &[UsageEntry]) -> u64 {
+        let reported = entries
+            .first()
+            .filter(|e| self.config.auto_context_limit && e.status.is_none())
+            .and_then(|e| e.limit)
+            .filter(|&limit| limit > 0);
+        reported.unwrap_or(self.config.context_limit as u64)
+    }
+
+    /// Persists the assistant text and output token estimate of the latest
+    /// turn, and the first time also its duration. Called once Claude has been
+    /// quiet for `assistant_idle`, and again when the next message starts and
+    /// on exit in case more output came in since.
+    fn finalize_last_turn(&mut self, db: &mut Database) -> Result<()> {
+        let started = self.turn_started.take();
+        if started.is_some() {
+            let end = self.output_lines.len().saturating_sub(1);
+            if let Some(last) = self.last_turn_mut() {
+                last.output_end = Some(end);
+            }
+        }
+        if let Some(last) = self.messages.last() {
+            db.set_assistant_text(&last.id, &last.assistant_text)?;
+            db.set_output_tokens(&last.id, self.estimate_tokens(&last.assistant_text))?;
+            if let (Some(started), Some(output)) = (started, self.last_output) {
+                if output > started {
+                    db.set_duration_ms(&last.id, (output - started).as_millis() as u64)?;
+                }
+            }
+            // Once per turn, the first time it is finalized.
+            if started.is_some() && self.config.snapshot_after_turn && self.snapshots_enabled && !last.snapshot_skipped {
+                debug_log!("snapshot enqueue idx={} after turn", last.idx);
+                let job = SnapshotJob {
+                    message_id: last.id.clone(),
+                    message_idx: last.idx,
+                    after_turn: true,
+                };
+                self.enqueue_snapshot(job);
+            }
+        }
+        Ok(())
+    }
+
+    /// Finalizes the open turn once Claude has answered and then gone quiet
+    /// for `assistant_idle`.
+    fn check_turn_idle(&mut self, db: &mut Database, now: Instant) -> Result<()> {
+        let (Some(started), Some(output)) = (self.turn_started, self.last_output) else {
+            return Ok(());
+        };
+        if output > started && now.saturating_duration_since(output) >= self.config.assistant_idle {
+            self.finalize_last_turn(db)?;
+        }
+        Ok(())
+    }
+
+    /// Whether to quit right away; otherwise opens the confirmation, which
+    /// `require_quit_confirm` makes unconditional.
+    fn request_quit(&mut self) -> bool {
+        if self.quit_blockers().is_empty() && !self.config.require_quit_confirm {
+            return true;
+        }
+        self.confirm_quit = true;
+        false
+    }
+
+    /// Why quitting now could lose something, if anything: snapshots still
+    /// being committed or Claude still writing.
+    fn quit_blockers(&self) -> Vec<String> {
+        let mut blockers = Vec::new();
+        match self.in_flight_snapshots {
+            0 => {}
+            1 => blockers.push("1 snapshot still being committed".to_string()),
+            n => blockers.push(format!("{} snapshots still being committed", n)),
+        }
+        if self.is_generating() {
+            blockers.push("Claude is still producing output".to_string());
+        }
+        blockers
+    }
+
+    /// Applies snapshot results until none are pending or `timeout` passes,
+    /// so a quick quit doesn't leave History rows without their snapshot.
+    fn drain_snapshots(&mut self, db: &mut Database, rx: &Receiver<SnapshotResult>, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.in_flight_snapshots > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(remaining) {
+                Ok(res) => self.update_snapshot(db, res)?,
+                Err(_) => break,
+            }
+        }
+        Ok(())
     }
 
     fn record_user_message(&mut self, db: &mut Database, content: String, output_line: usize) -> Result<()> {
+        self.finalize_last_turn(db)?;
         let idx = self.messages.len() as i64 + 1;
-        let message_id = db.insert_message(&self.session_id, idx, &content)?;
+        // The estimate and the in-memory entry keep the full prompt; only the
+        // stored copy is cut.
+        let stored = match self.config.max_stored_content {
+            Some(max) => truncate_chars(&content, max),
+            None => content.clone(),
+        };
+        let message_id = db.insert_message(&self.session_id, idx, &stored, self.estimate_tokens(&content))?;
+        let skip_snapshot = self.config.skips_snapshot(&content);
         let entry = MessageEntry {
             id: message_id.clone(),
             idx,
             content,
             output_line,
+            output_end: None,
             assistant_text: String::new(),
             snapshot_commit: None,
+            snapshot_commit_after: None,
+            changed_files: None,
+            diff_stat: None,
+            snapshot_error: None,
+            snapshot_skipped: skip_snapshot,
+            created_at: Utc::now(),
         };
         self.messages.push(entry);
         self.selected_message = self.messages.len().saturating_sub(1);
-        let _ = self.snapshot_job_tx.send(SnapshotJob {
-            message_id,
-            message_idx: idx,
-        });
+        self.turn_started = Some(Instant::now());
+        if self.snapshots_enabled && !skip_snapshot {
+            debug_log!("snapshot enqueue idx={}", idx);
+            self.enqueue_snapshot(SnapshotJob {
+                message_id,
+                message_idx: idx,
+                after_turn: false,
+            });
+        }
         Ok(())
     }
 
     fn update_snapshot(&mut self, db: &mut Database, res: SnapshotResult) -> Result<()> {
+        self.in_flight_snapshots = self.in_flight_snapshots.saturating_sub(1);
+        if res.after_turn {
+            let Some(msg) = self.messages.iter_mut().find(|m| m.id == res.message_id) else {
+                return Ok(());
+            };
+            match res.commit {
+                Some(commit) => {
+                    db.set_snapshot_after(&msg.id, &commit)?;
+                    msg.snapshot_commit_after = Some(commit);
+                }
+                None => {
+                    let note = format!(
+                        "Snapshot after message #{} failed: {}",
+                        msg.idx,
+                        res.error.unwrap_or_else(|| "snapshot failed".to_string())
+                    );
+                    self.push_notice(note, Color::Red);
+                }
+            }
+            return Ok(());
+        }
         if let Some(msg) = self.messages.iter_mut().find(|m| m.id == res.message_id) {
             if let Some(commit) = res.commit.clone() {
+                msg.changed_files = res.changed_files;
+                msg.diff_stat = res.diff_stat;
                 msg.snapshot_commit = Some(commit.clone());
+                msg.snapshot_error = None;
                 db.insert_snapshot(&self.session_id, msg.idx, &commit)?;
+            } else {
+                msg.snapshot_error = Some(res.error.unwrap_or_else(|| "snapshot failed".to_string()));
             }
         }
         Ok(())
     }
+
+    /// Snapshots the workspace as it is now, as the message's after-turn
+    /// snapshot. One taken when its turn ended is what `d` diffs the turn
+    /// against, so that is only replaced when asked twice in a row.
+    fn take_snapshot(&mut self, index: usize) {
+        if !self.snapshots_enabled {
+            self.push_notice(format!("Snapshots are off: {}", self.snapshots_off), Color::Yellow);
+            return;
+        }
+        let Some(msg) = self.messages.get(index) else {
+            self.push_notice("Nothing to snapshot yet: a snapshot belongs to a message".to_string(), Color::Yellow);
+            return;
+        };
+        let confirmed = self.confirm_snapshot.take().is_some_and(|id| id == msg.id);
+        if msg.snapshot_commit_after.is_some() && !confirmed {
+            let note = format!(
+                "Message #{} already has its after-turn snapshot, which its turn diff uses; snapshot again to replace it",
+                msg.idx
+            );
+            self.confirm_snapshot = Some(msg.id.clone());
+            self.push_notice(note, Color::Yellow);
+            return;
+        }
+        debug_log!("snapshot enqueue idx={} by hand", msg.idx);
+        let job = SnapshotJob {
+            message_id: msg.id.clone(),
+            message_idx: msg.idx,
+            after_turn: true,
+        };
+        let note = format!("Snapshotting the workspace for message #{}", msg.idx);
+        self.enqueue_snapshot(job);
+        self.push_notice(note, Color::DarkGray);
+    }
+
+    /// Re-enqueues the snapshot of a message whose previous attempt failed.
+    fn retry_snapshot(&mut self, index: usize) {
+        if !self.snapshots_enabled {
+            return;
+        }
+        if let Some(msg) = self.messages.get_mut(index) {
+            if msg.snapshot_error.take().is_some() {
+                debug_log!("snapshot retry idx={}", msg.idx);
+                let job = SnapshotJob {
+                    message_id: msg.id.clone(),
+                    message_idx: msg.idx,
+                    after_turn: false,
+                };
+                self.enqueue_snapshot(job);
+            }
+        }
+    }
+
+    /// Hands `job` to the snapshot worker and counts it until its result
+    /// comes back through `update_snapshot`.
+    fn enqueue_snapshot(&mut self, job: SnapshotJob) {
+        if self.snapshot_job_tx.send(job).is_ok() {
+            self.in_flight_snapshots += 1;
+        }
+    }
 }
 
 struct Database {
     conn: Connection,
 }
 
+/// Ordered schema steps; index + 1 is the `user_version` after the step runs.
+/// Never edit a shipped step, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema. `IF NOT EXISTS` adopts databases created before
+    // versioning existed.
+    "
+        CREATE TABLE IF NOT EXISTS workspaces (
+            id TEXT PRIMARY KEY,
+            path TEXT UNIQUE,
+            created_at TEXT
+        );
+        CREATE TABLE IF NOT EXISTS sessions (
+            id TEXT PRIMARY KEY,
+            workspace_id TEXT,
+            created_at TEXT
+        );
+        CREATE TABLE IF NOT EXISTS messages (
+            id TEXT PRIMARY KEY,
+            session_id TEXT,
+            idx INTEGER,
+            role TEXT,
+            content TEXT,
+            created_at TEXT
+        );
+        CREATE TABLE IF NOT EXISTS snapshots (
+            id TEXT PRIMARY KEY,
+            session_id TEXT,
+            idx INTEGER,
+            [commit] TEXT,
+            created_at TEXT
+        );
+        ",
+    // 2: per-turn token estimates.
+    "
+        ALTER TABLE messages ADD COLUMN input_tokens INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE messages ADD COLUMN output_tokens INTEGER NOT NULL DEFAULT 0;
+        ",
+    // 3: Claude's reply to each turn, written when the turn ends.
+    "
+        ALTER TABLE messages ADD COLUMN assistant_text TEXT NOT NULL DEFAULT '';
+        ",
+    // 4: how long Claude took to answer, from the prompt to its last output.
+    "
+        ALTER TABLE messages ADD COLUMN duration_ms INTEGER;
+        ",
+    // 5: the snapshot taken when the turn ended, with `snapshot_after_turn`.
+    "
+        ALTER TABLE messages ADD COLUMN snapshot_after TEXT;
+        ",
+];
+
 impl Database {
     fn new(path: &Path) -> Result<Self> {
         let conn = Connection::open(path)?;
+        // WAL lets readers and a writer proceed side by side; the busy timeout
+        // makes a second connection wait out a held lock instead of failing.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.query_row("PRAGMA journal_mode=WAL", [], |row| row.get::<_, String>(0))?;
+        let mut db = Self { conn };
+        db.migrate()?;
+        Ok(db)
+    }
+
+    /// Opens a database someone else keeps up to date, without migrating or
+    /// otherwise writing to it; it must already be at this build's version.
+    fn open_existing(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
         let db = Self { conn };
-        db.init()?;
+        let version = db.schema_version()?;
+        if version != MIGRATIONS.len() {
+            return Err(anyhow!(
+                "database schema version {} doesn't match this build ({}); quit the running instance first",
+                version,
+                MIGRATIONS.len()
+            ));
+        }
         Ok(db)
     }
 
-    fn init(&self) -> Result<()> {
-        self.conn.execute_batch(
-            "
-            CREATE TABLE IF NOT EXISTS workspaces (
-                id TEXT PRIMARY KEY,
-                path TEXT UNIQUE,
-                created_at TEXT
-            );
-            CREATE TABLE IF NOT EXISTS sessions (
-                id TEXT PRIMARY KEY,
-                workspace_id TEXT,
-                created_at TEXT
-            );
-            CREATE TABLE IF NOT EXISTS messages (
-                id TEXT PRIMARY KEY,
-                session_id TEXT,
-                idx INTEGER,
-                role TEXT,
-                content TEXT,
-                created_at TEXT
-            );
-            CREATE TABLE IF NOT EXISTS snapshots (
-                id TEXT PRIMARY KEY,
-                session_id TEXT,
-                idx INTEGER,
-                [commit] TEXT,
-                created_at TEXT
-            );
-            ",
-        )?;
+    fn schema_version(&self) -> Result<usize> {
+        let version: i64 = self.conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(version as usize)
+    }
+
+    /// Applies every migration newer than the stored `user_version`. Each step
+    /// runs in its own transaction together with the version bump, so an
+    /// interrupted upgrade leaves the database at the last completed version.
+    fn migrate(&mut self) -> Result<()> {
+        let current = self.schema_version()?;
+        if current > MIGRATIONS.len() {
+            return Err(anyhow!(
+                "database schema version {} is newer than this build supports ({})",
+                current,
+                MIGRATIONS.len()
+            ));
+        }
+        for (idx, sql) in MIGRATIONS.iter().enumerate().skip(current) {
+            // Take the write lock up front and re-check, in case another
+            // connection applied this step while we were waiting.
+            let tx = self
+                .conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+            let version: i64 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+            if version as usize > idx {
+                continue;
+            }
+            tx.execute_batch(sql)?;
+            tx.pragma_update(None, "user_version", (idx + 1) as i64)?;
+            tx.commit()?;
+        }
         Ok(())
     }
 
-    fn ensure_workspace(&mut self, path: &Path) -> Result<String> {
+    fn find_workspace(&self, path: &Path) -> Result<Option<String>> {
         let path_str = path.to_string_lossy();
         let mut stmt = self
             .conn
             .prepare("SELECT id FROM workspaces WHERE path = ?1")?;
         let mut rows = stmt.query(params![path_str.as_ref()])?;
-        if let Some(row) = rows.next()? {
-            let id: String = row.get(0)?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn ensure_workspace(&mut self, path: &Path) -> Result<String> {
+        if let Some(id) = self.find_workspace(path)? {
             return Ok(id);
         }
+        let path_str = path.to_string_lossy();
         let id = Uuid::new_v4().to_string();
         let now = Utc::now().to_rfc3339();
         self.conn.execute(
@@ -781,35 +3623,262 @@ impl Database {
         Ok(id)
     }
 
-    fn insert_message(&mut self, session_id: &str, idx: i64, content: &str) -> Result<String> {
-        let id = Uuid::new_v4().to_string();
-        let now = Utc::now().to_rfc3339();
-        self.conn.execute(
-            "INSERT INTO messages (id, session_id, idx, role, content, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![id, session_id, idx, "user", content, now],
+    /// Deletes sessions of `workspace_id` matched by `cutoff` together with
+    /// their messages and snapshot rows. The most recent session is always
+    /// kept since it may belong to a running instance.
+    fn prune_sessions(&mut self, workspace_id: &str, cutoff: &PruneCutoff) -> Result<usize> {
+        let tx = self.conn.transaction()?;
+        let sessions: Vec<(String, String)> = {
+            let mut stmt = tx.prepare(
+                "SELECT id, created_at FROM sessions WHERE workspace_id = ?1 ORDER BY created_at DESC",
+            )?;
+            let rows = stmt.query_map(params![workspace_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        let doomed: Vec<&String> = match cutoff {
+            PruneCutoff::OlderThanDays(days) => {
+                let cutoff = (Utc::now() - chrono::Duration::days(*days)).to_rfc3339();
+                sessions
+                    .iter()
+                    .skip(1)
+                    .filter(|(_, created_at)| *created_at < cutoff)
+                    .map(|(id, _)| id)
+                    .collect()
+            }
+            PruneCutoff::KeepNewest(keep) => sessions.iter().skip((*keep).max(1)).map(|(id, _)| id).collect(),
+        };
+        for id in &doomed {
+            tx.execute("DELETE FROM snapshots WHERE session_id = ?1", params![id])?;
+            tx.execute("DELETE FROM messages WHERE session_id = ?1", params![id])?;
+            tx.execute("DELETE FROM sessions WHERE id = ?1", params![id])?;
+        }
+        tx.commit()?;
+        Ok(doomed.len())
+    }
+
+    fn session_ids(&self, workspace_id: &str) -> Result<HashSet<String>> {
+        let mut stmt = self.conn.prepare("SELECT id FROM sessions WHERE workspace_id = ?1")?;
+        let ids = stmt
+            .query_map(params![workspace_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    /// Every snapshot commit the sessions of `workspace_id` refer to, taken
+    /// at prompt time or after the turn.
+    fn snapshot_commits(&self, workspace_id: &str) -> Result<HashSet<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT s.[commit] FROM snapshots s JOIN sessions ON sessions.id = s.session_id
+             WHERE sessions.workspace_id = ?1
+             UNION
+             SELECT m.snapshot_after FROM messages m JOIN sessions ON sessions.id = m.session_id
+             WHERE sessions.workspace_id = ?1 AND m.snapshot_after IS NOT NULL",
         )?;
-        Ok(id)
+        let commits = stmt
+            .query_map(params![workspace_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(commits)
     }
 
-    fn insert_snapshot(&mut self, session_id: &str, idx: i64, commit: &str) -> Result<String> {
+    fn insert_message(&mut self, session_id: &str, idx: i64, content: &str, input_tokens: u32) -> Result<String> {
         let id = Uuid::new_v4().to_string();
         let now = Utc::now().to_rfc3339();
         self.conn.execute(
-            "INSERT INTO snapshots (id, session_id, idx, [commit], created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![id, session_id, idx, commit, now],
+            "INSERT INTO messages (id, session_id, idx, role, content, created_at, input_tokens) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![id, session_id, idx, "user", content, now, input_tokens],
         )?;
         Ok(id)
     }
-}
 
-struct PtyProcess {
-    master: Box<dyn portable_pty::MasterPty>,
+    fn set_assistant_text(&mut self, message_id: &str, assistant_text: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE messages SET assistant_text = ?1 WHERE id = ?2",
+            params![assistant_text, message_id],
+        )?;
+        Ok(())
+    }
+
+    fn set_duration_ms(&mut self, message_id: &str, duration_ms: u64) -> Result<()> {
+        self.conn.execute(
+            "UPDATE messages SET duration_ms = ?1 WHERE id = ?2",
+            params![duration_ms as i64, message_id],
+        )?;
+        Ok(())
+    }
+
+    fn set_snapshot_after(&mut self, message_id: &str, commit: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE messages SET snapshot_after = ?1 WHERE id = ?2",
+            params![commit, message_id],
+        )?;
+        Ok(())
+    }
+
+    fn set_output_tokens(&mut self, message_id: &str, output_tokens: u32) -> Result<()> {
+        self.conn.execute(
+            "UPDATE messages SET output_tokens = ?1 WHERE id = ?2",
+            params![output_tokens, message_id],
+        )?;
+        Ok(())
+    }
+
+    /// Returns `(input, output)` token estimates summed over a session.
+    fn session_token_totals(&self, session_id: &str) -> Result<(u64, u64)> {
+        let totals = self.conn.query_row(
+            "SELECT COALESCE(SUM(input_tokens), 0), COALESCE(SUM(output_tokens), 0) FROM messages WHERE session_id = ?1",
+            params![session_id],
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+        )?;
+        Ok(totals)
+    }
+
+    fn latest_session(&self, workspace_id: &str) -> Result<Option<String>> {
+        let id = self
+            .conn
+            .query_row(
+                "SELECT id FROM sessions WHERE workspace_id = ?1 ORDER BY created_at DESC LIMIT 1",
+                params![workspace_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(id)
+    }
+
+    /// Snapshot rows of every session in the workspace, oldest first.
+    fn list_snapshots(&self, workspace_id: &str) -> Result<Vec<SnapshotRow>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT s.[commit], s.idx, s.created_at, s.session_id
+             FROM snapshots s JOIN sessions ON sessions.id = s.session_id
+             WHERE sessions.workspace_id = ?1
+             ORDER BY s.created_at, s.idx",
+        )?;
+        let rows = stmt
+            .query_map(params![workspace_id], |row| {
+                Ok(SnapshotRow {
+                    commit: row.get(0)?,
+                    idx: row.get(1)?,
+                    created_at: row.get(2)?,
+                    session_id: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    /// Every turn of `session_id` in order, each with its latest snapshot.
+    fn load_transcript(&self, session_id: &str) -> Result<Transcript> {
+        let created_at: String = self
+            .conn
+            .query_row(
+                "SELECT created_at FROM sessions WHERE id = ?1",
+                params![session_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("unknown session {}", session_id))?;
+        let mut stmt = self.conn.prepare(
+            "SELECT m.idx, m.content, m.assistant_text, m.created_at, m.input_tokens, m.output_tokens,
+                    m.duration_ms,
+                    (SELECT s.[commit] FROM snapshots s
+                     WHERE s.session_id = m.session_id AND s.idx = m.idx
+                     ORDER BY s.created_at DESC LIMIT 1),
+                    m.snapshot_after
+             FROM messages m WHERE m.session_id = ?1 ORDER BY m.idx",
+        )?;
+        let turns = stmt
+            .query_map(params![session_id], |row| {
+                Ok(TranscriptTurn {
+                    idx: row.get(0)?,
+                    user: row.get(1)?,
+                    assistant: row.get(2)?,
+                    created_at: row.get(3)?,
+                    input_tokens: row.get(4)?,
+                    output_tokens: row.get(5)?,
+                    duration_ms: row.get(6)?,
+                    snapshot_commit: row.get(7)?,
+                    snapshot_after: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(Transcript {
+            session_id: session_id.to_string(),
+            created_at,
+            turns,
+        })
+    }
+
+    fn insert_snapshot(&mut self, session_id: &str, idx: i64, commit: &str) -> Result<String> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now().to_rfc3339();
+        self.conn.execute(
+            "INSERT INTO snapshots (id, session_id, idx, [commit], created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![id, session_id, idx, commit, now],
+        )?;
+        Ok(id)
+    }
+}
+
+struct SnapshotRow {
+    commit: String,
+    idx: i64,
+    created_at: String,
+    session_id: String,
+}
+
+/// `--list-snapshots` output: one tab-separated line per snapshot that
+/// still exists in the repo, `commit idx created_at session_id`.
+fn snapshot_listing(rows: &[SnapshotRow], commits: &HashSet<String>) -> String {
+    rows.iter()
+        .filter(|row| commits.contains(&row.commit))
+        .map(|row| format!("{}\t{}\t{}\t{}\n", row.commit, row.idx, row.created_at, row.session_id))
+        .collect()
+}
+
+/// Machine-readable dump of a session, written by `--export-json`.
+#[derive(Serialize)]
+struct Transcript {
+    session_id: String,
+    created_at: String,
+    turns: Vec<TranscriptTurn>,
+}
+
+#[derive(Serialize)]
+struct TranscriptTurn {
+    idx: i64,
+    user: String,
+    assistant: String,
+    created_at: String,
+    input_tokens: u32,
+    output_tokens: u32,
+    /// Unset for turns that never went quiet (e.g. the session was killed).
+    duration_ms: Option<i64>,
+    snapshot_commit: Option<String>,
+    /// With `snapshot_after_turn`: the tree once Claude had answered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot_after: Option<String>,
+}
+
+struct PtyProcess {
+    master: Box<dyn portable_pty::MasterPty>,
     writer: Box<dyn Write + Send>,
-    _child: Box<dyn portable_pty::Child + Send>,
+    child: Box<dyn portable_pty::Child + Send>,
+    /// Claude's stderr arrives as `OutputStream::Stderr` chunks.
+    stderr_separated: bool,
 }
 
+/// Runs the command after `sh FIFO` with its stderr sent to the FIFO.
+const STDERR_SHIM: &str = "f=$1; shift; exec \"$@\" 2>\"$f\"";
+
 impl PtyProcess {
-    fn spawn(cmd: &str, args: &[String], output_tx: Sender<OutputChunk>) -> Result<Self> {
+    fn spawn(
+        cmd: &str,
+        args: &[String],
+        env: &HashMap<String, String>,
+        cwd: &Path,
+        encoding: OutputEncoding,
+        separate_stderr: bool,
+        output_tx: Sender<OutputChunk>,
+    ) -> Result<Self> {
         let pty_system = native_pty_system();
         let pair = pty_system.openpty(PtySize {
             rows: 24,
@@ -818,37 +3887,69 @@ impl PtyProcess {
             pixel_height: 0,
         })?;
 
-        let mut command = CommandBuilder::new(cmd);
-        for arg in args {
-            command.arg(arg);
-        }
-        let child = pair.slave.spawn_command(command)?;
+        // Without a FIFO (no `mkfifo`, not Unix) stderr stays on the PTY.
+        let fifo = if separate_stderr { make_stderr_fifo() } else { None };
+        let command = match &fifo {
+            Some(fifo) => {
+                let mut shim = vec![
+                    "-c".to_string(),
+                    STDERR_SHIM.to_string(),
+                    "sh".to_string(),
+                    fifo.to_string_lossy().to_string(),
+                    cmd.to_string(),
+                ];
+                shim.extend(args.iter().cloned());
+                build_command("sh", &shim, env, cwd)
+            }
+            None => build_command(cmd, args, env, cwd),
+        };
+        debug_log!("pty spawn cmd={} args={:?} stderr_fifo={}", cmd, args, fifo.is_some());
+        let child = match pair.slave.spawn_command(command) {
+            Ok(child) => child,
+            Err(err) => {
+                if let Some(fifo) = &fifo {
+                    let _ = fs::remove_file(fifo);
+                }
+                return Err(err);
+            }
+        };
         drop(pair.slave);
+        if let Some(fifo) = &fifo {
+            spawn_stderr_reader(fifo.clone(), encoding, output_tx.clone());
+        }
 
         let mut reader = pair.master.try_clone_reader()?;
         thread::spawn(move || {
             let mut buf = [0u8; 4096];
+            let mut decoder = OutputDecoder::new(encoding);
             loop {
                 match reader.read(&mut buf) {
-                    Ok(0) => break,
+                    Ok(0) | Err(_) => break,
                     Ok(n) => {
-                        let text = String::from_utf8_lossy(&buf[..n]).to_string();
-                        let _ = output_tx.send(OutputChunk { text });
+                        let text = decoder.decode(&buf[..n]);
+                        if !text.is_empty() {
+                            let _ = output_tx.send(OutputChunk { text, stream: OutputStream::Stdout });
+                        }
                     }
-                    Err(_) => break,
                 }
             }
+            let text = decoder.finish();
+            if !text.is_empty() {
+                let _ = output_tx.send(OutputChunk { text, stream: OutputStream::Stdout });
+            }
         });
 
         let writer = pair.master.take_writer()?;
         Ok(Self {
             master: pair.master,
             writer,
-            _child: child,
+            child,
+            stderr_separated: fifo.is_some(),
         })
     }
 
     fn resize(&self, cols: u16, rows: u16) {
+        debug_log!("pty resize cols={} rows={}", cols, rows);
         let _ = self.master.resize(PtySize {
             rows,
             cols,
@@ -862,17 +3963,335 @@ impl PtyProcess {
         self.writer.flush()?;
         Ok(())
     }
+
+    /// Waits for Claude to exit and returns its exit code.
+    fn wait(&mut self) -> Result<u32> {
+        Ok(self.child.wait()?.exit_code())
+    }
+
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A fresh named pipe in the temp dir for Claude's stderr, or `None` where
+/// one can't be made.
+fn make_stderr_fifo() -> Option<PathBuf> {
+    if !cfg!(unix) {
+        return None;
+    }
+    let path = env::temp_dir().join(format!("ccwb-stderr-{}", Uuid::new_v4()));
+    let made = std::process::Command::new("mkfifo")
+        .arg(&path)
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+    made.then_some(path)
+}
+
+/// Forwards what arrives on `fifo` as `Stderr` chunks until Claude exits.
+/// The pipe is unlinked once both ends are open.
+fn spawn_stderr_reader(fifo: PathBuf, encoding: OutputEncoding, output_tx: Sender<OutputChunk>) {
+    thread::spawn(move || {
+        let file = fs::File::open(&fifo);
+        let _ = fs::remove_file(&fifo);
+        let Ok(mut file) = file else {
+            return;
+        };
+        let mut buf = [0u8; 4096];
+        let mut decoder = OutputDecoder::new(encoding);
+        let send = |text: String| {
+            if !text.is_empty() {
+                let _ = output_tx.send(OutputChunk { text, stream: OutputStream::Stderr });
+            }
+        };
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => send(decoder.decode(&buf[..n])),
+            }
+        }
+        send(decoder.finish());
+    });
+}
+
+/// Runs `cmd <prefix> --version` so a broken `claude_cmd` fails loudly before
+/// the TUI starts. Returns the first word of the output (the version number).
+fn probe_claude(cmd: &str, prefix: &[String], env: &HashMap<String, String>, timeout: Duration) -> Result<String> {
+    let mut command = std::process::Command::new(cmd);
+    command
+        .args(prefix)
+        .arg("--version")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+    for (key, value) in env {
+        command.env(key, interpolate_env(value));
+    }
+    let mut child = command.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => anyhow!("Claude command not found: {}", cmd),
+        _ => anyhow!("could not run {}: {}", cmd, e),
+    })?;
+    let deadline = Instant::now() + timeout;
+    while child.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!("{} --version did not finish within {}s", cmd, timeout.as_secs()));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!(
+            "{} --version failed ({}): {}",
+            cmd,
+            output.status,
+            stderr.lines().next().unwrap_or_default()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.split_whitespace().next().unwrap_or_default().to_string())
+}
+
+/// Builds the Claude command line, run from the workspace. The child inherits
+/// our environment; entries from the config `env` map are layered on top and
+/// win over inherited values.
+fn build_command(cmd: &str, args: &[String], env: &HashMap<String, String>, cwd: &Path) -> CommandBuilder {
+    let mut command = CommandBuilder::new(cmd);
+    command.cwd(cwd);
+    for arg in args {
+        command.arg(arg);
+    }
+    for (key, value) in env {
+        command.env(key, interpolate_env(value));
+    }
+    command
+}
+
+/// Replaces `${VAR}` references with the value of `VAR` from our own
+/// environment (empty when unset). Anything else is kept verbatim.
+fn interpolate_env(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find('}') {
+            Some(end) => {
+                out.push_str(&env::var(&after[..end]).unwrap_or_default());
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
 }
 
 #[derive(Clone)]
 struct OutputChunk {
     text: String,
+    stream: OutputStream,
+}
+
+/// Where an `OutputChunk` came from. Everything is `Stdout` unless
+/// `separate_stderr` gave Claude's stderr its own pipe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Layout toggles remembered between runs in `ui_state.json` next to the
+/// database. `config.json` stays the place for settings; this file is
+/// rewritten on every clean exit.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct UiState {
+    fullscreen_output: bool,
+    /// Width of the Claude pane in percent.
+    split_percent: u16,
+}
+
+impl Default for UiState {
+    fn default() -> Self {
+        Self {
+            fullscreen_output: false,
+            split_percent: 68,
+        }
+    }
+}
+
+impl UiState {
+    /// Missing or unreadable files give the defaults; values are clamped so
+    /// a hand-edited file can't produce an unusable layout.
+    fn load(path: &Path) -> Self {
+        let mut state: UiState = fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        state.split_percent = state.split_percent.clamp(20, 90);
+        state
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Where the workspace lock lives: `ccwb.lock` in the data dir, or for a
+/// `shared` one `locks/<hash of the workspace path>.lock`, since the
+/// workspace id is only known once the database is open.
+fn workspace_lock_path(data_dir: &Path, workspace: &Path, shared: bool) -> PathBuf {
+    if !shared {
+        return data_dir.join("ccwb.lock");
+    }
+    // FNV-1a, so the name stays the same across builds.
+    let hash = workspace
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3));
+    data_dir.join("locks").join(format!("{:016x}.lock", hash))
+}
+
+/// An exclusive OS lock on the `workspace_lock_path` file, held for as long
+/// as this instance may write to the database, snapshots repo or backups.
+/// The OS drops it when we exit, crashed or not, so a stale lock can't
+/// outlive its holder; the pid inside is only there to name it.
+struct WorkspaceLock {
+    file: fs::File,
+}
+
+impl WorkspaceLock {
+    /// Takes the lock, failing while another process holds it.
+    fn acquire(path: &Path) -> Result<Self> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => {
+                let holder = fs::read_to_string(path).ok().and_then(|s| s.trim().parse::<u32>().ok());
+                return Err(match holder {
+                    Some(pid) => anyhow!("another cc-workbench (pid {}) is using this workspace", pid),
+                    None => anyhow!("another cc-workbench is using this workspace"),
+                });
+            }
+            Err(fs::TryLockError::Error(err)) => return Err(err.into()),
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for WorkspaceLock {
+    fn drop(&mut self) {
+        // Only the pid goes; removing the file would let a newcomer lock a
+        // fresh inode while someone else still waits on this one.
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Our mirror of the prompt line being edited in Claude, so History shows
+/// what was actually submitted. It follows cursor movement, backspace and
+/// pastes; anything we can't follow (e.g. history recall) clears it.
+#[derive(Default)]
+struct InputLine {
+    chars: Vec<char>,
+    cursor: usize,
+    /// Claude recalled a prompt we can't see, so the box may hold text even
+    /// though `chars` is empty. Cleared once the line is known empty again.
+    recalled: bool,
+}
+
+impl InputLine {
+    fn insert_str(&mut self, text: &str) {
+        for c in text.chars() {
+            let c = if c == '\r' { '\n' } else { c };
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn move_right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+
+    fn kill_to_start(&mut self) {
+        self.chars.drain(..self.cursor);
+        self.cursor = 0;
+    }
+
+    fn clear(&mut self) {
+        self.chars.clear();
+        self.cursor = 0;
+        self.recalled = false;
+    }
+
+    /// Claude replaced the line with one from its history.
+    fn recall(&mut self) {
+        self.clear();
+        self.recalled = true;
+    }
+
+    /// Whether the prompt box is known to be empty.
+    fn is_empty(&self) -> bool {
+        self.chars.is_empty() && !self.recalled
+    }
+
+    /// The line as typed so far, newlines from pastes shown as `⏎`.
+    fn display(&self) -> String {
+        self.chars.iter().map(|&c| if c == '\n' { '⏎' } else { c }).collect()
+    }
+
+    /// Returns the trimmed line and resets the buffer.
+    fn take(&mut self) -> String {
+        let text: String = self.chars.iter().collect();
+        self.clear();
+        text.trim().to_string()
+    }
 }
 
-fn setup_terminal() -> Result<Terminal<CrosstermBackend<io::Stdout>>> {
+/// The prompt as echoed by Claude: the last non-empty output line with the
+/// input box border and `>` marker stripped.
+fn submitted_line_from_output(lines: &[String]) -> Option<String> {
+    lines.iter().rev().find_map(|line| {
+        let text = line
+            .trim_matches(|c: char| c == '│' || c == '|' || c.is_whitespace())
+            .trim_start_matches('>')
+            .trim();
+        (!text.is_empty()).then(|| text.to_string())
+    })
+}
+
+fn setup_terminal(title: &str) -> Result<Terminal<CrosstermBackend<io::Stdout>>> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, cursor::Hide)?;
+    execute!(
+        stdout,
+        EnterAlternateScreen,
+        EnableBracketedPaste,
+        cursor::Hide,
+        SetTitle(format!("Claude — {}", title))
+    )?;
     let backend = CrosstermBackend::new(stdout);
     let terminal = Terminal::new(backend)?;
     Ok(terminal)
@@ -880,7 +4299,7 @@ fn setup_terminal() -> Result<Terminal<CrosstermBackend<io::Stdout>>> {
 
 fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> Result<()> {
     disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen, cursor::Show)?;
+    execute!(terminal.backend_mut(), DisableBracketedPaste, LeaveAlternateScreen, cursor::Show)?;
     terminal.show_cursor()?;
     Ok(())
 }
@@ -891,39 +4310,48 @@ fn run_app(
     db: &mut Database,
     app: &mut App,
     output_rx: Receiver<OutputChunk>,
-    snapshot_rx: Receiver<SnapshotResult>,
+    snapshot_rx: &Receiver<SnapshotResult>,
 ) -> Result<()> {
+    let started = Instant::now();
     let mut last_tick = Instant::now();
-    let mut last_left: Rect = Rect::default();
+    let mut last_draw = Instant::now();
+    let mut last_pty_size = (0u16, 0u16);
     loop {
+        let backlog = drain_output(app, &output_rx, OUTPUT_DRAIN_BUDGET);
+        while let Ok(res) = snapshot_rx.try_recv() {
+            app.update_snapshot(db, res)?;
+            app.dirty = true;
+        }
+
         let size = terminal.size()?;
-        let left = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([Constraint::Percentage(68), Constraint::Percentage(32)])
-            .split(size)[0];
-        if left != last_left {
-            let cols = left.width.saturating_sub(2);
-            let rows = left.height.saturating_sub(2);
+        let (left, _) = main_layout(size, app);
+        let pad = fit_padding(app.config.output_padding, left);
+        let cols = left.width.saturating_sub(2 + pad.left + pad.right + app.line_number_gutter() as u16);
+        let rows = left.height.saturating_sub(2 + pad.top + pad.bottom + app.input_line_height());
+        app.output_height = rows as usize;
+        if (cols, rows) != last_pty_size {
             pty.resize(cols, rows);
-            last_left = left;
+            last_pty_size = (cols, rows);
             app.dirty = true;
         }
 
-        // Only redraw if there's something to update
+        // Only redraw if there's something to update, but keep a steady
+        // cadence while output is flowing; an idle screen is left alone.
+        if app.is_generating() && last_draw.elapsed() >= REDRAW_INTERVAL {
+            app.dirty = true;
+        }
         if app.dirty {
             terminal.draw(|f| draw_ui(f, app))?;
             app.dirty = false;
+            last_draw = Instant::now();
         }
 
-        while let Ok(chunk) = output_rx.try_recv() {
-            app.handle_output(chunk);
-        }
-        while let Ok(res) = snapshot_rx.try_recv() {
-            app.update_snapshot(db, res)?;
-            app.dirty = true;
-        }
-
-        let timeout = Duration::from_millis(50);
+        // With output still queued, only check for keys instead of waiting.
+        let timeout = if backlog {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(50)
+        };
         if event::poll(timeout)? {
             match event::read()? {
                 Event::Key(key) => {
@@ -931,6 +4359,13 @@ fn run_app(
                     if handle_key_event(key, pty, db, app)? {
                         break;
                     }
+                    if let Some(path) = app.pending_edit.take() {
+                        edit_file(terminal, app, &path)?;
+                    }
+                }
+                Event::Paste(text) => {
+                    app.dirty = true;
+                    handle_paste(&text, pty, app)?;
                 }
                 Event::Resize(cols, rows) => {
                     pty.resize(cols, rows);
@@ -942,511 +4377,4613 @@ fn run_app(
 
         if last_tick.elapsed() >= Duration::from_millis(200) {
             last_tick = Instant::now();
+            app.tick();
+            app.publish_metrics();
+            app.check_usage_notifications();
+            app.check_provider_health();
+            app.check_turn_idle(db, Instant::now())?;
+            if app.pending_prompt.is_some() && prompt_ready(started, app.last_output) {
+                if let Some(prompt) = app.pending_prompt.take() {
+                    submit_prompt(pty, db, app, prompt)?;
+                }
+            }
         }
     }
     Ok(())
 }
 
-fn handle_key_event(key: KeyEvent, pty: &mut PtyProcess, db: &mut Database, app: &mut App) -> Result<bool> {
-    if app.diff_preview.is_some() {
-        return handle_diff_keys(key, app);
+/// Suspends the TUI while `$EDITOR` runs on `path`, then takes the terminal
+/// back and reloads the diff preview so it reflects the edit.
+fn edit_file(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, app: &mut App, path: &Path) -> Result<()> {
+    restore_terminal(terminal)?;
+    // Through sh so an `$EDITOR` with arguments, like `code -w`, works.
+    let status = std::process::Command::new("sh")
+        .args(["-c", "$EDITOR \"$1\"", "sh"])
+        .arg(path)
+        .status();
+    enable_raw_mode()?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen, EnableBracketedPaste, cursor::Hide)?;
+    terminal.clear()?;
+    let note = match status {
+        Ok(status) if status.success() => format!("Edited {}", path.display()),
+        Ok(status) => format!("$EDITOR exited with {}", status),
+        Err(err) => format!("Couldn't run $EDITOR: {}", err),
+    };
+    refresh_diff_preview(app);
+    if let Some(preview) = &mut app.diff_preview {
+        preview.status = Some(note);
     }
+    Ok(())
+}
 
-    match key {
-        KeyEvent {
-            code: KeyCode::Char('q'),
-            modifiers: KeyModifiers::CONTROL,
-            ..
-        } => return Ok(true),
-        KeyEvent {
-            code: KeyCode::Tab,
-            ..
-        } => {
-            app.focus = match app.focus {
-                Focus::Output => Focus::History,
-                Focus::History => Focus::Output,
-            };
+/// Feeds queued output to `app` until about `budget` bytes have been
+/// handled. Returns whether the budget ran out with output still queued.
+fn drain_output(app: &mut App, output_rx: &Receiver<OutputChunk>, budget: usize) -> bool {
+    let mut taken = 0;
+    while taken < budget {
+        match output_rx.try_recv() {
+            Ok(chunk) => {
+                taken += chunk.text.len();
+                app.handle_output(chunk);
+            }
+            Err(_) => return false,
         }
-        KeyEvent {
-            code: KeyCode::Up,
-            ..
-        } => {
-            if matches!(app.focus, Focus::History) {
-                if app.selected_message > 0 {
-                    app.selected_message -= 1;
+    }
+    true
+}
+
+/// `--headless`: drives Claude without the TUI, writing each output line to
+/// `out` with escape sequences stripped, and typing `prompt` once Claude is
+/// ready. Returns the process exit status: 0 once a line matches `until`, 1
+/// if Claude exits first, Claude's own exit code when there is no `until`,
+/// and `HEADLESS_TIMEOUT_EXIT` when `timeout` runs out.
+fn run_headless(
+    pty: &mut PtyProcess,
+    output_rx: &Receiver<OutputChunk>,
+    mut prompt: Option<String>,
+    until: Option<&Pattern>,
+    timeout: Duration,
+    out: &mut impl Write,
+) -> Result<i32> {
+    let started = Instant::now();
+    let mut last_output = None;
+    let mut lines = vec![String::new()];
+    let mut cursor = 0;
+    let matches = |line: &str| until.is_some_and(|p| p.is_match(line));
+    loop {
+        match output_rx.recv_timeout(Duration::from_millis(50)) {
+            Ok(chunk) => {
+                last_output = Some(Instant::now());
+                append_output_lines(&mut lines, &mut cursor, &strip_ansi(&chunk.text), false, usize::MAX, usize::MAX);
+                // Completed lines are written out straight away; only the
+                // one still being written stays buffered.
+                let partial = lines.pop().unwrap_or_default();
+                for line in lines.drain(..) {
+                    writeln!(out, "{}", line)?;
+                    if matches(&line) {
+                        pty.kill();
+                        return Ok(0);
+                    }
                 }
-            } else {
-                pty.send_bytes(b"\x1b[A")?;
+                // Prompts and spinners often never end their line.
+                if matches(&partial) {
+                    writeln!(out, "{}", partial)?;
+                    pty.kill();
+                    return Ok(0);
+                }
+                lines.push(partial);
             }
-        }
-        KeyEvent {
-            code: KeyCode::Down,
-            ..
-        } => {
-            if matches!(app.focus, Focus::History) {
-                if app.selected_message + 1 < app.messages.len() {
-                    app.selected_message += 1;
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                // The reader thread saw EOF: Claude has exited.
+                let partial = lines.pop().unwrap_or_default();
+                if !partial.is_empty() {
+                    writeln!(out, "{}", partial)?;
                 }
-            } else {
-                pty.send_bytes(b"\x1b[B")?;
+                let code = pty.wait()?;
+                return Ok(if until.is_some() { 1 } else { code as i32 });
             }
         }
-        KeyEvent {
-            code: KeyCode::Left,
-            ..
-        } => {
-            if matches!(app.focus, Focus::Output) {
-                pty.send_bytes(b"\x1b[D")?;
+        if started.elapsed() >= timeout {
+            let partial = lines.pop().unwrap_or_default();
+            if !partial.is_empty() {
+                writeln!(out, "{}", partial)?;
             }
+            eprintln!("cc-workbench: --headless timed out after {}s", timeout.as_secs());
+            pty.kill();
+            return Ok(HEADLESS_TIMEOUT_EXIT);
         }
-        KeyEvent {
-            code: KeyCode::Right,
-            ..
-        } => {
-            if matches!(app.focus, Focus::Output) {
-                pty.send_bytes(b"\x1b[C")?;
+        if prompt.is_some() && prompt_ready(started, last_output) {
+            if let Some(text) = prompt.take() {
+                pty.send_bytes(text.as_bytes())?;
+                pty.send_bytes(b"\r")?;
             }
         }
-        KeyEvent {
-            code: KeyCode::PageUp,
-            ..
-        } => {
-            app.follow_output = false;
-            app.output_scroll = app.output_scroll.saturating_sub(10);
-        }
-        KeyEvent {
-            code: KeyCode::PageDown,
-            ..
-        } => {
-            app.output_scroll = (app.output_scroll + 10).min(app.output_lines.len().saturating_sub(1));
+    }
+}
+
+/// App-level commands, shared by their keybindings, the command palette and
+/// the `:` command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    ToggleFocus,
+    DiffSelected,
+    RestoreSelected,
+    RetrySnapshot,
+    TakeSnapshot,
+    Compact,
+    RerunLast,
+    TogglePause,
+    ToggleInspect,
+    ToggleFullscreen,
+    ScrollTop,
+    ScrollBottom,
+    ToggleFollow,
+    ToggleAssistantOnly,
+    ClearOutput,
+    ToggleTimeDisplay,
+    NextProvider,
+    ToggleUsageMode,
+    ToggleOffline,
+    CopyCommit,
+    EditSettings,
+    RefreshUsage,
+    Quit,
+}
+
+impl Action {
+    /// Palette order.
+    const ALL: &'static [Action] = &[
+        Action::ToggleFocus,
+        Action::DiffSelected,
+        Action::RestoreSelected,
+        Action::RetrySnapshot,
+        Action::TakeSnapshot,
+        Action::Compact,
+        Action::RerunLast,
+        Action::TogglePause,
+        Action::ToggleInspect,
+        Action::ToggleFullscreen,
+        Action::ScrollTop,
+        Action::ScrollBottom,
+        Action::ToggleFollow,
+        Action::ToggleAssistantOnly,
+        Action::ClearOutput,
+        Action::ToggleTimeDisplay,
+        Action::NextProvider,
+        Action::ToggleUsageMode,
+        Action::ToggleOffline,
+        Action::CopyCommit,
+        Action::EditSettings,
+        Action::RefreshUsage,
+        Action::Quit,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Action::ToggleFocus => "Switch focus between output and history",
+            Action::DiffSelected => "Diff preview of selected message",
+            Action::RestoreSelected => "Restore selected message's snapshot",
+            Action::RetrySnapshot => "Retry failed snapshot",
+            Action::TakeSnapshot => "Snapshot the workspace now for selected message",
+            Action::Compact => "Send compact command to Claude",
+            Action::RerunLast => "Re-send the last prompt",
+            Action::TogglePause => "Pause/resume usage polling",
+            Action::ToggleInspect => "Toggle read-only inspect mode",
+            Action::ToggleFullscreen => "Toggle fullscreen output",
+            Action::ScrollTop => "Jump to top of output",
+            Action::ScrollBottom => "Follow output",
+            Action::ToggleFollow => "Freeze/unfreeze output view",
+            Action::ToggleAssistantOnly => "Show only Claude's replies in output",
+            Action::ClearOutput => "Clear output scrollback",
+            Action::ToggleTimeDisplay => "Toggle relative/absolute times",
+            Action::NextProvider => "Show next provider first in Usage",
+            Action::ToggleUsageMode => "Toggle usage tokens/percent",
+            Action::ToggleOffline => "Go offline/online (stop/start usage polling)",
+            Action::CopyCommit => "Copy selected message's snapshot commit",
+            Action::EditSettings => "Edit settings",
+            Action::RefreshUsage => "Refresh usage providers now",
+            Action::Quit => "Quit",
         }
-        KeyEvent {
-            code: KeyCode::End,
-            ..
-        } => {
-            app.follow_output = true;
-            app.output_scroll = app.output_lines.len().saturating_sub(1);
-        }
-        KeyEvent {
-            code: KeyCode::Enter,
-            ..
-        } => {
-            if matches!(app.focus, Focus::History) {
-                if let Some(msg) = app.messages.get(app.selected_message) {
-                    app.output_scroll = msg.output_line;
-                    app.follow_output = false;
-                }
-            } else {
-                pty.send_bytes(b"\r")?;
-                let content = app.input_buffer.trim_end().to_string();
-                if !content.is_empty() {
-                    let output_line = app.output_lines.len().saturating_sub(1);
-                    app.record_user_message(db, content, output_line)?;
-                }
-                app.input_buffer.clear();
-            }
-        }
-        KeyEvent {
-            code: KeyCode::Char('d'),
-            ..
-        } => {
-            if matches!(app.focus, Focus::History) {
-                if let Some(msg) = app.messages.get(app.selected_message) {
-                    if let Some(commit) = msg.snapshot_commit.clone() {
-                        open_diff_preview(app, &commit, false)?;
-                    }
-                }
-            }
+    }
+
+    /// The key shown next to the action; Quit follows `quit_key`.
+    fn binding(self, quit_key: char) -> String {
+        let key = match self {
+            Action::ToggleFocus => "Tab",
+            Action::DiffSelected => "d (history)",
+            Action::RestoreSelected => "r (history)",
+            Action::RetrySnapshot => "s (history)",
+            Action::TakeSnapshot => "S (history)",
+            Action::Compact => "F4",
+            Action::RerunLast => "F7",
+            Action::TogglePause => "F2",
+            Action::ToggleInspect => "F3",
+            Action::ToggleFullscreen => "F11",
+            Action::ScrollTop => "Home",
+            Action::ScrollBottom => "End",
+            Action::ToggleFollow => "F5, f (inspect)",
+            Action::ToggleAssistantOnly => "F6",
+            Action::ClearOutput => "Ctrl+K (history, inspect)",
+            Action::ToggleTimeDisplay => "t (history)",
+            Action::NextProvider => "F9",
+            Action::ToggleUsageMode => "F12",
+            Action::ToggleOffline => "F8",
+            Action::CopyCommit => "c (history)",
+            Action::EditSettings => "F10",
+            Action::RefreshUsage => "Ctrl+Space (history, inspect)",
+            Action::Quit => return format!("Ctrl+{}", quit_key.to_ascii_uppercase()),
+        };
+        key.to_string()
+    }
+
+    /// Name on the `:` command line.
+    fn command(self) -> &'static str {
+        match self {
+            Action::ToggleFocus => "focus",
+            Action::DiffSelected => "diff",
+            Action::RestoreSelected => "restore",
+            Action::RetrySnapshot => "retry",
+            Action::TakeSnapshot => "snapshot",
+            Action::Compact => "compact",
+            Action::RerunLast => "rerun",
+            Action::TogglePause => "pause",
+            Action::ToggleInspect => "inspect",
+            Action::ToggleFullscreen => "fullscreen",
+            Action::ScrollTop => "top",
+            Action::ScrollBottom => "bottom",
+            Action::ToggleFollow => "follow",
+            Action::ToggleAssistantOnly => "replies",
+            Action::ClearOutput => "clear",
+            Action::ToggleTimeDisplay => "time",
+            Action::NextProvider => "provider",
+            Action::ToggleUsageMode => "usage",
+            Action::ToggleOffline => "offline",
+            Action::CopyCommit => "copy",
+            Action::EditSettings => "settings",
+            Action::RefreshUsage => "refresh",
+            Action::Quit => "quit",
         }
-        KeyEvent {
-            code: KeyCode::Char('r'),
-            ..
-        } => {
-            if matches!(app.focus, Focus::History) {
-                if let Some(msg) = app.messages.get(app.selected_message) {
-                    if let Some(commit) = msg.snapshot_commit.clone() {
-                        open_diff_preview(app, &commit, true)?;
-                    }
-                }
+    }
+
+    /// Acts on the selected History message, so takes an optional `#n`.
+    fn takes_message(self) -> bool {
+        matches!(
+            self,
+            Action::DiffSelected
+                | Action::RestoreSelected
+                | Action::RetrySnapshot
+                | Action::TakeSnapshot
+                | Action::CopyCommit
+        )
+    }
+}
+
+/// What a `:` command line asks for.
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// An action, run after selecting message `#n` when one is given.
+    Run(Action, Option<i64>),
+    /// Write the session transcript, as `--export-json` does.
+    Export(String),
+    Help,
+}
+
+/// Parses a `:` command line, without the colon.
+fn parse_command(text: &str) -> Result<Command, String> {
+    let mut words = text.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+    match name {
+        "help" => return Ok(Command::Help),
+        "export" => {
+            return match args.as_slice() {
+                [path] => Ok(Command::Export(path.to_string())),
+                _ => Err("usage: :export <file>".to_string()),
             }
         }
-        KeyEvent {
-            code: KeyCode::Backspace,
-            ..
-        } => {
-            if matches!(app.focus, Focus::Output) {
-                app.input_buffer.pop();
-                pty.send_bytes(&[0x7f])?;
-            }
+        "resume" => return Err("nothing to resume: each run starts a new session".to_string()),
+        _ => {}
+    }
+    let action = Action::ALL
+        .iter()
+        .copied()
+        .find(|a| a.command() == name)
+        .ok_or_else(|| format!("unknown command :{} (:help lists them)", name))?;
+    match args.as_slice() {
+        [] => Ok(Command::Run(action, None)),
+        [n] if action.takes_message() => n
+            .trim_start_matches('#')
+            .parse()
+            .map(|n| Command::Run(action, Some(n)))
+            .map_err(|_| format!("not a message number: {}", n)),
+        _ if action.takes_message() => Err(format!("usage: :{} [#message]", name)),
+        _ => Err(format!(":{} takes no arguments", name)),
+    }
+}
+
+/// Runs a `:` command line; problems are reported as notices in the output.
+fn run_command(text: &str, pty: &mut PtyProcess, db: &mut Database, app: &mut App) -> Result<bool> {
+    if text.is_empty() {
+        return Ok(false);
+    }
+    match parse_command(text) {
+        Err(err) => app.push_notice(format!(":{}: {}", text, err), Color::Red),
+        Ok(Command::Help) => {
+            let names: Vec<&str> = Action::ALL.iter().map(|a| a.command()).collect();
+            app.push_notice(
+                format!(
+                    "Commands: {}, export <file>, help. diff, restore, retry, snapshot and copy take a message number",
+                    names.join(", ")
+                ),
+                Color::Cyan,
+            );
         }
-        KeyEvent {
-            code: KeyCode::Char(c),
-            modifiers: KeyModifiers::NONE,
-            ..
-        } => {
-            if matches!(app.focus, Focus::Output) {
-                app.input_buffer.push(c);
-                pty.send_bytes(c.to_string().as_bytes())?;
+        Ok(Command::Export(_)) if app.locked_out => app.push_notice(
+            "Another cc-workbench is using this workspace, so this one has no session to export".to_string(),
+            Color::Yellow,
+        ),
+        Ok(Command::Export(file)) => {
+            let path = resolve_path(&app.snapshot_manager.workspace, &file);
+            let transcript = db.load_transcript(&app.session_id)?;
+            match fs::write(&path, serde_json::to_string_pretty(&transcript)?) {
+                Ok(()) => app.push_notice(
+                    format!("Exported {} turn(s) to {}", transcript.turns.len(), path.display()),
+                    Color::Cyan,
+                ),
+                Err(err) => app.push_notice(format!("Export to {} failed: {}", path.display(), err), Color::Red),
             }
         }
-        KeyEvent {
-            code: KeyCode::Char(c),
-            modifiers,
-            ..
-        } => {
-            if matches!(app.focus, Focus::Output) {
-                if modifiers.contains(KeyModifiers::CONTROL) {
-                    let ctrl = (c as u8) & 0x1f;
-                    pty.send_bytes(&[ctrl])?;
-                } else {
-                    pty.send_bytes(c.to_string().as_bytes())?;
+        Ok(Command::Run(action, message)) => {
+            if let Some(idx) = message {
+                match app.messages.iter().position(|m| m.idx == idx) {
+                    Some(pos) => app.selected_message = pos,
+                    None => {
+                        app.push_notice(format!(":{}: no message #{}", text, idx), Color::Red);
+                        return Ok(false);
+                    }
                 }
             }
+            return perform_action(action, pty, db, app);
         }
-        _ => {}
     }
     Ok(false)
 }
 
-fn handle_diff_keys(key: KeyEvent, app: &mut App) -> Result<bool> {
-    let preview = app.diff_preview.as_mut().unwrap();
-    match key.code {
-        KeyCode::Esc => {
-            app.diff_preview = None;
-        }
-        KeyCode::Char('q') => {
-            app.diff_preview = None;
+/// Runs `action`; returns true when the app should quit.
+fn perform_action(action: Action, pty: &mut PtyProcess, db: &mut Database, app: &mut App) -> Result<bool> {
+    match action {
+        Action::Quit => return Ok(app.request_quit()),
+        Action::TogglePause => {
+            app.usage_manager.toggle_paused();
         }
-        KeyCode::Up => {
-            preview.scroll = preview.scroll.saturating_sub(1);
+        Action::ToggleInspect if app.locked_out => app.push_notice(
+            "Another cc-workbench is using this workspace; staying read-only".to_string(),
+            Color::Yellow,
+        ),
+        Action::ToggleInspect => app.inspect = !app.inspect,
+        Action::Compact => {
+            if app.inspect {
+                return Ok(false);
+            }
+            let command = app.config.compact_command.clone();
+            submit_prompt(pty, db, app, command)?;
         }
-        KeyCode::Down => {
-            preview.scroll = (preview.scroll + 1).min(preview.lines.len().saturating_sub(1));
+        Action::RerunLast => {
+            if app.inspect {
+                return Ok(false);
+            }
+            if let Some(prompt) = app.last_prompt() {
+                submit_prompt(pty, db, app, prompt)?;
+            }
         }
-        KeyCode::PageUp => {
-            preview.scroll = preview.scroll.saturating_sub(10);
+        Action::ToggleFullscreen => {
+            // run_app notices the output area changed and resizes the PTY.
+            app.fullscreen_output = !app.fullscreen_output;
         }
-        KeyCode::PageDown => {
-            preview.scroll = (preview.scroll + 10).min(preview.lines.len().saturating_sub(1));
+        Action::ToggleFocus => {
+            app.focus = match app.focus {
+                Focus::Output if app.config.panels.contains(&Panel::History) => Focus::History,
+                _ => Focus::Output,
+            };
         }
-        KeyCode::Char('y') => {
-            if let Some(commit) = preview.pending_restore.clone() {
-                app.snapshot_manager.restore(&commit)?;
+        Action::DiffSelected | Action::RestoreSelected => {
+            if !app.snapshots_enabled {
+                return Ok(false);
+            }
+            if let Some(msg) = app.messages.get(app.selected_message) {
+                if let Some(commit) = msg.snapshot_commit.clone() {
+                    // Restoring writes to the workspace, so read-only mode only previews.
+                    let restore = action == Action::RestoreSelected && !app.inspect;
+                    // With a snapshot from the end of the turn, `d` shows just
+                    // what the turn changed.
+                    let until = msg.snapshot_commit_after.clone().filter(|_| action == Action::DiffSelected);
+                    open_diff_preview(app, &commit, until.as_deref(), restore)?;
+                }
             }
-            app.diff_preview = None;
         }
-        KeyCode::Char('n') => {
-            app.diff_preview = None;
+        Action::RetrySnapshot => app.retry_snapshot(app.selected_message),
+        Action::TakeSnapshot if app.locked_out => app.push_notice(
+            "Another cc-workbench is using this workspace; not snapshotting".to_string(),
+            Color::Yellow,
+        ),
+        Action::TakeSnapshot => app.take_snapshot(app.selected_message),
+        Action::ScrollTop => app.scroll_output(isize::MIN),
+        Action::ScrollBottom => app.scroll_output(isize::MAX),
+        Action::ToggleFollow => app.toggle_follow(),
+        Action::ToggleAssistantOnly => app.toggle_assistant_only(),
+        Action::ClearOutput => app.clear_output(),
+        Action::ToggleTimeDisplay => app.toggle_time_display(),
+        Action::NextProvider => {
+            let providers = app.usage_entries().len();
+            app.usage_view.select_next(providers);
+        }
+        Action::ToggleUsageMode => app.usage_view.toggle_mode(),
+        Action::ToggleOffline => {
+            app.usage_manager.toggle_offline();
+        }
+        Action::CopyCommit => app.copy_selected_commit(copy_to_clipboard),
+        Action::EditSettings => app.settings = Some(SettingsEditor::new(&app.config)),
+        Action::RefreshUsage => {
+            if !app.usage_manager.refresh_now() {
+                app.push_notice(
+                    "Nothing to refresh: no httpjson provider is polling (offline, paused or none configured)".to_string(),
+                    Color::DarkGray,
+                );
+            }
         }
-        _ => {}
     }
     Ok(false)
 }
 
-fn open_diff_preview(app: &mut App, commit: &str, pending_restore: bool) -> Result<()> {
-    let diff = app.snapshot_manager.diff_preview(commit)?;
-    let lines: Vec<String> = if diff.is_empty() {
-        vec!["(no changes)".to_string()]
-    } else {
-        diff.lines().map(|l| l.to_string()).collect()
-    };
-    app.diff_preview = Some(DiffPreview {
-        title: format!("Diff for {}", commit),
-        lines,
-        scroll: 0,
-        pending_restore: if pending_restore {
-            Some(commit.to_string())
-        } else {
-            None
-        },
-    });
-    Ok(())
+/// Types `text` into Claude and submits it, recording it in History like a
+/// prompt typed by hand. Whatever was half-typed in the prompt box is
+/// replaced; Ctrl-U clears it first.
+fn submit_prompt(pty: &mut PtyProcess, db: &mut Database, app: &mut App, text: String) -> Result<()> {
+    pty.send_bytes(&[0x15])?;
+    pty.send_bytes(text.as_bytes())?;
+    pty.send_bytes(b"\r")?;
+    app.input.clear();
+    let output_line = app.output_lines.len().saturating_sub(1);
+    app.record_user_message(db, text, output_line)
 }
 
-fn draw_ui(f: &mut Frame, app: &mut App) {
-    let size = f.size();
-    let chunks = Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints([Constraint::Percentage(68), Constraint::Percentage(32)])
-        .split(size);
-
-    draw_output_panel(f, app, chunks[0]);
-    draw_workbench(f, app, chunks[1]);
-
-    if let Some(preview) = &app.diff_preview {
-        draw_diff_preview(f, preview, size);
+/// Claude counts as started once its banner has been quiet for
+/// `PROMPT_IDLE`, or after `PROMPT_MAX_WAIT` regardless.
+fn prompt_ready(started: Instant, last_output: Option<Instant>) -> bool {
+    match last_output {
+        Some(at) if at.elapsed() >= PROMPT_IDLE => true,
+        _ => started.elapsed() >= PROMPT_MAX_WAIT,
     }
 }
 
-fn draw_output_panel(f: &mut Frame, app: &mut App, area: Rect) {
-    let title = if matches!(app.focus, Focus::Output) {
-        "Claude (focused)"
-    } else {
-        "Claude"
-    };
-    let block = Block::default().title(title).borders(Borders::ALL);
-    let visible_height = area.height.saturating_sub(2) as usize;
-    let start = app.output_scroll.saturating_sub(visible_height.saturating_sub(1));
-    let end = (start + visible_height).min(app.output_lines.len());
-    let lines: Vec<Line> = app.output_lines[start..end]
-        .iter()
-        .map(|l| Line::raw(l.clone()))
-        .collect();
-    let paragraph = Paragraph::new(lines)
-        .block(block)
-        .wrap(Wrap { trim: false });
-    f.render_widget(paragraph, area);
+/// Ctrl-P overlay listing every `Action`, filtered by the typed query.
+struct CommandPalette {
+    query: String,
+    selected: usize,
+    /// `config.quit_key`, for Quit's binding.
+    quit_key: char,
 }
 
-fn draw_workbench(f: &mut Frame, app: &mut App, area: Rect) {
-    let sections = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([
-            Constraint::Length(7),
-            Constraint::Length(7),
-            Constraint::Min(5),
-        ])
-        .split(area);
+impl CommandPalette {
+    fn new(quit_key: char) -> Self {
+        Self {
+            query: String::new(),
+            selected: 0,
+            quit_key,
+        }
+    }
 
-    draw_usage_panel(f, app, sections[0]);
-    draw_context_panel(f, app, sections[1]);
-    draw_history_panel(f, app, sections[2]);
+    /// Actions whose label or binding contains the query, case-insensitively.
+    fn matches(&self) -> Vec<Action> {
+        let query = self.query.to_lowercase();
+        Action::ALL
+            .iter()
+            .copied()
+            .filter(|a| {
+                a.label().to_lowercase().contains(&query) || a.binding(self.quit_key).to_lowercase().contains(&query)
+            })
+            .collect()
+    }
 }
 
-fn draw_usage_panel(f: &mut Frame, app: &mut App, area: Rect) {
-    let context_tokens = app.estimate_context_tokens() as u64;
-    let entries = app.usage_manager.entries(context_tokens);
-    let mut lines: Vec<Line> = Vec::new();
-    if let Some(primary) = entries.first() {
-        if let (Some(used), Some(limit)) = (primary.used, primary.limit) {
-            let pct = if limit == 0 { 0.0 } else { used as f64 / limit as f64 };
-            let bar_width = area.width.saturating_sub(2) as usize;
-            let filled = ((bar_width as f64) * pct).round() as usize;
-            let mut bar = String::new();
-            for i in 0..bar_width {
-                if i < filled {
-                    bar.push('█');
-                } else {
-                    bar.push('░');
-                }
-            }
-            lines.push(Line::from(Span::raw(format!(
-                "{}: {} / {} tokens",
-                primary.name, used, limit
-            ))));
-            lines.push(Line::from(Span::raw(bar)));
-        } else {
-            lines.push(Line::from(Span::raw(format!(
-                "{}: {}",
-                primary.name,
-                primary.status.clone().unwrap_or_else(|| "unavailable".to_string())
-            ))));
+/// A setting the F10 editor can change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Setting {
+    ContextLimit,
+    CompressThreshold,
+    UsagePollSeconds,
+    ScrollbackLines,
+}
+
+impl Setting {
+    const ALL: &'static [Setting] = &[
+        Setting::ContextLimit,
+        Setting::CompressThreshold,
+        Setting::UsagePollSeconds,
+        Setting::ScrollbackLines,
+    ];
+
+    /// The `config.json` key.
+    fn key(self) -> &'static str {
+        match self {
+            Setting::ContextLimit => "context_limit",
+            Setting::CompressThreshold => "compress_threshold",
+            Setting::UsagePollSeconds => "usage_poll_seconds",
+            Setting::ScrollbackLines => "scrollback_lines",
         }
     }
-    for entry in entries.iter().skip(1) {
-        let line = match (entry.used, entry.limit) {
-            (Some(used), Some(limit)) => format!("{}: {} / {} tokens", entry.name, used, limit),
-            _ => format!(
-                "{}: {}",
-                entry.name,
-                entry.status.clone().unwrap_or_else(|| "unavailable".to_string())
-            ),
-        };
-        lines.push(Line::from(Span::raw(line)));
-    }
-    if lines.is_empty() {
-        lines.push(Line::from(Span::raw("No providers configured")));
+
+    /// Whether a change applies to the running session; the others are read
+    /// once at startup (the limit by the usage providers, the interval by
+    /// the pollers).
+    fn live(self) -> bool {
+        matches!(self, Setting::CompressThreshold | Setting::ScrollbackLines)
     }
-    let paragraph = Paragraph::new(lines)
-        .block(Block::default().title("Usage").borders(Borders::ALL));
-    f.render_widget(paragraph, area);
-}
 
-fn draw_context_panel(f: &mut Frame, app: &mut App, area: Rect) {
-    let used = app.estimate_context_tokens() as f32;
-    let limit = app.config.context_limit as f32;
-    let pct = if limit == 0.0 { 0.0 } else { used / limit };
-    let threshold = app.config.compress_threshold;
-    let remaining_pct = (threshold - pct).max(0.0);
-    let bar_width = area.width.saturating_sub(2) as usize;
-    let filled = ((bar_width as f32) * pct).round() as usize;
-    let mut bar = String::new();
-    for i in 0..bar_width {
-        if i < filled {
-            bar.push('█');
-        } else {
-            bar.push('░');
+    fn current(self, config: &Config) -> String {
+        match self {
+            Setting::ContextLimit => config.context_limit.to_string(),
+            Setting::CompressThreshold => format!("{:.2}", config.compress_threshold),
+            Setting::UsagePollSeconds => config.usage_poll_seconds.to_string(),
+            Setting::ScrollbackLines => config.scrollback_lines.to_string(),
         }
     }
-    let color = if pct >= threshold { Color::Red } else { Color::Green };
-    let lines = vec![
-        Line::from(vec![
-            Span::raw("Context: "),
-            Span::styled(format!("{:.1}%", pct * 100.0), Style::default().fg(color)),
-        ]),
-        Line::from(Span::raw(bar)),
-        Line::from(Span::raw(format!("Distance to compression: {:.1}%", remaining_pct * 100.0))),
-    ];
-    let paragraph = Paragraph::new(lines)
-        .block(Block::default().title("Context").borders(Borders::ALL));
-    f.render_widget(paragraph, area);
-}
 
-fn draw_history_panel(f: &mut Frame, app: &mut App, area: Rect) {
-    let title = if matches!(app.focus, Focus::History) {
-        "History (Tab to focus, d diff, r restore)"
-    } else {
-        "History"
-    };
-    let items: Vec<ListItem> = app
-        .messages
-        .iter()
-        .map(|m| {
-            let mut preview = m.content.clone();
-            if preview.len() > 40 {
-                preview.truncate(40);
-                preview.push_str("…");
-            }
-            let suffix = if m.snapshot_commit.is_some() { "✓" } else { "…" };
-            ListItem::new(Line::from(Span::raw(format!("{} {}", preview, suffix))))
+    /// Validates `text` into the value written to `config.json`.
+    fn parse(self, text: &str) -> Result<serde_json::Value> {
+        let text = text.trim();
+        let invalid = || anyhow!("{}: invalid value {:?}", self.key(), text);
+        Ok(match self {
+            Setting::ContextLimit => match text.parse::<u32>() {
+                Ok(val) if val > 0 => val.into(),
+                _ => return Err(invalid()),
+            },
+            Setting::CompressThreshold => match text.parse::<f64>() {
+                Ok(val) if val > 0.0 && val <= 1.0 => ((val * 100.0).round() / 100.0).into(),
+                _ => return Err(anyhow!("{}: must be above 0 and at most 1", self.key())),
+            },
+            Setting::UsagePollSeconds => match text.parse::<u64>() {
+                Ok(val) if val > 0 => val.into(),
+                _ => return Err(invalid()),
+            },
+            Setting::ScrollbackLines => match text.parse::<usize>() {
+                Ok(val) if val >= MIN_SCROLLBACK_LINES => val.into(),
+                _ => return Err(anyhow!("{}: must be at least {}", self.key(), MIN_SCROLLBACK_LINES)),
+            },
         })
-        .collect();
-    let list = List::new(items)
-        .block(Block::default().title(title).borders(Borders::ALL))
-        .highlight_style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD))
-        .highlight_symbol("➜ ");
-    let mut state = ListState::default();
-    if !app.messages.is_empty() {
-        state.select(Some(app.selected_message.min(app.messages.len() - 1)));
     }
-    f.render_stateful_widget(list, area, &mut state);
-}
 
-use ratatui::widgets::ListState;
+    /// `text` moved one step up or down, for Left/Right.
+    fn step(self, text: &str, up: bool) -> String {
+        let sign = if up { 1.0 } else { -1.0 };
+        let (step, min) = match self {
+            Setting::ContextLimit => (10_000.0, 10_000.0),
+            Setting::CompressThreshold => (0.05, 0.05),
+            Setting::UsagePollSeconds => (5.0, 5.0),
+            Setting::ScrollbackLines => (1000.0, MIN_SCROLLBACK_LINES as f64),
+        };
+        let Ok(val) = text.trim().parse::<f64>() else {
+            return text.to_string();
+        };
+        let next = (val + sign * step).max(min);
+        match self {
+            Setting::CompressThreshold => format!("{:.2}", next.min(1.0)),
+            _ => format!("{}", next.round() as u64),
+        }
+    }
 
-fn draw_diff_preview(f: &mut Frame, preview: &DiffPreview, area: Rect) {
-    let popup = centered_rect(90, 80, area);
-    let block = Block::default().title(preview.title.clone()).borders(Borders::ALL);
-    let height = popup.height.saturating_sub(2) as usize;
-    let start = preview.scroll.saturating_sub(height.saturating_sub(1));
-    let end = (start + height).min(preview.lines.len());
-    let lines: Vec<Line> = preview.lines[start..end]
-        .iter()
-        .map(|l| Line::raw(l.clone()))
-        .collect();
-    let mut footer = Vec::new();
-    if preview.pending_restore.is_some() {
-        footer.push(Line::from(Span::styled(
-            "Press y to restore, n to cancel",
-            Style::default().fg(Color::Yellow),
-        )));
-    } else {
-        footer.push(Line::from(Span::raw("Press q or Esc to close")));
+    fn apply(self, config: &mut Config, value: &serde_json::Value) {
+        match self {
+            Setting::ContextLimit => config.context_limit = value.as_u64().unwrap_or_default() as u32,
+            Setting::CompressThreshold => {
+                config.compress_threshold = value.as_f64().unwrap_or_default() as f32
+            }
+            Setting::UsagePollSeconds => config.usage_poll_seconds = value.as_u64().unwrap_or_default(),
+            Setting::ScrollbackLines => {
+                config.scrollback_lines = value.as_u64().unwrap_or_default() as usize
+            }
+        }
     }
-    let mut text = Text::from(lines);
-    text.lines.extend(footer);
-    let paragraph = Paragraph::new(text).block(block).wrap(Wrap { trim: false });
-    f.render_widget(paragraph, popup);
 }
 
-fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
-    let popup_layout = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([
-            Constraint::Percentage((100 - percent_y) / 2),
-            Constraint::Percentage(percent_y),
-            Constraint::Percentage((100 - percent_y) / 2),
-        ])
-        .split(r);
-    Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints([
-            Constraint::Percentage((100 - percent_x) / 2),
-            Constraint::Percentage(percent_x),
-            Constraint::Percentage((100 - percent_x) / 2),
-        ])
-        .split(popup_layout[1])[1]
+/// F10 overlay: the `Setting`s as editable text, one per row.
+struct SettingsEditor {
+    values: Vec<String>,
+    original: Vec<String>,
+    selected: usize,
+    error: Option<String>,
 }
 
-fn append_output_lines(lines: &mut Vec<String>, chunk: &str) {
-    let chunk = chunk.replace('\r', "");
-    let mut iter = chunk.split('\n');
-    if let Some(first) = iter.next() {
-        if let Some(last) = lines.last_mut() {
-            last.push_str(first);
-        } else {
-            lines.push(first.to_string());
+impl SettingsEditor {
+    fn new(config: &Config) -> Self {
+        let values: Vec<String> = Setting::ALL.iter().map(|s| s.current(config)).collect();
+        Self {
+            original: values.clone(),
+            values,
+            selected: 0,
+            error: None,
         }
     }
-    for part in iter {
-        lines.push(part.to_string());
+
+    /// The settings whose text changed, parsed; the first invalid one is an
+    /// error.
+    fn changes(&self) -> Result<Vec<(Setting, serde_json::Value)>> {
+        Setting::ALL
+            .iter()
+            .zip(self.values.iter().zip(&self.original))
+            .filter(|(_, (value, original))| value != original)
+            .map(|(setting, (value, _))| Ok((*setting, setting.parse(value)?)))
+            .collect()
+    }
+}
+
+/// Sets `values` in the JSON object at `path`, keeping every other key, and
+/// creates the file if needed.
+fn write_config_values(path: &Path, values: &[(&str, serde_json::Value)]) -> Result<()> {
+    let mut doc = match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| anyhow!("{}: {}", path.display(), e))?,
+        Err(_) => serde_json::Value::Object(Default::default()),
+    };
+    let obj = doc
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("{}: not a JSON object", path.display()))?;
+    for (key, value) in values {
+        obj.insert(key.to_string(), value.clone());
     }
-    let max_lines = 5000;
-    if lines.len() > max_lines {
-        let excess = lines.len() - max_lines;
-        lines.drain(0..excess);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
     }
+    fs::write(path, serde_json::to_string_pretty(&doc)? + "\n")?;
+    Ok(())
 }
 
-fn strip_ansi(input: &str) -> String {
-    let mut out = String::with_capacity(input.len());
-    let mut chars = input.chars().peekable();
-    while let Some(c) = chars.next() {
-        if c == '\u{1b}' {
-            if let Some('[') = chars.peek().copied() {
-                chars.next();
-                while let Some(ch) = chars.next() {
-                    if ('@'..='~').contains(&ch) {
-                        break;
-                    }
-                }
+impl App {
+    /// Writes the editor's changes to `config_path` and applies the live
+    /// ones. Returns the keys that only take effect after a restart.
+    fn save_settings(&mut self, changes: &[(Setting, serde_json::Value)]) -> Result<Vec<&'static str>> {
+        let values: Vec<(&str, serde_json::Value)> = changes.iter().map(|(s, v)| (s.key(), v.clone())).collect();
+        write_config_values(&self.config_path, &values)?;
+        let mut restart = Vec::new();
+        for (setting, value) in changes {
+            if setting.live() {
+                setting.apply(&mut self.config, value);
+            } else {
+                restart.push(setting.key());
             }
-        } else {
-            out.push(c);
         }
+        let drained = trim_scrollback(&mut self.output_lines, self.config.scrollback_lines);
+        self.lines_drained(drained);
+        self.output_scroll = self.output_scroll.min(self.output_lines.len().saturating_sub(1));
+        Ok(restart)
     }
-    out
 }
 
-fn estimate_tokens(text: &str) -> u32 {
-    let chars = text.chars().count() as f32;
-    (chars / 4.0).ceil() as u32
-}
+fn handle_settings_keys(key: KeyEvent, app: &mut App) -> Result<bool> {
+    let Some(editor) = app.settings.as_mut() else {
+        return Ok(false);
+    };
+    let setting = Setting::ALL[editor.selected];
+    let value = &mut editor.values[editor.selected];
+    match key.code {
+        KeyCode::Esc => app.settings = None,
+        KeyCode::Up => editor.selected = editor.selected.saturating_sub(1),
+        KeyCode::Down => editor.selected = (editor.selected + 1).min(Setting::ALL.len() - 1),
+        KeyCode::Left => *value = setting.step(value, false),
+        KeyCode::Right => *value = setting.step(value, true),
+        KeyCode::Backspace => {
+            value.pop();
+        }
+        KeyCode::Char(c) if c.is_ascii_digit() || c == '.' => value.push(c),
+        KeyCode::Enter => match editor.changes() {
+            Err(err) => editor.error = Some(err.to_string()),
+            Ok(changes) if changes.is_empty() => app.settings = None,
+            Ok(changes) => match app.save_settings(&changes) {
+                Err(err) => {
+                    if let Some(editor) = app.settings.as_mut() {
+                        editor.error = Some(format!("Could not save: {}", err));
+                    }
+                }
+                Ok(restart) => {
+                    app.settings = None;
+                    let mut note = format!("Saved {} setting(s) to {}", changes.len(), app.config_path.display());
+                    if !restart.is_empty() {
+                        note.push_str(&format!("; restart required for {}", restart.join(", ")));
+                    }
+                    app.push_notice(note, Color::DarkGray);
+                }
+            },
+        },
+        _ => {}
+    }
+    Ok(false)
+}
+
+fn handle_palette_keys(key: KeyEvent, pty: &mut PtyProcess, db: &mut Database, app: &mut App) -> Result<bool> {
+    let Some(palette) = app.palette.as_mut() else {
+        return Ok(false);
+    };
+    match key.code {
+        KeyCode::Esc => app.palette = None,
+        KeyCode::Up => palette.selected = palette.selected.saturating_sub(1),
+        KeyCode::Down => {
+            palette.selected = (palette.selected + 1).min(palette.matches().len().saturating_sub(1));
+        }
+        KeyCode::Backspace => {
+            palette.query.pop();
+            palette.selected = 0;
+        }
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+            palette.query.push(c);
+            palette.selected = 0;
+        }
+        KeyCode::Enter => {
+            let action = palette.matches().get(palette.selected).copied();
+            app.palette = None;
+            if let Some(action) = action {
+                return perform_action(action, pty, db, app);
+            }
+        }
+        _ => {}
+    }
+    Ok(false)
+}
+
+/// Keys while the `:` command line is open; none of them reach Claude. A
+/// second `:` right away closes it and types a literal `:` to Claude.
+fn handle_command_keys(key: KeyEvent, pty: &mut PtyProcess, db: &mut Database, app: &mut App) -> Result<bool> {
+    let Some(line) = app.command_line.as_mut() else {
+        return Ok(false);
+    };
+    match key.code {
+        KeyCode::Esc => app.command_line = None,
+        KeyCode::Backspace if line.chars.is_empty() => app.command_line = None,
+        KeyCode::Backspace => line.backspace(),
+        KeyCode::Left => line.move_left(),
+        KeyCode::Right => line.move_right(),
+        KeyCode::Char(':') if line.chars.is_empty() => {
+            app.command_line = None;
+            app.input.insert_str(":");
+            pty.send_bytes(b":")?;
+        }
+        KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => line.kill_to_start(),
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => line.insert_str(&c.to_string()),
+        KeyCode::Enter => {
+            let text = line.take();
+            app.command_line = None;
+            return run_command(&text, pty, db, app);
+        }
+        _ => {}
+    }
+    Ok(false)
+}
+
+fn handle_key_event(key: KeyEvent, pty: &mut PtyProcess, db: &mut Database, app: &mut App) -> Result<bool> {
+    debug_log!("key {} focus={:?}", key_summary(&key), app.focus);
+    if app.diff_preview.is_some() {
+        return handle_diff_keys(key, app);
+    }
+    if app.palette.is_some() {
+        return handle_palette_keys(key, pty, db, app);
+    }
+    if app.command_line.is_some() {
+        return handle_command_keys(key, pty, db, app);
+    }
+    if app.settings.is_some() {
+        return handle_settings_keys(key, app);
+    }
+    if app.confirm_quit {
+        return Ok(handle_confirm_quit_keys(key, app));
+    }
+
+    // In inspect mode nothing is forwarded to Claude; keys only navigate.
+    // Ctrl chords Claude has its own use for (history, kill line, yank, …)
+    // are ours only while nothing is being passed through.
+    let passthrough = matches!(app.focus, Focus::Output) && !app.inspect;
+    match key {
+        KeyEvent {
+            code: KeyCode::Char(c),
+            modifiers: KeyModifiers::CONTROL,
+            ..
+        } if c == app.config.quit_key => return perform_action(Action::Quit, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(1),
+            ..
+        } => {
+            app.palette = Some(CommandPalette::new(app.config.quit_key));
+        }
+        KeyEvent {
+            code: KeyCode::Char('p'),
+            modifiers: KeyModifiers::CONTROL,
+            ..
+        } if !passthrough => {
+            app.palette = Some(CommandPalette::new(app.config.quit_key));
+        }
+        KeyEvent {
+            code: KeyCode::Char('k'),
+            modifiers: KeyModifiers::CONTROL,
+            ..
+        } if !passthrough => return perform_action(Action::ClearOutput, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(7),
+            ..
+        } => return perform_action(Action::RerunLast, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(2),
+            ..
+        } => return perform_action(Action::TogglePause, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(8),
+            ..
+        } => return perform_action(Action::ToggleOffline, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Char(' '),
+            modifiers: KeyModifiers::CONTROL,
+            ..
+        } if !passthrough => return perform_action(Action::RefreshUsage, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(10),
+            ..
+        } => return perform_action(Action::EditSettings, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(5),
+            ..
+        } => return perform_action(Action::ToggleFollow, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(6),
+            ..
+        } => return perform_action(Action::ToggleAssistantOnly, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Char('f'),
+            modifiers: KeyModifiers::NONE,
+            ..
+        } if matches!(app.focus, Focus::Output) && !passthrough => {
+            return perform_action(Action::ToggleFollow, pty, db, app)
+        }
+        KeyEvent {
+            code: KeyCode::F(9),
+            ..
+        } => return perform_action(Action::NextProvider, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(12),
+            ..
+        } => return perform_action(Action::ToggleUsageMode, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(3),
+            ..
+        } => return perform_action(Action::ToggleInspect, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(4),
+            ..
+        } => return perform_action(Action::Compact, pty, db, app),
+        KeyEvent {
+            code: KeyCode::F(11),
+            ..
+        } => return perform_action(Action::ToggleFullscreen, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Tab,
+            ..
+        } => return perform_action(Action::ToggleFocus, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Up,
+            ..
+        } => {
+            if matches!(app.focus, Focus::History) {
+                if app.selected_message > 0 {
+                    app.selected_message -= 1;
+                }
+            } else if passthrough {
+                // Claude recalls its own history here; we can't see what it put in the box.
+                app.input.recall();
+                pty.send_bytes(b"\x1b[A")?;
+            } else {
+                app.scroll_output(-1);
+            }
+        }
+        KeyEvent {
+            code: KeyCode::Down,
+            ..
+        } => {
+            if matches!(app.focus, Focus::History) {
+                if app.selected_message + 1 < app.messages.len() {
+                    app.selected_message += 1;
+                }
+            } else if passthrough {
+                app.input.recall();
+                pty.send_bytes(b"\x1b[B")?;
+            } else {
+                app.scroll_output(1);
+            }
+        }
+        KeyEvent {
+            code: KeyCode::Left,
+            ..
+        } if passthrough => {
+            app.input.move_left();
+            pty.send_bytes(b"\x1b[D")?;
+        }
+        KeyEvent {
+            code: KeyCode::Right,
+            ..
+        } if passthrough => {
+            app.input.move_right();
+            pty.send_bytes(b"\x1b[C")?;
+        }
+        KeyEvent {
+            code: KeyCode::PageUp,
+            ..
+        } => {
+            app.scroll_output(-(app.output_height.max(1) as isize));
+        }
+        KeyEvent {
+            code: KeyCode::PageDown,
+            ..
+        } => {
+            app.scroll_output(app.output_height.max(1) as isize);
+        }
+        KeyEvent {
+            code: KeyCode::Char('u'),
+            modifiers: KeyModifiers::CONTROL,
+            ..
+        } if !passthrough => {
+            app.scroll_output(-((app.output_height / 2).max(1) as isize));
+        }
+        KeyEvent {
+            code: KeyCode::Char('d'),
+            modifiers: KeyModifiers::CONTROL,
+            ..
+        } if !passthrough => {
+            app.scroll_output((app.output_height / 2).max(1) as isize);
+        }
+        KeyEvent {
+            code: KeyCode::Home | KeyCode::Char('g'),
+            modifiers: KeyModifiers::NONE,
+            ..
+        } if matches!(app.focus, Focus::History) => app.select_history_edge(false),
+        KeyEvent {
+            code: KeyCode::End | KeyCode::Char('G'),
+            ..
+        } if matches!(app.focus, Focus::History) => app.select_history_edge(true),
+        KeyEvent {
+            code: KeyCode::Home,
+            ..
+        } => return perform_action(Action::ScrollTop, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Char('g'),
+            modifiers: KeyModifiers::NONE,
+            ..
+        } if !passthrough => return perform_action(Action::ScrollTop, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Char('G'),
+            ..
+        } if !passthrough => return perform_action(Action::ScrollBottom, pty, db, app),
+        KeyEvent {
+            code: KeyCode::End,
+            ..
+        } => return perform_action(Action::ScrollBottom, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Enter,
+            ..
+        } => {
+            if matches!(app.focus, Focus::History) {
+                if let Some(msg) = app.messages.get(app.selected_message) {
+                    app.output_scroll = msg.output_line;
+                    app.follow_output = false;
+                }
+            } else if passthrough {
+                pty.send_bytes(b"\r")?;
+                let typed = app.input.take();
+                let content = match app.config.history_capture {
+                    HistoryCapture::Input => typed,
+                    HistoryCapture::Output => submitted_line_from_output(&app.output_lines).unwrap_or(typed),
+                };
+                if !content.is_empty() {
+                    let output_line = app.output_lines.len().saturating_sub(1);
+                    app.record_user_message(db, content, output_line)?;
+                }
+            }
+        }
+        KeyEvent {
+            code: KeyCode::Char('d'),
+            ..
+        } if matches!(app.focus, Focus::History) => return perform_action(Action::DiffSelected, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Char('t'),
+            ..
+        } if matches!(app.focus, Focus::History) => return perform_action(Action::ToggleTimeDisplay, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Char('r'),
+            ..
+        } if matches!(app.focus, Focus::History) => return perform_action(Action::RestoreSelected, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Char('s'),
+            ..
+        } if matches!(app.focus, Focus::History) => return perform_action(Action::RetrySnapshot, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Char('S'),
+            ..
+        } if matches!(app.focus, Focus::History) => return perform_action(Action::TakeSnapshot, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Char('c'),
+            modifiers: KeyModifiers::NONE,
+            ..
+        } if matches!(app.focus, Focus::History) => return perform_action(Action::CopyCommit, pty, db, app),
+        KeyEvent {
+            code: KeyCode::Backspace,
+            ..
+        } if passthrough => {
+            app.input.backspace();
+            pty.send_bytes(&[0x7f])?;
+        }
+        // A `:` at the start of an empty prompt opens the command line.
+        KeyEvent {
+            code: KeyCode::Char(':'),
+            modifiers,
+            ..
+        } if passthrough
+            && app.input.is_empty()
+            && !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+        {
+            app.command_line = Some(InputLine::default())
+        }
+        KeyEvent {
+            code: KeyCode::Char(c),
+            modifiers: KeyModifiers::NONE,
+            ..
+        } if passthrough => {
+            app.input.insert_str(&c.to_string());
+            pty.send_bytes(c.to_string().as_bytes())?;
+        }
+        KeyEvent {
+            code: KeyCode::Char(c),
+            modifiers,
+            ..
+        } if passthrough => {
+            if modifiers.contains(KeyModifiers::CONTROL) {
+                match c {
+                    'u' => app.input.kill_to_start(),
+                    'c' => app.input.clear(),
+                    _ => {}
+                }
+                let ctrl = (c as u8) & 0x1f;
+                pty.send_bytes(&[ctrl])?;
+            } else {
+                app.input.insert_str(&c.to_string());
+                pty.send_bytes(c.to_string().as_bytes())?;
+            }
+        }
+        _ => {}
+    }
+    Ok(false)
+}
+
+/// Forwards a paste to Claude as a bracketed paste, so multi-line text isn't
+/// submitted line by line, and mirrors it into the tracked input line.
+fn handle_paste(text: &str, pty: &mut PtyProcess, app: &mut App) -> Result<()> {
+    if let Some(line) = &mut app.command_line {
+        line.insert_str(text);
+        return Ok(());
+    }
+    if app.diff_preview.is_some()
+        || app.palette.is_some()
+        || app.settings.is_some()
+        || app.confirm_quit
+        || !matches!(app.focus, Focus::Output)
+        || app.inspect
+    {
+        return Ok(());
+    }
+    app.input.insert_str(text);
+    pty.send_bytes(b"\x1b[200~")?;
+    pty.send_bytes(text.as_bytes())?;
+    pty.send_bytes(b"\x1b[201~")?;
+    Ok(())
+}
+
+fn handle_diff_keys(key: KeyEvent, app: &mut App) -> Result<bool> {
+    let preview = app.diff_preview.as_mut().unwrap();
+    if let Some(origin) = preview.search_from {
+        match key.code {
+            KeyCode::Esc => {
+                preview.query.clear();
+                preview.current_match = None;
+                preview.search_from = None;
+                preview.scroll = origin;
+            }
+            KeyCode::Enter => preview.search_from = None,
+            KeyCode::Backspace => {
+                preview.query.pop();
+                preview.seek_match(origin);
+            }
+            KeyCode::Char(c) => {
+                preview.query.push(c);
+                preview.seek_match(origin);
+            }
+            _ => {}
+        }
+        return Ok(false);
+    }
+    match key.code {
+        KeyCode::Char('/') => {
+            preview.query.clear();
+            preview.current_match = None;
+            preview.search_from = Some(preview.scroll);
+        }
+        // In the restore prompt `n` keeps meaning "cancel".
+        KeyCode::Char('n') if !preview.query.is_empty() && preview.pending_restore.is_none() => {
+            preview.cycle_match(true)
+        }
+        KeyCode::Char('N') if !preview.query.is_empty() => preview.cycle_match(false),
+        KeyCode::Esc => {
+            app.diff_preview = None;
+        }
+        KeyCode::Char('q') => {
+            app.diff_preview = None;
+        }
+        KeyCode::Up => {
+            preview.scroll = preview.scroll.saturating_sub(1);
+        }
+        KeyCode::Down => {
+            preview.scroll = (preview.scroll + 1).min(preview.lines.len().saturating_sub(1));
+        }
+        KeyCode::PageUp => {
+            preview.scroll = preview.scroll.saturating_sub(10);
+        }
+        KeyCode::PageDown => {
+            preview.scroll = (preview.scroll + 10).min(preview.lines.len().saturating_sub(1));
+        }
+        KeyCode::Left => {
+            preview.hscroll = preview.hscroll.saturating_sub(DIFF_HSCROLL_STEP);
+        }
+        KeyCode::Right => {
+            preview.hscroll += DIFF_HSCROLL_STEP;
+        }
+        KeyCode::Char('w') => {
+            preview.status = Some(match app.snapshot_manager.export_patch(&preview.commit, preview.until.as_deref()) {
+                Ok(path) => format!("Wrote {}", path.display()),
+                Err(err) => format!("Export failed: {}", err),
+            });
+        }
+        KeyCode::Char('y') => {
+            if let Some(plan) = preview.pending_restore.take() {
+                app.snapshot_manager.apply_restore(&plan)?;
+                if let Some(keep) = app.config.backup_retention {
+                    for err in app.snapshot_manager.prune_backups(keep).1 {
+                        app.push_notice(err, Color::Yellow);
+                    }
+                }
+            }
+            app.diff_preview = None;
+        }
+        KeyCode::Char('t') => app.toggle_time_display(),
+        KeyCode::Char('o') => {
+            let editor = std::env::var("EDITOR").ok().filter(|e| !e.trim().is_empty());
+            match preview.file_at_scroll() {
+                None => preview.status = Some("No file at the top of the view".to_string()),
+                Some(_) if editor.is_none() => {
+                    preview.status = Some("$EDITOR is not set; set it to open files from the diff".to_string())
+                }
+                Some(file) => app.pending_edit = Some(app.snapshot_manager.workspace.join(file)),
+            }
+        }
+        KeyCode::Char('b') if preview.pending_restore.is_some() => {
+            preview.status = Some(match app.snapshot_manager.restore_to_worktree(&preview.commit) {
+                Ok(path) => {
+                    let mut status = format!("Checked out to {}", path.display());
+                    if let Some(keep) = app.config.backup_retention {
+                        for err in app.snapshot_manager.prune_worktrees(keep).1 {
+                            status.push_str("; ");
+                            status.push_str(&err);
+                        }
+                    }
+                    status
+                }
+                Err(err) => format!("Worktree checkout failed: {}", err),
+            });
+        }
+        KeyCode::Char('n') => {
+            app.diff_preview = None;
+        }
+        _ => {}
+    }
+    Ok(false)
+}
+
+/// y (or the quit key again) quits; n or Esc goes back.
+fn handle_confirm_quit_keys(key: KeyEvent, app: &mut App) -> bool {
+    match key.code {
+        KeyCode::Char('y') | KeyCode::Char('Y') => true,
+        KeyCode::Char(c) if c == app.config.quit_key && key.modifiers.contains(KeyModifiers::CONTROL) => true,
+        KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => {
+            app.confirm_quit = false;
+            false
+        }
+        _ => false,
+    }
+}
+
+fn open_diff_preview(app: &mut App, commit: &str, until: Option<&str>, pending_restore: bool) -> Result<()> {
+    let diff = app.snapshot_manager.diff_preview(commit, until)?;
+    let lines: Vec<String> = if diff.is_empty() {
+        vec!["(no changes)".to_string()]
+    } else {
+        diff.lines().map(|l| l.to_string()).collect()
+    };
+    let (mut title, committed_at) = match app.snapshot_manager.commit_info(commit) {
+        Ok((short, subject, time)) => (format!("{} · {}", short, subject), Some(time)),
+        Err(_) => (commit.to_string(), None),
+    };
+    if pending_restore {
+        if let Some(msg) = app.messages.iter().find(|m| m.snapshot_commit.as_deref() == Some(commit)) {
+            title = format!("Restore to message #{} — {}", msg.idx, title);
+        }
+    } else if until.is_some() {
+        title = format!("Turn diff {}", title);
+    } else {
+        title = format!("Diff {}", title);
+    }
+    app.diff_preview = Some(DiffPreview {
+        title,
+        committed_at,
+        commit: commit.to_string(),
+        until: until.map(str::to_string),
+        lines,
+        scroll: 0,
+        hscroll: 0,
+        query: String::new(),
+        search_from: None,
+        current_match: None,
+        pending_restore: if pending_restore {
+            Some(app.snapshot_manager.restore_plan(commit)?)
+        } else {
+            None
+        },
+        status: None,
+    });
+    Ok(())
+}
+
+/// Re-reads the open diff, e.g. after a file was edited, keeping the view
+/// where it was. If git fails the old diff stays up with the error.
+fn refresh_diff_preview(app: &mut App) {
+    let Some(old) = app.diff_preview.take() else {
+        return;
+    };
+    let commit = old.commit.clone();
+    let until = old.until.clone();
+    match open_diff_preview(app, &commit, until.as_deref(), old.pending_restore.is_some()) {
+        Ok(()) => {
+            if let Some(preview) = &mut app.diff_preview {
+                preview.scroll = old.scroll.min(preview.lines.len() - 1);
+                preview.hscroll = old.hscroll;
+                preview.query = old.query;
+            }
+        }
+        Err(err) => {
+            app.diff_preview = Some(DiffPreview {
+                status: Some(format!("Reloading the diff failed: {}", err)),
+                ..old
+            })
+        }
+    }
+}
+
+/// Splits the screen into the Claude pane and the workbench column. In
+/// fullscreen mode the Claude pane takes everything and there is no workbench.
+fn main_layout(size: Rect, app: &App) -> (Rect, Option<Rect>) {
+    if app.fullscreen_output {
+        return (size, None);
+    }
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(app.split_percent),
+            Constraint::Percentage(100 - app.split_percent),
+        ])
+        .split(size);
+    (chunks[0], Some(chunks[1]))
+}
+
+/// `padding` if the panel in `area` still has a row and a column left
+/// inside its border, otherwise none.
+fn fit_padding(padding: Padding, area: Rect) -> Padding {
+    let across = u32::from(padding.left) + u32::from(padding.right) + 2;
+    let down = u32::from(padding.top) + u32::from(padding.bottom) + 2;
+    if across < u32::from(area.width) && down < u32::from(area.height) {
+        padding
+    } else {
+        Padding::zero()
+    }
+}
+
+fn draw_ui(f: &mut Frame, app: &mut App) {
+    let size = f.size();
+    let (output_area, workbench_area) = main_layout(size, app);
+
+    draw_output_panel(f, app, output_area);
+    if let Some(area) = workbench_area {
+        draw_workbench(f, app, area);
+    }
+
+    let time_display = app.time_display;
+    if let Some(preview) = &mut app.diff_preview {
+        draw_diff_preview(f, preview, time_display, size);
+    }
+    if let Some(palette) = &app.palette {
+        draw_command_palette(f, palette, size);
+    }
+    if let Some(editor) = &app.settings {
+        draw_settings_editor(f, editor, &app.config_path, size);
+    }
+    if let Some(line) = &app.command_line {
+        draw_command_line(f, line, size);
+    }
+    if app.confirm_quit {
+        draw_confirm_quit(f, &app.quit_blockers(), app.config.require_quit_confirm, size);
+    }
+}
+
+fn draw_output_panel(f: &mut Frame, app: &mut App, area: Rect) {
+    let activity = if app.is_generating() {
+        SPINNER_FRAMES[app.spinner_frame]
+    } else {
+        '·'
+    };
+    let mut title = match &app.claude_version {
+        Some(version) => format!("Claude {} {} — {}", version, activity, app.config.title),
+        None => format!("Claude {} — {}", activity, app.config.title),
+    };
+    if matches!(app.focus, Focus::Output) {
+        title.push_str(" (focused)");
+    }
+    if app.inspect {
+        title.push_str(" (read-only)");
+    }
+    if app.assistant_only {
+        title.push_str(" (replies only)");
+    }
+    title.push(' ');
+    title.push_str(&scroll_indicator(app.output_scroll, app.output_lines.len(), app.follow_output));
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .padding(fit_padding(app.config.output_padding, area));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    let input_height = app.input_line_height().min(inner.height);
+    let text_area = Rect { height: inner.height - input_height, ..inner };
+    let input_area = Rect { y: inner.bottom() - input_height, height: input_height, ..inner };
+    let visible_height = text_area.height as usize;
+    let rows = output_rows(app, visible_height);
+    let start = rows.first().copied().unwrap_or(0);
+    let gutter = app.line_number_gutter();
+    let markdown = app.config.render_markdown;
+    let code_blocks = markdown || app.config.highlight_code_blocks;
+    let code_width = (text_area.width as usize).saturating_sub(gutter);
+    // An unterminated fence simply runs to the end of the turn (or buffer).
+    let mut in_code = code_blocks && app.code_block_open_at(start);
+    let mut lines: Vec<Line> = Vec::with_capacity(rows.len());
+    let mut prev: Option<usize> = None;
+    for idx in rows {
+        let l = &app.output_lines[idx];
+        // Hidden lines in between may have opened or closed a fence.
+        if prev.is_some_and(|prev| prev + 1 != idx) {
+            in_code = code_blocks && app.code_block_open_at(idx);
+        }
+        prev = Some(idx);
+        let mut spans = if let Some(&color) = app.notices.get(&idx) {
+            vec![Span::styled(l.clone(), Style::default().fg(color).add_modifier(Modifier::BOLD))]
+        } else if !code_blocks {
+            vec![Span::raw(l.clone())]
+        } else if app.messages.iter().any(|m| m.output_line == idx) {
+            // A prompt line starts a new turn; fences don't carry over.
+            in_code = false;
+            vec![Span::raw(l.clone())]
+        } else if app.turn_start(idx).is_some() {
+            match code_block_spans(l, &mut in_code, code_width) {
+                Some(spans) => spans,
+                None if markdown => markdown_spans(l),
+                None => vec![Span::raw(l.clone())],
+            }
+        } else {
+            vec![Span::raw(l.clone())]
+        };
+        if gutter > 0 {
+            let number = format!("{:>width$} ", idx + 1, width = gutter - 1);
+            spans.insert(0, Span::styled(number, Style::default().add_modifier(Modifier::DIM)));
+        }
+        lines.push(Line::from(spans));
+    }
+    let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false });
+    f.render_widget(paragraph, text_area);
+    if input_area.height > 0 {
+        let text = input_line_text(&app.input.display(), input_area.width as usize);
+        f.render_widget(
+            Paragraph::new(Line::from(vec![
+                Span::styled("> ", Style::default().add_modifier(Modifier::DIM)),
+                Span::raw(text),
+            ])),
+            input_area,
+        );
+    }
+}
+
+/// Output lines to draw in `height` rows, ending at `output_scroll`. With
+/// `assistant_only`, prompt echoes are skipped and earlier lines fill in.
+fn output_rows(app: &App, height: usize) -> Vec<usize> {
+    let end = (app.output_scroll + 1).min(app.output_lines.len());
+    if !app.assistant_only {
+        let start = app.output_scroll.saturating_sub(height.saturating_sub(1));
+        return (start..(start + height).min(app.output_lines.len())).collect();
+    }
+    let mut rows: Vec<usize> = (0..end).rev().filter(|&idx| !app.is_prompt_echo(idx)).take(height).collect();
+    rows.reverse();
+    rows
+}
+
+/// The end of `typed` that fits after the `> ` marker in `width` columns,
+/// so the cursor end stays visible.
+fn input_line_text(typed: &str, width: usize) -> String {
+    let room = width.saturating_sub(2);
+    let count = typed.chars().count();
+    typed.chars().skip(count.saturating_sub(room)).collect()
+}
+
+/// Styling for a line of a fenced code block: the fence itself dimmed, the
+/// lines inside indented on a dark background padded to `width` so the
+/// block reads as one. `in_code` carries fence state between lines; `None`
+/// means `line` is ordinary text.
+fn code_block_spans(line: &str, in_code: &mut bool, width: usize) -> Option<Vec<Span<'static>>> {
+    let code = Style::default().fg(Color::Cyan);
+    if line.trim_start().starts_with("```") {
+        *in_code = !*in_code;
+        return Some(vec![Span::styled(line.to_string(), code.add_modifier(Modifier::DIM))]);
+    }
+    if !*in_code {
+        return None;
+    }
+    let text = format!("  {}", line);
+    let pad = width.saturating_sub(text.chars().count());
+    Some(vec![Span::styled(
+        format!("{}{}", text, " ".repeat(pad)),
+        code.bg(Color::Indexed(236)),
+    )])
+}
+
+/// Light Markdown styling for one line of an assistant reply outside code
+/// blocks: `#` headings and `**bold**`. Anything else is left as is.
+fn markdown_spans(line: &str) -> Vec<Span<'static>> {
+    let trimmed = line.trim_start();
+    let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+    if (1..=6).contains(&hashes) && trimmed[hashes..].starts_with(' ') {
+        return vec![Span::styled(
+            trimmed[hashes..].trim().to_string(),
+            Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+        )];
+    }
+    let mut spans = Vec::new();
+    let mut rest = line;
+    while let Some(open) = rest.find("**") {
+        let Some(close) = rest[open + 2..].find("**") else {
+            break;
+        };
+        if open > 0 {
+            spans.push(Span::raw(rest[..open].to_string()));
+        }
+        spans.push(Span::styled(
+            rest[open + 2..open + 2 + close].to_string(),
+            Style::default().add_modifier(Modifier::BOLD),
+        ));
+        rest = &rest[open + 2 + close + 2..];
+    }
+    if !rest.is_empty() || spans.is_empty() {
+        spans.push(Span::raw(rest.to_string()));
+    }
+    spans
+}
+
+fn draw_workbench(f: &mut Frame, app: &mut App, area: Rect) {
+    let panels = app.config.panels.clone();
+    let sections = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(panel_constraints(&panels))
+        .split(area);
+
+    for (panel, section) in panels.iter().zip(sections.iter()) {
+        match panel {
+            Panel::Usage => draw_usage_panel(f, app, *section),
+            Panel::Context => draw_context_panel(f, app, *section),
+            Panel::History => draw_history_panel(f, app, *section),
+        }
+    }
+}
+
+/// History takes whatever height is left; without it the last panel does.
+fn panel_constraints(panels: &[Panel]) -> Vec<Constraint> {
+    let stretch = if panels.contains(&Panel::History) {
+        Panel::History
+    } else {
+        panels[panels.len() - 1]
+    };
+    panels
+        .iter()
+        .map(|panel| match panel {
+            Panel::History => Constraint::Min(5),
+            panel if *panel == stretch => Constraint::Min(7),
+            _ => Constraint::Length(7),
+        })
+        .collect()
+}
+
+/// ` (30s ago)` after a provider's numbers, or ` (failing, last ok 2m ago)`
+/// when its latest poll failed and the numbers are from an earlier one.
+fn usage_freshness(entry: &UsageEntry) -> Option<String> {
+    let age = chrono::Duration::from_std(entry.fetched_at?.elapsed()).unwrap_or_default();
+    Some(match entry.status {
+        Some(_) => format!(" (failing, last ok {})", format_age(age)),
+        None => format!(" ({})", format_age(age)),
+    })
+}
+
+fn freshness_span(entry: &UsageEntry, fresh: Style, failing: Style) -> Option<Span<'static>> {
+    let text = usage_freshness(entry)?;
+    let style = if entry.status.is_some() { failing } else { fresh };
+    Some(Span::styled(text, style))
+}
+
+fn draw_usage_panel(f: &mut Frame, app: &mut App, area: Rect) {
+    let entries = app.usage_entries();
+    let split = |entry: &UsageEntry| match (entry.input_tokens, entry.output_tokens) {
+        (Some(input), Some(output)) => Some(Line::from(Span::styled(
+            format!("  in: {} / out: {}", input, output),
+            Style::default().add_modifier(Modifier::DIM),
+        ))),
+        _ => None,
+    };
+    let threshold = app.config.effective_compress_threshold() as f64;
+    let unavailable = Style::default().fg(Color::DarkGray);
+    let failing = Style::default().fg(Color::Red);
+    let title = if app.usage_manager.is_offline() {
+        "Usage (offline)"
+    } else if app.usage_manager.is_paused() {
+        "Usage (paused)"
+    } else if app.usage_manager.is_refreshing() {
+        "Usage (refreshing…)"
+    } else {
+        "Usage"
+    };
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .padding(fit_padding(app.config.workbench_padding, area));
+    // Lines aren't wrapped, so a long label is cut off at the edge and the
+    // bar below it always gets exactly the inner width.
+    let width = block.inner(area).width as usize;
+    let view = &app.usage_view;
+    let ordered = view.order(&entries);
+    let mut lines: Vec<Line> = Vec::new();
+    if let Some(&primary) = ordered.first() {
+        if let (Some(used), Some(limit)) = (primary.used, primary.limit) {
+            let pct = usage_ratio(used, limit);
+            let style = Style::default().fg(usage_color(pct, threshold));
+            let bar = gradient_bar(width, pct, threshold);
+            let mut spans = vec![Span::styled(view.amount(&primary.name, used, limit), style)];
+            spans.extend(freshness_span(primary, unavailable, failing));
+            lines.push(Line::from(spans));
+            lines.push(Line::from(bar));
+            lines.extend(split(primary));
+        } else {
+            lines.push(Line::from(Span::styled(
+                format!(
+                    "{}: {}",
+                    primary.name,
+                    primary.status.clone().unwrap_or_else(|| "unavailable".to_string())
+                ),
+                unavailable,
+            )));
+        }
+    }
+    for &entry in ordered.iter().skip(1) {
+        let line = match (entry.used, entry.limit) {
+            (Some(used), Some(limit)) => {
+                let mut spans = vec![Span::styled(
+                    view.amount(&entry.name, used, limit),
+                    Style::default().fg(usage_color(usage_ratio(used, limit), threshold)),
+                )];
+                spans.extend(freshness_span(entry, unavailable, failing));
+                Line::from(spans)
+            }
+            _ => Line::from(Span::styled(
+                format!(
+                    "{}: {}",
+                    entry.name,
+                    entry.status.clone().unwrap_or_else(|| "unavailable".to_string())
+                ),
+                unavailable,
+            )),
+        };
+        lines.push(line);
+        lines.extend(split(entry));
+    }
+    if lines.is_empty() {
+        lines.push(Line::from(Span::raw("No providers configured")));
+    }
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Green below the warning band, yellow within 15 points of the compression
+/// threshold, red at or past it (the same threshold the Context panel uses).
+fn usage_color(ratio: f64, threshold: f64) -> Color {
+    if ratio >= threshold {
+        Color::Red
+    } else if ratio >= threshold - 0.15 {
+        Color::Yellow
+    } else {
+        Color::Green
+    }
+}
+
+/// A `width`-cell bar filled to `ratio`, each filled cell colored by the
+/// `usage_color` band of its own position, so the fill turns yellow and then
+/// red as it nears `threshold`. The empty part is dim.
+fn gradient_bar(width: usize, ratio: f64, threshold: f64) -> Vec<Span<'static>> {
+    if width == 0 {
+        return Vec::new();
+    }
+    let filled = ((width as f64) * ratio).round().clamp(0.0, width as f64) as usize;
+    let mut spans: Vec<Span> = Vec::new();
+    let mut run = String::new();
+    let mut run_color = None;
+    for i in 0..filled {
+        let color = usage_color(i as f64 / width as f64, threshold);
+        if run_color != Some(color) {
+            if let Some(prev) = run_color {
+                spans.push(Span::styled(std::mem::take(&mut run), Style::default().fg(prev)));
+            }
+            run_color = Some(color);
+        }
+        run.push('█');
+    }
+    if let Some(color) = run_color {
+        spans.push(Span::styled(run, Style::default().fg(color)));
+    }
+    if filled < width {
+        spans.push(Span::styled("░".repeat(width - filled), Style::default().fg(Color::DarkGray)));
+    }
+    spans
+}
+
+fn draw_context_panel(f: &mut Frame, app: &mut App, area: Rect) {
+    let used = app.estimate_context_tokens() as f32;
+    let limit = app.context_limit(&app.usage_entries()) as f32;
+    let pct = if limit == 0.0 { 0.0 } else { used / limit };
+    let threshold = app.config.effective_compress_threshold();
+    let remaining_pct = (threshold - pct).max(0.0);
+    let mut block = Block::default()
+        .title("Context")
+        .borders(Borders::ALL)
+        .padding(fit_padding(app.config.workbench_padding, area));
+    let bar = gradient_bar(block.inner(area).width as usize, pct as f64, threshold as f64);
+    let color = if pct >= threshold { Color::Red } else { Color::Green };
+    let lines = vec![
+        Line::from(vec![
+            Span::raw("Context: "),
+            Span::styled(format!("{:.1}%", pct * 100.0), Style::default().fg(color)),
+        ]),
+        Line::from(bar),
+        Line::from(Span::raw(format!("Distance to compression: {:.1}%", remaining_pct * 100.0))),
+        Line::from(Span::raw(format!(
+            "Remaining: {} tokens",
+            format_thousands((limit - used).max(0.0) as u64)
+        ))),
+        Line::from(Span::raw(format!(
+            "Until compress: {} tokens",
+            format_thousands((threshold * limit - used).max(0.0) as u64)
+        ))),
+    ];
+    if app.snapshots_enabled {
+        if let Some((count, bytes)) = app.snapshot_manager.cached_repo_stats() {
+            let note = format!("snapshots: {} ({})", count, format_size(bytes));
+            block = block.title(
+                Title::from(Span::styled(note, Style::default().add_modifier(Modifier::DIM)))
+                    .position(Position::Bottom),
+            );
+        }
+    }
+    let paragraph = Paragraph::new(lines).block(block);
+    f.render_widget(paragraph, area);
+}
+
+fn draw_history_panel(f: &mut Frame, app: &mut App, area: Rect) {
+    let title = if !matches!(app.focus, Focus::History) {
+        "History"
+    } else if app.snapshots_enabled {
+        "History (Tab to focus, d diff, r restore)"
+    } else {
+        "History (Tab to focus)"
+    };
+    let marker_item = |marker: &CompactionMarker| {
+        ListItem::new(Line::from(Span::styled(
+            format!("⟳ compacted (~{} tokens reclaimed)", marker.reclaimed),
+            Style::default().fg(Color::Cyan),
+        )))
+    };
+    let mut items: Vec<ListItem> = Vec::with_capacity(app.messages.len() + app.compactions.len());
+    let mut markers = app.compactions.iter().peekable();
+    let mut selected = None;
+    for (i, m) in app.messages.iter().enumerate() {
+        while let Some(marker) = markers.next_if(|marker| marker.after <= i) {
+            items.push(marker_item(marker));
+        }
+        if i == app.selected_message.min(app.messages.len() - 1) {
+            selected = Some(items.len());
+        }
+        let preview = truncate_chars(&m.content.replace('\n', " "), 40);
+        let suffix = match (&m.snapshot_commit, m.changed_files, &m.snapshot_error) {
+            _ if !app.snapshots_enabled => String::new(),
+            _ if m.snapshot_skipped => "-".to_string(),
+            (Some(_), Some(count), _) => match m.diff_stat {
+                Some((added, removed)) if count > 0 => format!("✓ {}f +{} -{}", count, added, removed),
+                _ => format!("✓ {}f", count),
+            },
+            (Some(_), None, _) => "✓".to_string(),
+            (None, _, Some(_)) => "✗".to_string(),
+            (None, _, None) => "…".to_string(),
+        };
+        items.push(ListItem::new(Line::from(vec![
+            Span::raw(format!("{} {} ", preview, suffix)),
+            Span::styled(
+                app.time_display.format(m.created_at),
+                Style::default().add_modifier(Modifier::DIM),
+            ),
+        ])));
+    }
+    items.extend(markers.map(marker_item));
+    let mut block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .padding(fit_padding(app.config.workbench_padding, area));
+    if !app.snapshots_enabled {
+        block = block.title(
+            Title::from(Span::styled(
+                format!("snapshots off: {}", app.snapshots_off),
+                Style::default().fg(Color::Yellow),
+            ))
+            .position(Position::Bottom),
+        );
+    } else if let Some(err) = app
+        .messages
+        .get(app.selected_message)
+        .and_then(|m| m.snapshot_error.as_ref())
+    {
+        let note = format!("✗ {} (s to retry)", git_error_summary(err));
+        block = block.title(
+            Title::from(Span::styled(note, Style::default().fg(Color::Red))).position(Position::Bottom),
+        );
+    }
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD))
+        .highlight_symbol("➜ ");
+    let mut state = ListState::default();
+    state.select(selected);
+    f.render_stateful_widget(list, area, &mut state);
+}
+
+use ratatui::widgets::ListState;
+
+/// Columns Left/Right move the diff preview by.
+const DIFF_HSCROLL_STEP: usize = 8;
+
+impl DiffPreview {
+    /// The `height` lines from `scroll`, shifted left by `hscroll`. Both
+    /// offsets are clamped first, since only drawing knows the popup size.
+    fn window(&mut self, height: usize, width: usize) -> Vec<String> {
+        self.scroll = self.scroll.min(self.lines.len().saturating_sub(height));
+        let end = (self.scroll + height).min(self.lines.len());
+        let visible = &self.lines[self.scroll..end];
+        let widest = visible.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+        self.hscroll = self.hscroll.min(widest.saturating_sub(width));
+        visible.iter().map(|l| l.chars().skip(self.hscroll).collect()).collect()
+    }
+
+    /// Indices of the lines containing `query`.
+    fn match_lines(&self) -> Vec<usize> {
+        if self.query.is_empty() {
+            return Vec::new();
+        }
+        let query = self.query.as_str();
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.contains(query))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Moves to the first match at or after line `from`, wrapping around;
+    /// with no match the view goes back to `from`.
+    fn seek_match(&mut self, from: usize) {
+        let matches = self.match_lines();
+        self.current_match = matches.iter().copied().find(|&l| l >= from).or(matches.first().copied());
+        self.scroll = self.current_match.map_or(from, Self::scroll_for);
+    }
+
+    /// Moves to the next (or previous) match, wrapping around.
+    fn cycle_match(&mut self, forward: bool) {
+        let matches = self.match_lines();
+        let (Some(&first), Some(&last)) = (matches.first(), matches.last()) else {
+            return;
+        };
+        let current = self.current_match;
+        let next = if forward {
+            matches.iter().copied().find(|&l| current.is_none_or(|c| l > c)).unwrap_or(first)
+        } else {
+            matches.iter().rev().copied().find(|&l| current.is_none_or(|c| l < c)).unwrap_or(last)
+        };
+        self.current_match = Some(next);
+        self.scroll = Self::scroll_for(next);
+    }
+
+    /// Path of the file whose section the top visible line belongs to, from
+    /// the `diff --git a/… b/…` header above it.
+    fn file_at_scroll(&self) -> Option<&str> {
+        let top = self.scroll.min(self.lines.len().saturating_sub(1));
+        self.lines
+            .iter()
+            .take(top + 1)
+            .rev()
+            .find_map(|l| l.strip_prefix("diff --git "))
+            .and_then(|header| header.rsplit_once(" b/"))
+            .map(|(_, path)| path)
+    }
+
+    /// Scroll that shows `line` with a little context above it.
+    fn scroll_for(line: usize) -> usize {
+        line.saturating_sub(2)
+    }
+
+    /// Footer text for the search: the prompt while typing, else where the
+    /// current match sits among all of them.
+    fn search_status(&self) -> Option<String> {
+        if self.search_from.is_some() {
+            return Some(format!("/{}", self.query));
+        }
+        if self.query.is_empty() {
+            return None;
+        }
+        let matches = self.match_lines();
+        Some(match self.current_match.and_then(|c| matches.iter().position(|&l| l == c)) {
+            Some(pos) => format!("\"{}\": match {}/{} (n/N to cycle, / to search again)", self.query, pos + 1, matches.len()),
+            None => format!("\"{}\": no matches", self.query),
+        })
+    }
+}
+
+/// `line` with every occurrence of `query` highlighted; brighter when it is
+/// the current match.
+fn highlight_matches(line: String, query: &str, current: bool) -> Line<'static> {
+    if query.is_empty() || !line.contains(query) {
+        return Line::raw(line);
+    }
+    let color = if current { Color::LightRed } else { Color::Yellow };
+    let hit = Style::default().bg(color).fg(Color::Black);
+    let mut spans = Vec::new();
+    let mut rest = line.as_str();
+    while let Some(pos) = rest.find(query) {
+        if pos > 0 {
+            spans.push(Span::raw(rest[..pos].to_string()));
+        }
+        spans.push(Span::styled(query.to_string(), hit));
+        rest = &rest[pos + query.len()..];
+    }
+    if !rest.is_empty() {
+        spans.push(Span::raw(rest.to_string()));
+    }
+    Line::from(spans)
+}
+
+/// Diff lines are drawn unwrapped, with Left/Right scrolling, so columns
+/// line up and `scroll` counts rows.
+fn draw_diff_preview(f: &mut Frame, preview: &mut DiffPreview, time_display: TimeMode, area: Rect) {
+    let popup = centered_rect(90, 80, area);
+    let title = match preview.committed_at {
+        Some(ts) => format!("{} · {}", preview.title, time_display.format(ts)),
+        None => preview.title.clone(),
+    };
+    let block = Block::default().title(title).borders(Borders::ALL);
+    let mut footer = Vec::new();
+    if let Some(status) = &preview.status {
+        footer.push(Line::from(Span::styled(status.clone(), Style::default().fg(Color::Cyan))));
+    }
+    if let Some(search) = preview.search_status() {
+        footer.push(Line::from(Span::styled(search, Style::default().fg(Color::Yellow))));
+    }
+    if let Some(plan) = &preview.pending_restore {
+        footer.extend(restore_plan_lines(plan, 5));
+        footer.push(Line::from(Span::styled(
+            "Press y to restore in place, b to check out to a worktree, n to cancel",
+            Style::default().fg(Color::Yellow),
+        )));
+    } else {
+        footer.push(Line::from(Span::raw(
+            "Press / to search, o to edit the file at the top, w to export patch, q or Esc to close",
+        )));
+    }
+    let inner = block.inner(popup);
+    f.render_widget(block, popup);
+    let footer_height = (footer.len() as u16).min(inner.height);
+    let diff_area = Rect { height: inner.height - footer_height, ..inner };
+    let footer_area = Rect { y: diff_area.bottom(), height: footer_height, ..inner };
+
+    let window = preview.window(diff_area.height as usize, diff_area.width as usize);
+    let lines: Vec<Line> = window
+        .into_iter()
+        .enumerate()
+        .map(|(i, l)| {
+            let current = preview.current_match == Some(preview.scroll + i);
+            highlight_matches(l, &preview.query, current)
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines), diff_area);
+    f.render_widget(Paragraph::new(footer).wrap(Wrap { trim: false }), footer_area);
+}
+
+fn draw_settings_editor(f: &mut Frame, editor: &SettingsEditor, path: &Path, area: Rect) {
+    let popup = centered_rect(60, 40, area);
+    let block = Block::default()
+        .title("Settings (←/→ or type to change, Enter to save, Esc to cancel)")
+        .borders(Borders::ALL);
+    let mut lines = Vec::new();
+    for (i, (setting, value)) in Setting::ALL.iter().zip(&editor.values).enumerate() {
+        let style = if i == editor.selected {
+            Style::default().bg(Color::Blue)
+        } else {
+            Style::default()
+        };
+        let note = if setting.live() { "" } else { " (restart required)" };
+        lines.push(Line::from(vec![
+            Span::styled(format!("{:<20} {:<12}", setting.key(), value), style),
+            Span::styled(note, Style::default().fg(Color::DarkGray)),
+        ]));
+    }
+    lines.push(Line::raw(""));
+    lines.push(Line::from(Span::styled(
+        format!("Saves to {}", path.display()),
+        Style::default().fg(Color::DarkGray),
+    )));
+    if let Some(err) = &editor.error {
+        lines.push(Line::from(Span::styled(err.clone(), Style::default().fg(Color::Red))));
+    }
+    f.render_widget(Clear, popup);
+    f.render_widget(Paragraph::new(lines).block(block).wrap(Wrap { trim: false }), popup);
+}
+
+fn draw_command_palette(f: &mut Frame, palette: &CommandPalette, area: Rect) {
+    let popup = centered_rect(60, 50, area);
+    let block = Block::default()
+        .title("Commands (Enter to run, Esc to close)")
+        .borders(Borders::ALL);
+    let mut lines = vec![Line::from(vec![
+        Span::styled("> ", Style::default().fg(Color::Cyan)),
+        Span::raw(palette.query.clone()),
+    ])];
+    let matches = palette.matches();
+    if matches.is_empty() {
+        lines.push(Line::from(Span::styled("(no matching commands)", Style::default().fg(Color::DarkGray))));
+    }
+    let height = (popup.height.saturating_sub(3) as usize).max(1);
+    let start = palette.selected.saturating_sub(height - 1);
+    for (i, action) in matches.iter().enumerate().skip(start).take(height) {
+        let style = if i == palette.selected {
+            Style::default().bg(Color::Blue)
+        } else {
+            Style::default()
+        };
+        lines.push(Line::from(vec![
+            Span::styled(format!("{:<44}", action.label()), style),
+            Span::styled(format!(" {}", action.binding(palette.quit_key)), Style::default().fg(Color::DarkGray)),
+        ]));
+    }
+    f.render_widget(Clear, popup);
+    f.render_widget(Paragraph::new(lines).block(block), popup);
+}
+
+/// The `:` command line, across the bottom of the screen and set apart from
+/// Claude's own prompt box so it is clear where the input goes.
+fn draw_command_line(f: &mut Frame, line: &InputLine, area: Rect) {
+    let height = area.height.min(3);
+    let popup = Rect { y: area.bottom() - height, height, ..area };
+    let block = Block::default()
+        .title("Command, not sent to Claude (Enter to run, Esc to cancel, :help)")
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(Color::Yellow));
+    let text = input_line_text(&line.display(), popup.width.saturating_sub(2) as usize);
+    let prompt = Line::from(vec![Span::styled(": ", Style::default().fg(Color::Yellow)), Span::raw(text)]);
+    f.render_widget(Clear, popup);
+    f.render_widget(Paragraph::new(prompt).block(block), popup);
+}
+
+/// With `always_asks` and nothing in flight this is a plain `Quit? (y/n)`.
+fn draw_confirm_quit(f: &mut Frame, blockers: &[String], always_asks: bool, area: Rect) {
+    let popup = centered_rect(50, 30, area);
+    let mut lines: Vec<Line> = blockers.iter().map(|b| Line::raw(format!("• {}", b))).collect();
+    let question = if !lines.is_empty() {
+        "Quit anyway? (y/n)"
+    } else if always_asks {
+        "Quit? (y/n)"
+    } else {
+        lines.push(Line::raw("Nothing in flight any more."));
+        "Quit anyway? (y/n)"
+    };
+    if !lines.is_empty() {
+        lines.push(Line::raw(""));
+    }
+    lines.push(Line::from(Span::styled(
+        question,
+        Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+    )));
+    let block = Block::default().title("Quit").borders(Borders::ALL);
+    f.render_widget(Clear, popup);
+    f.render_widget(Paragraph::new(lines).block(block), popup);
+}
+
+/// Summarises a restore plan: a counts line, up to `max_entries` paths and a
+/// note about the rest.
+fn restore_plan_lines(plan: &RestorePlan, max_entries: usize) -> Vec<Line<'static>> {
+    let mut lines = vec![Line::from(Span::styled(
+        format!(
+            "Restore: overwrite {}, delete {}, recreate {}; backup to {}",
+            plan.count(RestoreAction::Overwrite),
+            plan.count(RestoreAction::Delete),
+            plan.count(RestoreAction::Recreate),
+            plan.backup_dir.display()
+        ),
+        Style::default().fg(Color::Yellow),
+    ))];
+    for (action, path) in plan.entries.iter().take(max_entries) {
+        let label = match action {
+            RestoreAction::Overwrite => "overwrite",
+            RestoreAction::Delete => "delete",
+            RestoreAction::Recreate => "recreate",
+        };
+        lines.push(Line::raw(format!("  {:<9} {}", label, path)));
+    }
+    if plan.entries.len() > max_entries {
+        lines.push(Line::raw(format!("  … {} more", plan.entries.len() - max_entries)));
+    }
+    lines
+}
+
+fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
+    let popup_layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(r);
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(popup_layout[1])[1]
+}
+
+/// Appends terminal output to `lines`. `cursor` is the char column in the
+/// last line where the next text lands; a lone `\r` moves it back to 0 so
+/// progress bars overwrite themselves like they would in a terminal. With
+/// `collapse_blank`, a run of 3+ blank lines shrinks to one once text follows.
+fn append_output_lines(
+    lines: &mut Vec<String>,
+    cursor: &mut usize,
+    chunk: &str,
+    collapse_blank: bool,
+    max_lines: usize,
+    max_line_chars: usize,
+) -> Vec<Range<usize>> {
+    let mut drained = Vec::new();
+    if lines.is_empty() {
+        lines.push(String::new());
+        *cursor = 0;
+    }
+    let mut rest = chunk;
+    while !rest.is_empty() {
+        let split = rest.find(['\r', '\n']).unwrap_or(rest.len());
+        let (text, tail) = rest.split_at(split);
+        if !text.is_empty() {
+            if collapse_blank && !text.trim().is_empty() {
+                drained.push(collapse_blank_run(lines));
+            }
+            if let Some(line) = lines.last_mut() {
+                *cursor = write_at_cursor(line, *cursor, text);
+            }
+            wrap_long_line(lines, cursor, max_line_chars);
+        }
+        match tail.chars().next() {
+            Some('\r') => *cursor = 0,
+            Some('\n') => {
+                lines.push(String::new());
+                *cursor = 0;
+            }
+            _ => {}
+        }
+        rest = tail.get(1..).unwrap_or("");
+    }
+    drained.push(trim_scrollback(lines, max_lines));
+    drained.retain(|range| !range.is_empty());
+    drained
+}
+
+/// Splits the last line into lines of at most `max_chars` characters, so a
+/// huge newline-free blob (minified JSON, say) keeps rendering and scroll
+/// math bounded. The cursor follows the text onto the continuation line;
+/// while it is still within the first `max_chars` (after a `\r`, say) the
+/// line is left whole, since the next write lands in its head.
+fn wrap_long_line(lines: &mut Vec<String>, cursor: &mut usize, max_chars: usize) {
+    while let Some(last) = lines.last_mut() {
+        if *cursor < max_chars {
+            return;
+        }
+        let Some((split, _)) = last.char_indices().nth(max_chars) else {
+            return;
+        };
+        let tail = last.split_off(split);
+        lines.push(tail);
+        *cursor -= max_chars;
+    }
+}
+
+/// Drops the oldest lines beyond `max_lines`; returns the range removed.
+fn trim_scrollback(lines: &mut Vec<String>, max_lines: usize) -> Range<usize> {
+    let excess = lines.len().saturating_sub(max_lines);
+    lines.drain(0..excess);
+    0..excess
+}
+
+/// Drops all but one of the blank lines right before the last line, when
+/// there are at least three. Whitespace-only lines count as blank. Returns
+/// the range removed.
+fn collapse_blank_run(lines: &mut Vec<String>) -> Range<usize> {
+    let Some(last) = lines.len().checked_sub(1) else {
+        return 0..0;
+    };
+    if !lines[last].trim().is_empty() {
+        return 0..0;
+    }
+    let run = lines[..last].iter().rev().take_while(|l| l.trim().is_empty()).count();
+    if run < 3 {
+        return 0..0;
+    }
+    let removed = last - run + 1..last;
+    lines.drain(removed.clone());
+    removed
+}
+
+/// Writes `text` over `line` starting at char column `cursor`, extending the
+/// line when it runs past the end. Returns the new cursor column.
+fn write_at_cursor(line: &mut String, cursor: usize, text: &str) -> usize {
+    let text_len = text.chars().count();
+    let mut offsets = line.char_indices().map(|(idx, _)| idx).skip(cursor);
+    match offsets.next() {
+        None => line.push_str(text),
+        Some(start) => {
+            let end = offsets.nth(text_len.saturating_sub(1)).unwrap_or(line.len());
+            line.replace_range(start..end, text);
+        }
+    }
+    cursor + text_len
+}
+
+/// Removes terminal escape sequences: CSI (`ESC [ ... final`), string
+/// sequences such as OSC titles/hyperlinks (`ESC ] ... BEL` or `ESC ] ... ESC \`),
+/// charset selection (`ESC ( B`) and single-character escapes (`ESC =`).
+fn strip_ansi(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for ch in chars.by_ref() {
+                    if ('@'..='~').contains(&ch) {
+                        break;
+                    }
+                }
+            }
+            // OSC, DCS, SOS, PM and APC all run until BEL or ST.
+            Some(']' | 'P' | 'X' | '^' | '_') => {
+                while let Some(ch) = chars.next() {
+                    if ch == '\u{7}' {
+                        break;
+                    }
+                    if ch == '\u{1b}' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            Some('(' | ')' | '*' | '+' | '#' | '%') => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+/// `[following]` while new output moves the view, otherwise
+/// `[frozen line/total pct%]` for the bottom visible line.
+fn scroll_indicator(scroll: usize, total: usize, follow: bool) -> String {
+    if follow {
+        return "[following]".to_string();
+    }
+    let total = total.max(1);
+    let line = (scroll + 1).min(total);
+    format!("[frozen {}/{} {}%]", line, total, line * 100 / total)
+}
+
+/// New value for `output_scroll` (the index of the bottom visible line) after
+/// moving `delta` lines, clamped so the top of the view never overshoots line 0.
+fn scroll_target(current: usize, delta: isize, height: usize, total: usize) -> usize {
+    let bottom = total.saturating_sub(1);
+    let top = height.saturating_sub(1).min(bottom);
+    let target = if delta < 0 {
+        current.saturating_sub(delta.unsigned_abs())
+    } else {
+        current.saturating_add(delta as usize)
+    };
+    target.clamp(top, bottom)
+}
+
+/// Compact age such as `45s ago`, `5m ago`, `3h ago` or `2d ago`.
+fn format_age(age: chrono::Duration) -> String {
+    let secs = age.num_seconds().max(0);
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// `1234567` -> `1,234,567`.
+fn format_thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Total size of the files under `path`; unreadable entries count as 0.
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// `2.3 MB`-style size with one decimal above a kilobyte.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Shortens `input` to at most `max_chars` characters, appending `…` when
+/// anything was cut. Counts chars rather than bytes so multibyte text is safe.
+fn truncate_chars(input: &str, max_chars: usize) -> String {
+    match input.char_indices().nth(max_chars) {
+        Some((byte_idx, _)) => {
+            let mut out = input[..byte_idx].to_string();
+            out.push('…');
+            out
+        }
+        None => input.to_string(),
+    }
+}
+
+/// A small regular expression subset for config-supplied line patterns:
+/// literals, `.`, `[...]` classes with ranges and `^` negation, `\d \w \s`,
+/// `* + ?`, `^`/`$` anchors and a leading `(?i)` for case-insensitive matching.
+/// Matching is unanchored unless `^` or `$` is given, and takes time linear
+/// in the text, since patterns run on every output line.
+#[derive(Clone, Debug)]
+struct Pattern {
+    nodes: Vec<PatternNode>,
+    anchored_start: bool,
+    anchored_end: bool,
+    ignore_case: bool,
+}
+
+/// One atom, matched exactly once, at most once or any number of times;
+/// `x+` is stored as `x` followed by `x*`.
+#[derive(Clone, Debug)]
+struct PatternNode {
+    atom: PatternAtom,
+    optional: bool,
+    repeat: bool,
+}
+
+#[derive(Clone, Debug)]
+enum PatternAtom {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+}
+
+impl PatternAtom {
+    fn matches(&self, c: char, ignore_case: bool) -> bool {
+        let fold = |c: char| if ignore_case { c.to_lowercase().next().unwrap_or(c) } else { c };
+        match self {
+            PatternAtom::Char(want) => fold(*want) == fold(c),
+            PatternAtom::Any => true,
+            PatternAtom::Class { ranges, negated } => {
+                let hit = ranges.iter().any(|&(lo, hi)| {
+                    (lo..=hi).contains(&c)
+                        || (ignore_case
+                            && ((lo..=hi).contains(&fold(c))
+                                || c.to_uppercase().next().is_some_and(|u| (lo..=hi).contains(&u))))
+                });
+                hit != *negated
+            }
+        }
+    }
+}
+
+fn escape_class(c: char) -> Option<Vec<(char, char)>> {
+    match c {
+        'd' => Some(vec![('0', '9')]),
+        'w' => Some(vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]),
+        's' => Some(vec![(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r')]),
+        _ => None,
+    }
+}
+
+impl Pattern {
+    fn new(source: &str) -> Result<Self> {
+        let (ignore_case, rest) = match source.strip_prefix("(?i)") {
+            Some(rest) => (true, rest),
+            None => (false, source),
+        };
+        let mut chars = rest.chars().peekable();
+        let anchored_start = chars.next_if_eq(&'^').is_some();
+        let mut anchored_end = false;
+        let mut nodes: Vec<PatternNode> = Vec::new();
+        while let Some(c) = chars.next() {
+            let atom = match c {
+                '$' if chars.peek().is_none() => {
+                    anchored_end = true;
+                    break;
+                }
+                '.' => PatternAtom::Any,
+                '\\' => {
+                    let esc = chars.next().ok_or_else(|| anyhow!("pattern ends with a backslash"))?;
+                    match escape_class(esc) {
+                        Some(ranges) => PatternAtom::Class { ranges, negated: false },
+                        None => PatternAtom::Char(esc),
+                    }
+                }
+                '[' => {
+                    let negated = chars.next_if_eq(&'^').is_some();
+                    let mut ranges = Vec::new();
+                    let mut first = true;
+                    loop {
+                        let c = chars.next().ok_or_else(|| anyhow!("unclosed `[` in pattern"))?;
+                        let lo = match c {
+                            ']' if !first => break,
+                            '\\' => {
+                                let esc = chars.next().ok_or_else(|| anyhow!("unclosed `[` in pattern"))?;
+                                if let Some(class) = escape_class(esc) {
+                                    ranges.extend(class);
+                                    first = false;
+                                    continue;
+                                }
+                                esc
+                            }
+                            c => c,
+                        };
+                        first = false;
+                        if chars.peek() == Some(&'-') {
+                            chars.next();
+                            match chars.next() {
+                                Some(']') => {
+                                    ranges.push((lo, lo));
+                                    ranges.push(('-', '-'));
+                                    break;
+                                }
+                                Some(hi) if hi >= lo => ranges.push((lo, hi)),
+                                Some(hi) => return Err(anyhow!("invalid range `{}-{}` in pattern", lo, hi)),
+                                None => return Err(anyhow!("unclosed `[` in pattern")),
+                            }
+                        } else {
+                            ranges.push((lo, lo));
+                        }
+                    }
+                    PatternAtom::Class { ranges, negated }
+                }
+                '*' | '+' | '?' => return Err(anyhow!("`{}` has nothing to repeat", c)),
+                '(' | ')' | '|' | '{' | '}' => {
+                    return Err(anyhow!("`{}` is not supported in patterns (escape it with `\\`)", c))
+                }
+                c => PatternAtom::Char(c),
+            };
+            let (optional, repeat) = match chars.next_if(|c| matches!(c, '*' | '+' | '?')) {
+                Some('*') => (true, true),
+                Some('+') => {
+                    nodes.push(PatternNode { atom: atom.clone(), optional: false, repeat: false });
+                    (true, true)
+                }
+                Some('?') => (true, false),
+                _ => (false, false),
+            };
+            nodes.push(PatternNode { atom, optional, repeat });
+        }
+        Ok(Self {
+            nodes,
+            anchored_start,
+            anchored_end,
+            ignore_case,
+        })
+    }
+
+    /// Runs all match attempts at once over the set of pattern positions
+    /// still alive (a Thompson simulation), one pass over `text`.
+    fn is_match(&self, text: &str) -> bool {
+        let done = self.nodes.len();
+        let mut alive = vec![false; done + 1];
+        let mut next = vec![false; done + 1];
+        self.enter(&mut alive, 0);
+        for c in text.chars() {
+            if alive[done] && !self.anchored_end {
+                return true;
+            }
+            next.fill(false);
+            for (at, node) in self.nodes.iter().enumerate() {
+                if alive[at] && node.atom.matches(c, self.ignore_case) {
+                    self.enter(&mut next, if node.repeat { at } else { at + 1 });
+                }
+            }
+            if !self.anchored_start {
+                self.enter(&mut next, 0);
+            }
+            std::mem::swap(&mut alive, &mut next);
+        }
+        alive[done]
+    }
+
+    /// Marks position `at` alive, and the ones after it that optional nodes
+    /// let a match skip to.
+    fn enter(&self, alive: &mut [bool], mut at: usize) {
+        while !alive[at] {
+            alive[at] = true;
+            match self.nodes.get(at) {
+                Some(node) if node.optional => at += 1,
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Rough token count from the character count; `chars_per_token` is about
+/// 4 for English prose and lower for code or CJK text.
+fn estimate_tokens(text: &str, chars_per_token: f32) -> u32 {
+    let chars = text.chars().count() as f32;
+    (chars / chars_per_token).ceil() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use tempfile::TempDir;
+
+    /// Answers each git subcommand from a script and records every call, so
+    /// `SnapshotManager` logic can be tested without git.
+    #[derive(Default)]
+    struct ScriptedGit {
+        replies: HashMap<&'static str, Result<&'static str, &'static str>>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl GitRunner for ScriptedGit {
+        fn run(&self, _git_dir: &Path, _work_tree: Option<&Path>, args: &[&str]) -> Result<String> {
+            self.calls.lock().unwrap().push(args.join(" "));
+            // Skip `-c key=value` overrides to find the subcommand.
+            let sub = args.iter().find(|a| !a.starts_with('-') && !a.contains('=')).copied().unwrap_or("");
+            match self.replies.get(sub) {
+                Some(Ok(out)) => Ok(out.to_string()),
+                Some(Err(err)) => Err(anyhow!("git failed: {}", err)),
+                None => Ok(String::new()),
+            }
+        }
+    }
+
+    fn scripted_manager(replies: &[(&'static str, Result<&'static str, &'static str>)]) -> (SnapshotManager, Arc<ScriptedGit>) {
+        let git = Arc::new(ScriptedGit {
+            replies: replies.iter().cloned().collect(),
+            ..Default::default()
+        });
+        let mut manager = SnapshotManager::detached(Path::new("/ws"), Path::new("/ws/.cc-workbench"));
+        manager.git = git.clone();
+        (manager, git)
+    }
+
+    /// The built-in defaults, without reading any config file or `CCWB_*`.
+    fn test_config() -> Config {
+        Config::from_file(Path::new("/nonexistent"), None).expect("defaults parse")
+    }
+
+    /// The defaults under the settings in `json`.
+    fn test_config_json(json: &str) -> Result<Config> {
+        Config::from_file(Path::new("/nonexistent"), Some(serde_json::from_str(json)?))
+    }
+
+    /// An app on `config` whose snapshot repo doesn't exist; snapshot jobs
+    /// go nowhere.
+    fn test_app_with(config: Config) -> App {
+        let (tx, _rx) = mpsc::channel();
+        let manager = SnapshotManager::detached(Path::new("/nonexistent"), Path::new("/nonexistent/.cc-workbench"));
+        App::new(config, "s".to_string(), manager, tx)
+    }
+
+    fn test_app() -> App {
+        test_app_with(test_config())
+    }
+
+    /// Prompt #`idx` ("hi") starting at output line `output_line`.
+    fn message(idx: i64, output_line: usize) -> MessageEntry {
+        MessageEntry {
+            id: format!("m{}", idx),
+            idx,
+            content: "hi".to_string(),
+            output_line,
+            output_end: None,
+            assistant_text: String::new(),
+            snapshot_commit: None,
+            snapshot_commit_after: None,
+            changed_files: None,
+            diff_stat: None,
+            snapshot_error: None,
+            snapshot_skipped: false,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens("", 4.0), 0);
+        assert_eq!(estimate_tokens("abcd", 4.0), 1);
+        assert_eq!(estimate_tokens("abcdefgh", 4.0), 2);
+        assert_eq!(estimate_tokens("abcdefgh", 2.0), 4);
+        assert_eq!(estimate_tokens("你好世界", 1.5), 3);
+    }
+
+    #[test]
+    fn output_decoder_modes() {
+        // "é" split across reads, a stray 0xff, then "ok".
+        let reads: [&[u8]; 3] = [b"caf\xc3", b"\xa9 \xff", b"ok"];
+        let run = |encoding| {
+            let mut decoder = OutputDecoder::new(encoding);
+            let mut out: String = reads.iter().map(|r| decoder.decode(r)).collect();
+            out.push_str(&decoder.finish());
+            out
+        };
+        assert_eq!(run(OutputEncoding::Utf8Lossy), "café �ok");
+        assert_eq!(run(OutputEncoding::Utf8StrictDrop), "café ok");
+        assert_eq!(run(OutputEncoding::Latin1), "cafÃ© ÿok");
+
+        let mut decoder = OutputDecoder::new(OutputEncoding::Utf8Lossy);
+        assert_eq!(decoder.decode(b"x\xe2\x82"), "x");
+        assert_eq!(decoder.finish(), "\u{FFFD}");
+        let mut decoder = OutputDecoder::new(OutputEncoding::Utf8StrictDrop);
+        assert_eq!(decoder.decode(b"x\xe2\x82"), "x");
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(strip_ansi("\x1b[1;31mred\x1b[0m"), "red");
+        assert_eq!(strip_ansi("\x1b]0;my title\x07hello"), "hello");
+        assert_eq!(strip_ansi("\x1b]2;title\x1b\\hello"), "hello");
+        assert_eq!(
+            strip_ansi("see \x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\ here"),
+            "see link here"
+        );
+        assert_eq!(strip_ansi("\x1b=\x1b>\x1b(Bplain"), "plain");
+        assert_eq!(strip_ansi("unterminated \x1b]0;title"), "unterminated ");
+    }
+
+    #[test]
+    fn carriage_return_overwrites_line() {
+        let mut lines = vec![String::new()];
+        let mut cursor = 0;
+        append_output_lines(&mut lines, &mut cursor, "loading 10%\rloading 90%\n", false, 5000, usize::MAX);
+        assert_eq!(lines, vec!["loading 90%".to_string(), String::new()]);
+
+        append_output_lines(&mut lines, &mut cursor, "abcdef\r", false, 5000, usize::MAX);
+        append_output_lines(&mut lines, &mut cursor, "xy", false, 5000, usize::MAX);
+        assert_eq!(lines.last().unwrap(), "xycdef");
+
+        append_output_lines(&mut lines, &mut cursor, "\r\n进度 50%\r进度 99%\r\n", false, 5000, usize::MAX);
+        assert_eq!(lines[1], "xycdef");
+        assert_eq!(lines[2], "进度 99%");
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn long_lines_are_hard_wrapped() {
+        let mut lines = vec![String::new()];
+        let mut cursor = 0;
+        let blob = "é".repeat(50_000);
+        append_output_lines(&mut lines, &mut cursor, &blob, false, 5000, 10_000);
+        assert_eq!(lines.len(), 5);
+        assert!(lines.iter().all(|l| l.chars().count() == 10_000));
+        // The rest of the logical line continues on the last piece.
+        append_output_lines(&mut lines, &mut cursor, "x\ny", false, 5000, 10_000);
+        assert_eq!(lines.len(), 7);
+        assert_eq!((lines[5].as_str(), lines[6].as_str()), ("x", "y"));
+        assert_eq!(lines.concat().chars().count(), 50_002);
+
+        // A cursor back in the head keeps the line whole, so it still writes there.
+        let mut lines = vec!["abcdefghijklmno".to_string()];
+        let mut cursor = 3;
+        wrap_long_line(&mut lines, &mut cursor, 10);
+        assert_eq!((lines.len(), cursor), (1, 3));
+        cursor = 12;
+        wrap_long_line(&mut lines, &mut cursor, 10);
+        assert_eq!(lines, ["abcdefghij", "klmno"]);
+        assert_eq!(cursor, 2);
+    }
+
+    #[test]
+    fn blank_line_runs_collapse() {
+        let mut lines = vec![String::new()];
+        let mut cursor = 0;
+        append_output_lines(&mut lines, &mut cursor, "a\n\n \n\n\nb\n\nc\n\n\n\n", true, 5000, usize::MAX);
+        assert_eq!(lines, vec!["a", "", "b", "", "c", "", "", "", ""]);
+        append_output_lines(&mut lines, &mut cursor, "d", true, 5000, usize::MAX);
+        assert_eq!(lines, vec!["a", "", "b", "", "c", "", "d"]);
+
+        let mut lines = vec![String::new()];
+        append_output_lines(&mut lines, &mut cursor, "a\n\n\n\nb", false, 5000, usize::MAX);
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn ages_are_compact() {
+        let d = chrono::Duration::seconds;
+        assert_eq!(format_age(d(-3)), "0s ago");
+        assert_eq!(format_age(d(59)), "59s ago");
+        assert_eq!(format_age(d(300)), "5m ago");
+        assert_eq!(format_age(d(3 * 3600 + 59)), "3h ago");
+        assert_eq!(format_age(d(2 * 86400)), "2d ago");
+        let ts = Utc::now() - d(90);
+        assert_eq!(TimeMode::Relative.format(ts), "1m ago");
+        assert_eq!(TimeMode::Absolute.format(ts), ts.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string());
+    }
+
+    #[test]
+    fn thousands_separators() {
+        assert_eq!(format_thousands(0), "0");
+        assert_eq!(format_thousands(999), "999");
+        assert_eq!(format_thousands(1000), "1,000");
+        assert_eq!(format_thousands(170_000), "170,000");
+        assert_eq!(format_thousands(1_234_567), "1,234,567");
+    }
+
+    #[test]
+    fn test_truncate_chars() {
+        assert_eq!(truncate_chars("hello", 40), "hello");
+        assert_eq!(truncate_chars("hello world", 5), "hello…");
+        assert_eq!(truncate_chars("你好世界你好世界", 3), "你好世…");
+        assert_eq!(truncate_chars("🎉🎉🎉", 3), "🎉🎉🎉");
+        assert_eq!(truncate_chars("a🎉b🎉c", 2), "a🎉…");
+        assert_eq!(truncate_chars("", 0), "");
+    }
+
+    #[test]
+    fn pattern_subset_matches() -> Result<()> {
+        let p = Pattern::new("(?i)^\\s*compacted")?;
+        assert!(p.is_match("  Compacted conversation"));
+        assert!(!p.is_match("not compacted"));
+        let p = Pattern::new("summar(y|ies)").err();
+        assert!(p.is_some(), "groups are rejected, not silently mismatched");
+        let p = Pattern::new("[a-c]+\\d?x$")?;
+        assert!(p.is_match("zz abc7x"));
+        assert!(p.is_match("bx"));
+        assert!(!p.is_match("abc7xy"));
+        assert!(!p.is_match("d9x"));
+        let p = Pattern::new("a.*b[^0-9-]")?;
+        assert!(p.is_match("a--b-b!"));
+        assert!(!p.is_match("ab1 b-"));
+        assert!(Pattern::new("*a").is_err());
+        assert!(Pattern::new("[ab").is_err());
+        // Backtracking would take exponential time on these.
+        let line = "a".repeat(10_000);
+        assert!(!Pattern::new(".*a.*a.*b")?.is_match(&line));
+        assert!(!Pattern::new("(?i)^A*a*A*a*a*$")?.is_match(&format!("{}!", line)));
+        assert!(Pattern::new("a+a+a+$")?.is_match(&line));
+        assert!(!Pattern::new("^a+a+$")?.is_match("a"));
+        assert!(Pattern::new("^$")?.is_match(""));
+        Ok(())
+    }
+
+    #[test]
+    fn chars_per_token_scales_context_estimate() -> Result<()> {
+        let mut config = test_config();
+        config.chars_per_token = 2.5;
+        config.providers = vec![ProviderConfig::Local {
+            name: None,
+            limit_tokens: None,
+            compress_threshold: None,
+        }];
+        let mut app = test_app_with(config);
+        app.messages.push(MessageEntry {
+            content: "x".repeat(100),
+            assistant_text: "y".repeat(50),
+            ..message(1, 0)
+        });
+        assert_eq!(app.estimate_context_tokens(), 40 + 20);
+        assert_eq!((app.estimate_input_tokens(), app.estimate_output_tokens()), (40, 20));
+        let local = &app.usage_entries()[0];
+        assert_eq!((local.used, local.input_tokens, local.output_tokens), (Some(60), Some(40), Some(20)));
+        Ok(())
+    }
+
+    #[test]
+    fn output_drain_yields_under_a_flood() -> Result<()> {
+        let mut app = test_app();
+        let (output_tx, output_rx) = mpsc::channel();
+        for i in 0..10 {
+            output_tx.send(OutputChunk { text: format!("line {}\n", i), stream: OutputStream::Stdout })?;
+        }
+        // 7 bytes per chunk: the budget stops it after three.
+        assert!(drain_output(&mut app, &output_rx, 20));
+        assert!(app.output_lines.iter().any(|l| l == "line 2"));
+        assert!(!app.output_lines.iter().any(|l| l == "line 3"));
+        // The other seven take three more frames, the last one emptying the queue.
+        assert!(drain_output(&mut app, &output_rx, 20));
+        assert!(drain_output(&mut app, &output_rx, 20));
+        assert!(!drain_output(&mut app, &output_rx, 20));
+        let expected: Vec<String> = (0..10).map(|i| format!("line {}", i)).collect();
+        assert_eq!(app.output_lines[..10], expected[..]);
+        assert_eq!(app.output_lines[10..], [""]);
+        Ok(())
+    }
+
+    #[test]
+    fn diff_window_scrolls_by_rows_and_columns() {
+        let mut preview = DiffPreview {
+            title: String::new(),
+            committed_at: None,
+            commit: "abc".to_string(),
+            until: None,
+            lines: (0..10).map(|i| format!("+line {} {}", i, "x".repeat(i))).collect(),
+            scroll: 7,
+            hscroll: 0,
+            query: String::new(),
+            search_from: None,
+            current_match: None,
+            pending_restore: None,
+            status: None,
+        };
+        assert_eq!(preview.window(3, 80), vec!["+line 7 xxxxxxx", "+line 8 xxxxxxxx", "+line 9 xxxxxxxxx"]);
+        // Past the end clamps so the last page stays full.
+        preview.scroll = 9;
+        assert_eq!(preview.window(3, 80).len(), 3);
+        assert_eq!(preview.scroll, 7);
+        preview.hscroll = 100;
+        assert_eq!(preview.window(3, 10), vec![" xxxxxxx", " xxxxxxxx", " xxxxxxxxx"]);
+        assert_eq!(preview.hscroll, 7);
+    }
+
+    #[test]
+    fn diff_search_cycles_matches() {
+        let mut preview = DiffPreview {
+            title: String::new(),
+            committed_at: None,
+            commit: "abc".to_string(),
+            until: None,
+            lines: (0..30).map(|i| if i % 10 == 5 { format!("+fn parse_{}", i) } else { format!(" ctx {}", i) }).collect(),
+            scroll: 8,
+            hscroll: 0,
+            query: "parse".to_string(),
+            search_from: None,
+            current_match: None,
+            pending_restore: None,
+            status: None,
+        };
+        // Find-as-you-type starts from where `/` was pressed.
+        preview.seek_match(8);
+        assert_eq!((preview.current_match, preview.scroll), (Some(15), 13));
+        preview.cycle_match(true);
+        assert_eq!(preview.current_match, Some(25));
+        preview.cycle_match(true);
+        assert_eq!(preview.current_match, Some(5));
+        preview.cycle_match(false);
+        assert_eq!(preview.current_match, Some(25));
+        assert_eq!(preview.search_status().unwrap(), "\"parse\": match 3/3 (n/N to cycle, / to search again)");
+
+        preview.query = "nowhere".to_string();
+        preview.seek_match(8);
+        assert_eq!((preview.current_match, preview.scroll), (None, 8));
+        assert_eq!(preview.search_status().unwrap(), "\"nowhere\": no matches");
+
+        let line = highlight_matches("a parse b parse".to_string(), "parse", true);
+        let text: Vec<&str> = line.spans.iter().map(|s| s.content.as_ref()).collect();
+        assert_eq!(text, ["a ", "parse", " b ", "parse"]);
+        assert_eq!(line.spans[1].style.bg, Some(Color::LightRed));
+    }
+
+    #[test]
+    fn command_lines_parse_into_actions() {
+        assert_eq!(parse_command("restore 3"), Ok(Command::Run(Action::RestoreSelected, Some(3))));
+        assert_eq!(parse_command("diff #2"), Ok(Command::Run(Action::DiffSelected, Some(2))));
+        assert_eq!(parse_command("snapshot"), Ok(Command::Run(Action::TakeSnapshot, None)));
+        assert_eq!(parse_command("retry 1"), Ok(Command::Run(Action::RetrySnapshot, Some(1))));
+        assert_eq!(parse_command("  compact "), Ok(Command::Run(Action::Compact, None)));
+        assert_eq!(parse_command("export out.json"), Ok(Command::Export("out.json".to_string())));
+        assert_eq!(parse_command("help"), Ok(Command::Help));
+        assert!(parse_command("compact now").unwrap_err().contains("no arguments"));
+        assert!(parse_command("restore x").unwrap_err().contains("not a message number"));
+        assert!(parse_command("export").unwrap_err().contains("usage"));
+        assert!(parse_command("resume").unwrap_err().contains("new session"));
+        assert!(parse_command("bogus").unwrap_err().contains("unknown command"));
+        // Every action is reachable under its own name.
+        for action in Action::ALL {
+            assert_eq!(parse_command(action.command()), Ok(Command::Run(*action, None)));
+        }
+    }
+
+    #[test]
+    fn diff_file_at_scroll_uses_the_header_above() {
+        let mut preview = DiffPreview {
+            title: String::new(),
+            committed_at: None,
+            commit: "abc".to_string(),
+            until: None,
+            lines: ["diff --git a/src/main.rs b/src/main.rs", "+one", "diff --git a/old name b/new name", "-two"]
+                .iter()
+                .map(|l| l.to_string())
+                .collect(),
+            scroll: 1,
+            hscroll: 0,
+            query: String::new(),
+            search_from: None,
+            current_match: None,
+            pending_restore: None,
+            status: None,
+        };
+        assert_eq!(preview.file_at_scroll(), Some("src/main.rs"));
+        preview.scroll = 3;
+        assert_eq!(preview.file_at_scroll(), Some("new name"));
+        preview.lines = vec!["(no changes)".to_string()];
+        assert_eq!(preview.file_at_scroll(), None);
+    }
+
+    #[test]
+    fn copy_commit_falls_back_to_a_notice() -> Result<()> {
+        let mut app = test_app();
+        app.messages.push(message(1, 0));
+        app.copy_selected_commit(|_| panic!("nothing to copy"));
+        app.messages[0].snapshot_commit = Some("abc123".to_string());
+        let mut copied = String::new();
+        app.copy_selected_commit(|commit| {
+            copied = commit.to_string();
+            true
+        });
+        app.copy_selected_commit(|_| false);
+        assert_eq!(copied, "abc123");
+        let notes: Vec<&str> = app.output_lines.iter().map(String::as_str).filter(|l| !l.is_empty()).collect();
+        assert_eq!(
+            notes,
+            [
+                "No snapshot for message #1 yet",
+                "Copied snapshot abc123 to the clipboard",
+                "Snapshot for message #1: abc123"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn settings_editor_saves_and_applies_live_values() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut app = test_app();
+        app.config_path = tmp.path().join("config.json");
+        fs::write(&app.config_path, r#"{"title": "kept", "scrollback_lines": 5000}"#)?;
+        app.output_lines = (0..300).map(|i| i.to_string()).collect();
+
+        let press = |app: &mut App, code| handle_settings_keys(KeyEvent::new(code, KeyModifiers::NONE), app);
+        app.settings = Some(SettingsEditor::new(&app.config));
+        for _ in 0..3 {
+            press(&mut app, KeyCode::Down)?;
+        }
+        for _ in 0..4 {
+            press(&mut app, KeyCode::Backspace)?;
+        }
+        press(&mut app, KeyCode::Char('5'))?;
+        press(&mut app, KeyCode::Enter)?;
+        assert!(app.settings.as_ref().and_then(|e| e.error.as_deref()).unwrap().contains("at least 100"));
+
+        press(&mut app, KeyCode::Char('0'))?;
+        press(&mut app, KeyCode::Char('0'))?;
+        press(&mut app, KeyCode::Up)?;
+        press(&mut app, KeyCode::Right)?;
+        press(&mut app, KeyCode::Enter)?;
+        assert!(app.settings.is_none());
+        assert_eq!(app.config.scrollback_lines, 500);
+        assert_eq!(app.config.usage_poll_seconds, 30);
+        assert!(app.output_lines.iter().any(|l| l.ends_with("restart required for usage_poll_seconds")));
+
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(&app.config_path)?)?;
+        assert_eq!(saved["title"], "kept");
+        assert_eq!(saved["scrollback_lines"], 500);
+        assert_eq!(saved["usage_poll_seconds"], 35);
+        Ok(())
+    }
+
+    #[test]
+    fn history_edges_select_first_and_last() -> Result<()> {
+        let mut app = test_app();
+        app.select_history_edge(true);
+        assert_eq!(app.selected_message, 0);
+        for idx in 1..=3 {
+            app.messages.push(message(idx, 0));
+        }
+        app.select_history_edge(true);
+        assert_eq!(app.selected_message, 2);
+        app.select_history_edge(false);
+        assert_eq!(app.selected_message, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn session_log_mirrors_cleaned_output() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut app = test_app();
+        let dir = tmp.path().join("logs");
+        // A log that can't be opened is reported and skipped.
+        fs::write(tmp.path().join("not-a-dir"), "")?;
+        app.start_session_log(&tmp.path().join("not-a-dir"));
+        assert!(app.session_log.is_none());
+        assert!(app.output_lines.iter().any(|l| l.starts_with("Session log in ")));
+        app.session_id = "s1".to_string();
+        app.start_session_log(&dir);
+        app.handle_output(OutputChunk { text: "\x1b[1mbuilding\x1b[0m\n".to_string(), stream: OutputStream::Stdout });
+        app.handle_output(OutputChunk { text: "done\n".to_string(), stream: OutputStream::Stdout });
+        drop(app.session_log.take());
+        assert_eq!(fs::read_to_string(dir.join("s1.log"))?, "building\ndone\n");
+        Ok(())
+    }
+
+    #[test]
+    fn stderr_lines_become_notices() -> Result<()> {
+        let mut app = test_app();
+        let stderr = |text: &str| OutputChunk { text: text.to_string(), stream: OutputStream::Stderr };
+        app.handle_output(OutputChunk { text: "work".to_string(), stream: OutputStream::Stdout });
+        app.handle_output(stderr("oops\npart"));
+        app.handle_output(stderr("ial\r\n"));
+        assert_eq!(app.output_lines, ["[err] oops", "[err] partial", "work"]);
+        assert_eq!(app.notices.get(&1), Some(&Color::LightRed));
+        assert_eq!(app.output_cursor, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn clear_output_keeps_history() -> Result<()> {
+        let mut app = test_app();
+        app.handle_output(OutputChunk { text: "a\nb\nc\n".to_string(), stream: OutputStream::Stdout });
+        app.messages.push(MessageEntry {
+            assistant_text: "b\nc\n".to_string(),
+            ..message(1, 2)
+        });
+        app.follow_output = false;
+        app.clear_output();
+        assert_eq!(app.output_lines, vec!["── cleared ──".to_string(), String::new()]);
+        assert!(app.follow_output);
+        assert_eq!(app.messages.len(), 1);
+        assert_eq!(app.messages[0].output_line, 0);
+        app.handle_output(OutputChunk { text: "d".to_string(), stream: OutputStream::Stdout });
+        assert_eq!(app.output_lines[1], "d");
+        Ok(())
+    }
+
+    #[test]
+    fn quit_asks_while_snapshots_are_pending() -> Result<()> {
+        let mut app = test_app();
+        assert!(app.quit_blockers().is_empty());
+        app.in_flight_snapshots = 1;
+        assert_eq!(app.quit_blockers(), vec!["1 snapshot still being committed".to_string()]);
+
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        app.confirm_quit = true;
+        assert!(!handle_confirm_quit_keys(key(KeyCode::Char('x')), &mut app));
+        assert!(app.confirm_quit);
+        assert!(!handle_confirm_quit_keys(key(KeyCode::Esc), &mut app));
+        assert!(!app.confirm_quit);
+        app.confirm_quit = true;
+        assert!(handle_confirm_quit_keys(key(KeyCode::Char('y')), &mut app));
+        Ok(())
+    }
+
+    #[test]
+    fn after_turn_snapshots_hold_up_quit_until_drained() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut db = Database::new(&tmp.path().join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        let mut app = test_app_with(test_config_json(r#"{"snapshot_after_turn": true}"#)?);
+        app.session_id = db.create_session(&ws)?;
+        let (tx, jobs) = mpsc::channel();
+        app.snapshot_job_tx = tx;
+        app.snapshots_enabled = true;
+        app.record_user_message(&mut db, "edit main.txt".to_string(), 0)?;
+        let prompt_job = jobs.try_recv()?;
+        let result = |job: SnapshotJob| SnapshotResult {
+            message_id: job.message_id,
+            after_turn: job.after_turn,
+            commit: Some("abc".to_string()),
+            ..Default::default()
+        };
+        app.update_snapshot(&mut db, result(prompt_job))?;
+        assert!(app.quit_blockers().is_empty());
+
+        // The prompt's snapshot is in; the one from the turn's end isn't.
+        app.finalize_last_turn(&mut db)?;
+        assert_eq!(app.quit_blockers(), vec!["1 snapshot still being committed".to_string()]);
+        let (results_tx, results) = mpsc::channel();
+        results_tx.send(result(jobs.try_recv()?))?;
+        app.drain_snapshots(&mut db, &results, Duration::from_secs(1))?;
+        assert_eq!(app.in_flight_snapshots, 0);
+        assert_eq!(app.messages[0].snapshot_commit_after.as_deref(), Some("abc"));
+        Ok(())
+    }
+
+    #[test]
+    fn quit_confirmation_and_key_are_configurable() -> Result<()> {
+        let mut app = test_app_with(test_config_json(r#"{"require_quit_confirm": true, "quit_key": "Ctrl+X"}"#)?);
+        assert!(app.quit_blockers().is_empty());
+        assert!(!app.request_quit());
+        assert!(app.confirm_quit);
+        let ctrl = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL);
+        assert!(!handle_confirm_quit_keys(ctrl('q'), &mut app));
+        assert!(handle_confirm_quit_keys(ctrl('x'), &mut app));
+
+        assert_eq!(parse_quit_key(" ctrl+W "), Ok('w'));
+        assert!(parse_quit_key("ctrl+c").unwrap_err().contains("taken"));
+        assert_eq!(parse_quit_key("ctrl+y"), Ok('y'));
+        assert!(parse_quit_key("alt+q").is_err());
+        assert!(parse_quit_key("ctrl+qq").is_err());
+        assert!(test_config_json(r#"{"quit_key": "F10"}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_marker_tags_history() -> Result<()> {
+        let mut config = test_config();
+        config.compaction_marker = Some(Pattern::new("^Compacted")?);
+        let mut app = test_app_with(config);
+        app.messages.push(MessageEntry {
+            content: "x".repeat(400),
+            ..message(1, 0)
+        });
+        app.handle_output(OutputChunk { text: "Compacted".to_string(), stream: OutputStream::Stdout });
+        assert_eq!(app.messages.len(), 1, "the line is not complete yet");
+        app.handle_output(OutputChunk { text: " conversation\r\nok\r\n".to_string(), stream: OutputStream::Stdout });
+        assert_eq!(app.messages.len(), 1);
+        let marker = &app.compactions[0];
+        assert_eq!(marker.reclaimed, 100 + estimate_tokens("Compacted conversation\r\nok\r\n", 4.0));
+        assert_eq!((marker.after, marker.output_line), (1, 0));
+        assert_eq!(app.estimate_context_tokens(), 0);
+        // Replies keep going to the prompt, and count against the new baseline.
+        app.handle_output(OutputChunk { text: "1234".to_string(), stream: OutputStream::Stdout });
+        assert!(app.messages[0].assistant_text.ends_with("1234"));
+        assert!(app.estimate_context_tokens() > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_marker_is_found_once_scrollback_is_full() -> Result<()> {
+        let mut config = test_config();
+        config.compaction_marker = Some(Pattern::new("^Compacted")?);
+        config.scrollback_lines = 10;
+        let mut app = test_app_with(config);
+        app.messages.push(message(1, 0));
+        let stdout = |text: String| OutputChunk { text, stream: OutputStream::Stdout };
+        app.handle_output(stdout("line\n".repeat(25)));
+        assert_eq!(app.output_lines.len(), 10);
+        assert!(app.compactions.is_empty());
+        app.handle_output(stdout("more\nCompacted conversation\nok\n".to_string()));
+        assert_eq!(app.compactions.len(), 1);
+        assert_eq!(app.output_lines[app.compactions[0].output_line], "Compacted conversation");
+        // It scrolls away with the lines around it.
+        app.handle_output(stdout("x\n".repeat(5)));
+        assert_eq!(app.output_lines[app.compactions[0].output_line], "Compacted conversation");
+        assert_eq!(app.compactions.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn line_references_follow_trimmed_scrollback() {
+        let mut config = test_config();
+        config.scrollback_lines = 10;
+        config.collapse_blank_lines = true;
+        let mut app = test_app_with(config);
+        let stdout = |text: &str| OutputChunk { text: text.to_string(), stream: OutputStream::Stdout };
+        app.handle_output(stdout("a\nb\n> hi\n"));
+        app.messages.push(MessageEntry { output_end: Some(4), ..message(1, 2) });
+        app.handle_output(stdout("reply\n"));
+        app.push_notice("note".to_string(), Color::Red);
+        app.follow_output = false;
+        app.output_scroll = 3;
+        // The blank run is collapsed and the oldest lines trimmed.
+        app.handle_output(stdout("\n\n\n\nafter\n"));
+        app.handle_output(stdout(&"x\n".repeat(4)));
+        let at = |idx: usize| app.output_lines[idx].as_str();
+        assert_eq!(at(app.messages[0].output_line), "> hi");
+        assert_eq!(at(app.messages[0].output_end.unwrap() - 1), "note");
+        assert_eq!(app.notices.keys().map(|&idx| at(idx)).collect::<Vec<_>>(), ["note"]);
+        assert_eq!(at(app.output_scroll), "reply");
+        // Notices trimmed away are dropped rather than landing on other lines.
+        app.handle_output(stdout(&"y\n".repeat(10)));
+        assert!(app.notices.is_empty());
+        assert_eq!(app.messages[0].output_line, 0);
+    }
+
+    #[test]
+    fn assistant_only_hides_prompt_echoes() -> Result<()> {
+        let mut app = test_app();
+        let turn = |idx: i64, output_line: usize, output_end: Option<usize>| MessageEntry {
+            content: String::new(),
+            output_end,
+            ..message(idx, output_line)
+        };
+        app.output_lines = ["banner", "> hi", "reply 1", "reply 2", "> s", "> second", "reply 3", ""]
+            .map(String::from)
+            .to_vec();
+        app.messages = vec![turn(1, 1, Some(4)), turn(2, 5, None)];
+        app.output_scroll = 7;
+        assert_eq!(output_rows(&app, 3), [5, 6, 7]);
+
+        app.toggle_assistant_only();
+        assert_eq!(output_rows(&app, 10), [0, 2, 3, 6, 7]);
+        assert_eq!(output_rows(&app, 3), [3, 6, 7]);
+        // Notices show, and a finished reply's end moves down with them.
+        app.messages.pop();
+        app.output_lines.truncate(5);
+        app.push_notice("note".to_string(), Color::Yellow);
+        assert_eq!(app.messages[0].output_end, Some(5));
+        app.output_scroll = 5;
+        assert_eq!(output_rows(&app, 10), [0, 2, 3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_and_restore() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        fs::create_dir_all(&workspace)?;
+        let data_dir = workspace.join(".cc-workbench");
+        fs::create_dir_all(&data_dir)?;
+        let manager = SnapshotManager::new(&workspace, &data_dir)?;
+
+        let file = workspace.join("main.txt");
+        fs::write(&file, "hello")?;
+        let commit1 = manager.snapshot(1)?;
+
+        fs::write(&file, "hello world")?;
+        let commit2 = manager.snapshot(2)?;
+
+        let (short, subject, time) = manager.commit_info(&commit1)?;
+        assert!(commit1.starts_with(&short) && short.len() < commit1.len());
+        assert_eq!(subject, "snapshot 1");
+        assert!(manager.list_commits()?.contains(&commit1));
+        assert!((Utc::now() - time).num_seconds().abs() < 60);
+
+        let diff = manager.diff_preview(&commit1, None)?;
+        assert!(diff.contains("hello world"));
+        assert_eq!(parse_name_status(&manager.commit_name_status(&commit1)?).len(), 1);
+        assert_eq!(parse_name_status(&manager.commit_name_status(&commit2)?).len(), 1);
+        assert_eq!(manager.diff_shortstat(EMPTY_TREE, &commit1)?, (1, 0));
+        assert_eq!(manager.diff_shortstat(&commit1, &commit2)?, (1, 1));
+        assert_eq!(manager.commit_stats(&commit1), (Some(1), Some((1, 0))));
+        assert_eq!(manager.commit_stats(&commit2), (Some(1), Some((1, 1))));
+
+        manager.apply_restore(&manager.restore_plan(&commit1)?)?;
+        let contents = fs::read_to_string(&file)?;
+        assert_eq!(contents, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn queued_snapshot_jobs_are_coalesced() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        let data_dir = workspace.join(".cc-workbench");
+        fs::create_dir_all(&data_dir)?;
+        let manager = SnapshotManager::new(&workspace, &data_dir)?;
+        fs::write(workspace.join("main.txt"), "hello")?;
+
+        let (job_tx, job_rx) = mpsc::channel();
+        let (res_tx, res_rx) = mpsc::channel();
+        for idx in 1..=3 {
+            job_tx.send(SnapshotJob {
+                message_id: format!("m{}", idx),
+                message_idx: idx,
+                after_turn: false,
+            })?;
+        }
+        spawn_snapshot_worker(manager.clone(), job_rx, res_tx);
+        let results: Vec<SnapshotResult> = (0..3)
+            .map(|_| res_rx.recv_timeout(Duration::from_secs(10)))
+            .collect::<Result<_, _>>()?;
+        let commit = results[0].commit.clone().expect("snapshot commit");
+        assert!(results.iter().all(|r| r.commit.as_deref() == Some(commit.as_str())));
+        assert_eq!(
+            results.iter().map(|r| r.message_id.as_str()).collect::<Vec<_>>(),
+            vec!["m1", "m2", "m3"]
+        );
+        let log = run_git_bare(&manager.git_dir, &["log", "--format=%s"], None)?;
+        assert_eq!(log.trim(), "snapshot 3");
+        Ok(())
+    }
+
+    #[test]
+    fn restore_plan_lists_actions_without_touching_files() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        let data_dir = workspace.join(".cc-workbench");
+        fs::create_dir_all(&data_dir)?;
+        let manager = SnapshotManager::new(&workspace, &data_dir)?;
+
+        fs::write(workspace.join("keep.txt"), "v1")?;
+        fs::write(workspace.join("gone.txt"), "old")?;
+        let commit = manager.snapshot(1)?;
+        fs::write(workspace.join("keep.txt"), "v2")?;
+        fs::remove_file(workspace.join("gone.txt"))?;
+        fs::write(workspace.join("new.txt"), "new")?;
+        manager.snapshot(2)?;
+
+        let plan = manager.restore_plan(&commit)?;
+        let mut entries = plan.entries.clone();
+        entries.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            entries,
+            vec![
+                (RestoreAction::Recreate, "gone.txt".to_string()),
+                (RestoreAction::Overwrite, "keep.txt".to_string()),
+                (RestoreAction::Delete, "new.txt".to_string()),
+            ]
+        );
+        assert!(!plan.backup_dir.exists());
+        assert_eq!(fs::read_to_string(workspace.join("keep.txt"))?, "v2");
+
+        manager.apply_restore(&plan)?;
+        assert_eq!(fs::read_to_string(workspace.join("keep.txt"))?, "v1");
+        assert!(workspace.join("gone.txt").exists());
+        assert!(!workspace.join("new.txt").exists());
+        assert!(plan.backup_dir.join("new.txt").exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_preserves_symlinks() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        let data_dir = workspace.join(".cc-workbench");
+        fs::create_dir_all(&data_dir)?;
+        let manager = SnapshotManager::new(&workspace, &data_dir)?;
+
+        fs::write(workspace.join("a.txt"), "a")?;
+        fs::write(workspace.join("b.txt"), "b")?;
+        let link = workspace.join("current");
+        std::os::unix::fs::symlink("a.txt", &link)?;
+        let commit = manager.snapshot(1)?;
+
+        fs::remove_file(&link)?;
+        std::os::unix::fs::symlink("b.txt", &link)?;
+        manager.snapshot(2)?;
+
+        let plan = manager.restore_plan(&commit)?;
+        manager.apply_restore(&plan)?;
+        assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert_eq!(fs::read_link(&link)?, PathBuf::from("a.txt"));
+
+        let backup = plan.backup_dir.join("current");
+        assert!(fs::symlink_metadata(&backup)?.file_type().is_symlink());
+        assert_eq!(fs::read_link(&backup)?, PathBuf::from("b.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn restore_to_worktree_leaves_workspace_alone() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        let data_dir = workspace.join(".cc-workbench");
+        fs::create_dir_all(&data_dir)?;
+        let manager = SnapshotManager::new(&workspace, &data_dir)?;
+
+        fs::write(workspace.join("main.txt"), "v1")?;
+        let commit = manager.snapshot(1)?;
+        fs::write(workspace.join("main.txt"), "v2")?;
+        manager.snapshot(2)?;
+
+        assert_eq!(manager.resolve_commit(&commit[..8])?, commit);
+        assert!(manager.resolve_commit("0000000").is_err());
+        assert!(manager.resolve_commit("HEAD~5").is_err());
+
+        let path = manager.restore_to_worktree(&commit)?;
+        assert!(path.starts_with(data_dir.join("worktrees")));
+        assert_eq!(fs::read_to_string(path.join("main.txt"))?, "v1");
+        assert_eq!(fs::read_to_string(workspace.join("main.txt"))?, "v2");
+        // The worktree sits in the data dir, so it never ends up in a snapshot.
+        let next = manager.snapshot(3)?;
+        assert!(manager.commit_name_status(&next)?.is_empty());
+
+        // Retention removes the oldest worktree, from git's list as well.
+        let older = manager.worktrees_dir.join(format!("{}-20000101T000000", &commit[..12]));
+        manager.git_bare(&["worktree", "add", "--detach", &older.display().to_string(), &commit])?;
+        assert_eq!(manager.prune_worktrees(1), (1, vec![]));
+        assert!(!older.exists() && path.exists());
+        let listed = manager.git_bare(&["worktree", "list", "--porcelain"])?;
+        assert!(!listed.contains("20000101T000000"), "{}", listed);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_manager_with_scripted_git() -> Result<()> {
+        let (manager, git) = scripted_manager(&[("commit", Err("fatal: unable to write new index file"))]);
+        let err = manager.snapshot(3).unwrap_err().to_string();
+        assert!(err.contains("unable to write new index file"), "{}", err);
+        // The failed commit stops it before rev-parse.
+        let calls = git.calls.lock().unwrap().clone();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[1].ends_with("commit -m snapshot 3 --allow-empty"));
+
+        let (manager, _) = scripted_manager(&[
+            ("diff", Ok("M\tsrc/lib.rs\nA\tnew.txt\nD\tgone.txt\n")),
+            ("log", Ok("abc1234\x00snapshot 3\x002024-01-02T03:04:05+00:00\n")),
+            ("rev-parse", Err("")),
+        ]);
+        let plan = manager.restore_plan("abc")?;
+        assert_eq!(
+            plan.entries,
+            [
+                (RestoreAction::Overwrite, "src/lib.rs".to_string()),
+                (RestoreAction::Delete, "new.txt".to_string()),
+                (RestoreAction::Recreate, "gone.txt".to_string()),
+            ]
+        );
+        let (short, subject, time) = manager.commit_info("abc")?;
+        assert_eq!((short.as_str(), subject.as_str()), ("abc1234", "snapshot 3"));
+        assert_eq!(time.to_rfc3339(), "2024-01-02T03:04:05+00:00");
+        assert_eq!(manager.resolve_commit("nope").unwrap_err().to_string(), "no snapshot commit nope");
+        Ok(())
+    }
+
+    #[test]
+    fn diff_shortstat_counts_added_and_removed_lines() -> Result<()> {
+        assert_eq!(parse_shortstat(" 3 files changed, 42 insertions(+), 7 deletions(-)\n"), (42, 7));
+        assert_eq!(parse_shortstat(" 1 file changed, 1 insertion(+)\n"), (1, 0));
+        assert_eq!(parse_shortstat(" 2 files changed, 5 deletions(-)\n"), (0, 5));
+        assert_eq!(parse_shortstat(""), (0, 0));
+
+        let (manager, git) = scripted_manager(&[("diff", Ok(" 2 files changed, 10 insertions(+), 3 deletions(-)\n"))]);
+        assert_eq!(manager.diff_shortstat("aaa", "bbb")?, (10, 3));
+        assert_eq!(git.calls.lock().unwrap().clone(), ["diff --shortstat aaa bbb --"]);
+        Ok(())
+    }
+
+    #[test]
+    fn repo_stats_are_cached() -> Result<()> {
+        let (manager, git) = scripted_manager(&[("rev-list", Ok("14\n"))]);
+        assert_eq!(manager.cached_repo_stats(), None);
+        assert_eq!(manager.repo_stats(), Some((14, 0)));
+        assert_eq!(manager.clone().repo_stats(), Some((14, 0)));
+        assert_eq!(manager.cached_repo_stats(), Some((14, 0)));
+        assert_eq!(git.calls.lock().unwrap().len(), 1);
+        // A failure is cached too, rather than retried on every call.
+        let (manager, git) = scripted_manager(&[("rev-list", Err("fatal: not a git repository"))]);
+        assert_eq!(manager.repo_stats(), None);
+        assert_eq!(manager.repo_stats(), None);
+        assert_eq!(git.calls.lock().unwrap().len(), 1);
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(2_411_724), "2.3 MB");
+        Ok(())
+    }
+
+    #[test]
+    fn git_errors_are_summarized() {
+        let err = "git failed: warning: in the working copy of 'a.txt', LF will be replaced by CRLF\n\
+                   error: open(\"secret\"): Permission denied\n\
+                   fatal: adding files failed\n";
+        assert_eq!(git_error_summary(err), "git: error: open(\"secret\"): Permission denied");
+        assert_eq!(
+            git_error_summary("git failed: fatal: not a git repository: '/x'\n"),
+            "git: fatal: not a git repository: '/x'"
+        );
+        assert_eq!(git_error_summary("No such file or directory (os error 2)"), "git: No such file or directory (os error 2)");
+        assert_eq!(git_error_summary("git failed: "), "git: snapshot failed");
+    }
+
+    #[test]
+    fn snapshot_uses_configured_author() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        let data_dir = workspace.join(".cc-workbench");
+        fs::create_dir_all(&data_dir)?;
+        let mut manager = SnapshotManager::new(&workspace, &data_dir)?;
+        let default = manager.snapshot(1)?;
+        manager.set_author("Ada Lovelace", "ada@example.com");
+        let custom = manager.snapshot(2)?;
+
+        let author = |commit: &str| run_git_bare(&manager.git_dir, &["log", "-1", "--format=%an <%ae>", commit], None);
+        assert_eq!(author(&default)?.trim(), "ccwb <ccwb@local>");
+        assert_eq!(author(&custom)?.trim(), "Ada Lovelace <ada@example.com>");
+        Ok(())
+    }
+
+    #[test]
+    fn export_patch_writes_raw_diff() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        let data_dir = workspace.join(".cc-workbench");
+        fs::create_dir_all(&data_dir)?;
+        let manager = SnapshotManager::new(&workspace, &data_dir)?;
+
+        fs::write(workspace.join("main.txt"), "one\n")?;
+        let commit = manager.snapshot(1)?;
+        fs::write(workspace.join("main.txt"), "two\n")?;
+
+        let path = manager.export_patch(&commit, None)?;
+        assert_eq!(path, data_dir.join("diffs").join(format!("{}.patch", commit)));
+        assert_eq!(fs::read_to_string(&path)?, manager.diff_preview(&commit, None)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli_prune_flags() -> Result<()> {
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let cli = CliArgs::parse(args(&["ccwb", "--prune", "--keep-days", "30", "--", "--keep-days"]))?;
+        assert_eq!(cli.prune, Some(PruneCutoff::OlderThanDays(30)));
+        assert_eq!(cli.claude_args, vec!["--keep-days".to_string()]);
+        assert_eq!(cli.cwd, None);
+        let cli = CliArgs::parse(args(&["ccwb", "--cwd", "../other", "--", "--cwd", "x"]))?;
+        assert_eq!(cli.cwd, Some(PathBuf::from("../other")));
+        assert_eq!(cli.claude_args, args(&["--cwd", "x"]));
+        assert!(CliArgs::parse(args(&["ccwb", "--cwd"])).is_err());
+        let cli = CliArgs::parse(args(&["ccwb", "--initial-prompt", "fix it", "--", "-p", "other"]))?;
+        assert_eq!(cli.initial_prompt.as_deref(), Some("fix it"));
+        assert_eq!(cli.claude_args, args(&["-p", "other"]));
+        let cli = CliArgs::parse(args(&["ccwb", "--headless", "--until", "^done", "--timeout", "30"]))?;
+        assert!(cli.headless);
+        assert_eq!(cli.until.as_deref(), Some("^done"));
+        assert_eq!(cli.timeout, Some(30));
+        assert!(CliArgs::parse(args(&["ccwb", "--until", "x"])).is_err());
+        assert!(CliArgs::parse(args(&["ccwb", "--prune"])).is_err());
+        assert!(CliArgs::parse(args(&["ccwb", "--keep-sessions", "3"])).is_err());
+        let cli = CliArgs::parse(args(&["ccwb", "--new-session", "--", "--new-session"]))?;
+        assert_eq!(cli.claude_args, ["--new-session"]);
+        Ok(())
+    }
+
+    #[test]
+    fn prune_sessions_removes_old_rows() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut db = Database::new(&tmp.path().join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        let old = (Utc::now() - chrono::Duration::days(40)).to_rfc3339();
+        for id in ["old-1", "old-2"] {
+            db.conn.execute(
+                "INSERT INTO sessions (id, workspace_id, created_at) VALUES (?1, ?2, ?3)",
+                params![id, ws, old],
+            )?;
+            db.insert_message(id, 1, "hi", 0)?;
+            db.insert_snapshot(id, 1, "abc")?;
+        }
+        let current = db.create_session(&ws)?;
+        db.insert_message(&current, 1, "keep me", 0)?;
+
+        assert_eq!(db.prune_sessions(&ws, &PruneCutoff::OlderThanDays(30))?, 2);
+        let count = |sql: &str| -> Result<i64> { Ok(db.conn.query_row(sql, [], |r| r.get(0))?) };
+        assert_eq!(count("SELECT COUNT(*) FROM sessions")?, 1);
+        assert_eq!(count("SELECT COUNT(*) FROM messages")?, 1);
+        assert_eq!(count("SELECT COUNT(*) FROM snapshots")?, 0);
+
+        assert_eq!(db.prune_sessions(&ws, &PruneCutoff::KeepNewest(0))?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn prune_drops_the_pruned_sessions_snapshots() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        let data_dir = workspace.join(".cc-workbench");
+        fs::create_dir_all(&data_dir)?;
+        let mut db = Database::new(&data_dir.join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(&workspace)?;
+        let mut manager = SnapshotManager::new(&workspace, &data_dir)?;
+        // Taken before snapshots were kept per session, on the default branch.
+        fs::write(workspace.join("notes.txt"), "v1")?;
+        let legacy = manager.snapshot(1)?;
+
+        // Noise doesn't compress, so dropping it shows on disk.
+        let mut seed = 1u64;
+        let noise: Vec<u8> = (0..256 * 1024)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (seed >> 56) as u8
+            })
+            .collect();
+        let old = (Utc::now() - chrono::Duration::days(40)).to_rfc3339();
+        db.conn.execute(
+            "INSERT INTO sessions (id, workspace_id, created_at) VALUES ('old', ?1, ?2)",
+            params![ws, old],
+        )?;
+        manager.start_session("old")?;
+        fs::write(workspace.join("noise.bin"), &noise)?;
+        let doomed = manager.snapshot(1)?;
+        db.insert_snapshot("old", 1, &doomed)?;
+
+        let current = db.create_session(&ws)?;
+        manager.start_session(&current)?;
+        fs::remove_file(workspace.join("noise.bin"))?;
+        fs::write(workspace.join("notes.txt"), "v2")?;
+        let kept = manager.snapshot(1)?;
+        db.insert_snapshot(&current, 1, &kept)?;
+        // A session's first snapshot is compared with the one before it.
+        assert_eq!(manager.commit_stats(&kept).0, Some(2));
+
+        assert_eq!(db.prune_sessions(&ws, &PruneCutoff::KeepNewest(1))?, 1);
+        let before = dir_size(&manager.git_dir);
+        let freed = manager.gc(&db.session_ids(&ws)?, &db.snapshot_commits(&ws)?)?;
+        assert!(freed > 128 * 1024, "freed {} bytes", freed);
+        assert_eq!(dir_size(&manager.git_dir), before - freed);
+        assert_eq!(manager.list_commits()?, HashSet::from([kept.clone()]));
+        assert!(manager.resolve_commit(&doomed).is_err());
+        assert!(manager.resolve_commit(&legacy).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn migrations_are_idempotent_and_versioned() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("ccwb.sqlite");
+        {
+            // A pre-versioning database: tables exist but user_version is 0.
+            let conn = Connection::open(&path)?;
+            conn.execute_batch(MIGRATIONS[0])?;
+        }
+        let db = Database::new(&path)?;
+        assert_eq!(db.schema_version()?, MIGRATIONS.len());
+        drop(db);
+        let db = Database::new(&path)?;
+        assert_eq!(db.schema_version()?, MIGRATIONS.len());
+
+        db.conn.pragma_update(None, "user_version", (MIGRATIONS.len() + 1) as i64)?;
+        drop(db);
+        assert!(Database::new(&path).is_err());
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+    #[test]
+    fn session_token_totals_sum_turns() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut db = Database::new(&tmp.path().join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        let session = db.create_session(&ws)?;
+        let first = db.insert_message(&session, 1, "abcdefgh", 2)?;
+        db.insert_message(&session, 2, "abcd", 1)?;
+        db.set_output_tokens(&first, 10)?;
+        assert_eq!(db.session_token_totals(&session)?, (3, 10));
+        assert_eq!(db.session_token_totals("missing")?, (0, 0));
+        Ok(())
+    }
 
     #[test]
-    fn test_estimate_tokens() {
-        assert_eq!(estimate_tokens(""), 0);
-        assert_eq!(estimate_tokens("abcd"), 1);
-        assert_eq!(estimate_tokens("abcdefgh"), 2);
+    fn trivial_prompts_skip_snapshots() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut db = Database::new(&tmp.path().join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        let mut app = test_app_with(test_config_json(
+            r#"{"snapshot_min_chars": 4, "snapshot_skip_patterns": ["(?i)^continue$"]}"#,
+        )?);
+        app.session_id = db.create_session(&ws)?;
+        let (tx, jobs) = mpsc::channel();
+        app.snapshot_job_tx = tx;
+        app.snapshots_enabled = true;
+        for prompt in ["yes", "Continue", "fix the parser"] {
+            app.record_user_message(&mut db, prompt.to_string(), 0)?;
+        }
+        let queued: Vec<i64> = jobs.try_iter().map(|job| job.message_idx).collect();
+        assert_eq!(queued, [3]);
+        let skipped: Vec<bool> = app.messages.iter().map(|m| m.snapshot_skipped).collect();
+        assert_eq!(skipped, [true, true, false]);
+        assert_eq!(app.in_flight_snapshots, 1);
+        Ok(())
     }
 
     #[test]
-    fn snapshot_and_restore() -> Result<()> {
+    fn take_snapshot_asks_before_replacing_the_turn_snapshot() {
+        let mut app = test_app();
+        let (tx, jobs) = mpsc::channel();
+        app.snapshot_job_tx = tx;
+        app.snapshots_enabled = false;
+        app.take_snapshot(0);
+        app.snapshots_enabled = true;
+        app.take_snapshot(0);
+        // Skipped prompts can still be snapshotted by hand.
+        app.messages.push(MessageEntry { snapshot_skipped: true, ..message(1, 0) });
+        app.take_snapshot(0);
+        let queued: Vec<(i64, bool)> = jobs.try_iter().map(|job| (job.message_idx, job.after_turn)).collect();
+        assert_eq!(queued, [(1, true)]);
+        assert!(app.output_lines.iter().any(|l| l.ends_with("Snapshots are off: git unavailable")));
+
+        // Once the turn has its own after-snapshot, the first ask only warns.
+        app.messages.push(MessageEntry { snapshot_commit_after: Some("abc".to_string()), ..message(2, 0) });
+        app.take_snapshot(1);
+        assert_eq!(jobs.try_iter().count(), 0);
+        assert!(app.output_lines.iter().any(|l| l.contains("snapshot again to replace it")));
+        // Asking about another message in between starts over.
+        app.take_snapshot(0);
+        app.take_snapshot(1);
+        let queued: Vec<i64> = jobs.try_iter().map(|job| job.message_idx).collect();
+        assert_eq!(queued, [1]);
+        app.take_snapshot(1);
+        let queued: Vec<i64> = jobs.try_iter().map(|job| job.message_idx).collect();
+        assert_eq!(queued, [2]);
+    }
+
+    #[test]
+    fn history_says_why_snapshots_are_off() -> Result<()> {
+        let mut app = test_app();
+        app.snapshots_enabled = false;
+        app.snapshots_off = "Permission denied".to_string();
+        let mut terminal = Terminal::new(TestBackend::new(60, 6))?;
+        terminal.draw(|f| draw_history_panel(f, &mut app, f.size()))?;
+        let buffer = terminal.backend().buffer();
+        let bottom: String = (0..60).map(|x| buffer.get(x, 5).symbol.clone()).collect();
+        assert!(bottom.contains("snapshots off: Permission denied"), "{}", bottom);
+        Ok(())
+    }
+
+    #[test]
+    fn stored_prompts_are_truncated() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut db = Database::new(&tmp.path().join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        let session = db.create_session(&ws)?;
+        let mut app = test_app_with(test_config_json(r#"{"max_stored_content": 5}"#)?);
+        app.session_id = session.clone();
+        for prompt in ["héllö wörld", "short"] {
+            app.record_user_message(&mut db, prompt.to_string(), 0)?;
+        }
+        assert_eq!(app.messages[0].content, "héllö wörld");
+        let turns = db.load_transcript(&session)?.turns;
+        let stored: Vec<&str> = turns.iter().map(|t| t.user.as_str()).collect();
+        assert_eq!(stored, ["héllö…", "short"]);
+        assert_eq!(turns[0].input_tokens, app.estimate_tokens("héllö wörld"));
+        Ok(())
+    }
+
+    #[test]
+    fn turn_end_snapshot_diffs_the_turn() -> Result<()> {
         let tmp = TempDir::new()?;
         let workspace = tmp.path().join("ws");
-        fs::create_dir_all(&workspace)?;
         let data_dir = workspace.join(".cc-workbench");
         fs::create_dir_all(&data_dir)?;
+        let mut db = Database::new(&data_dir.join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(&workspace)?;
+        let session = db.create_session(&ws)?;
         let manager = SnapshotManager::new(&workspace, &data_dir)?;
+        let mut app = test_app_with(test_config_json(r#"{"snapshot_after_turn": true}"#)?);
+        app.session_id = session.clone();
+        app.snapshot_manager = manager.clone();
+        let (tx, jobs) = mpsc::channel();
+        app.snapshot_job_tx = tx;
+        app.snapshots_enabled = true;
+        fs::write(workspace.join("main.txt"), "before\n")?;
+        app.record_user_message(&mut db, "edit main.txt".to_string(), 0)?;
+        app.finalize_last_turn(&mut db)?;
+        // Finalizing again (e.g. on exit) doesn't queue another one.
+        app.finalize_last_turn(&mut db)?;
+        let queued: Vec<bool> = jobs.try_iter().map(|job| job.after_turn).collect();
+        assert_eq!(queued, [false, true]);
 
-        let file = workspace.join("main.txt");
-        fs::write(&file, "hello")?;
-        let commit1 = manager.snapshot(1)?;
+        let id = app.messages[0].id.clone();
+        let before = manager.snapshot(1)?;
+        fs::write(workspace.join("main.txt"), "after\n")?;
+        let after = manager.snapshot(1)?;
+        for (commit, after_turn) in [(&before, false), (&after, true)] {
+            let res = SnapshotResult {
+                message_id: id.clone(),
+                after_turn,
+                commit: Some(commit.clone()),
+                ..Default::default()
+            };
+            app.update_snapshot(&mut db, res)?;
+        }
+        assert_eq!(app.messages[0].snapshot_commit.as_ref(), Some(&before));
+        assert_eq!(app.messages[0].snapshot_commit_after.as_ref(), Some(&after));
+        assert_eq!(db.load_transcript(&session)?.turns[0].snapshot_after.as_ref(), Some(&after));
 
-        fs::write(&file, "hello world")?;
-        let _commit2 = manager.snapshot(2)?;
+        // Later edits show up against the workspace but not in the turn diff.
+        fs::write(workspace.join("main.txt"), "later\n")?;
+        open_diff_preview(&mut app, &before, Some(&after), false)?;
+        let preview = app.diff_preview.as_ref().unwrap();
+        assert!(preview.title.starts_with("Turn diff"));
+        assert!(preview.lines.iter().any(|l| l == "+after"));
+        assert!(!preview.lines.iter().any(|l| l == "+later"));
+        Ok(())
+    }
 
-        let diff = manager.diff_preview(&commit1)?;
-        assert!(diff.contains("hello world"));
+    #[test]
+    fn last_prompt_skips_compaction_markers() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut db = Database::new(&tmp.path().join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        let mut app = test_app();
+        app.session_id = db.create_session(&ws)?;
+        let (tx, jobs) = mpsc::channel();
+        app.snapshot_job_tx = tx;
+        app.snapshots_enabled = true;
+        assert_eq!(app.last_prompt(), None);
+        app.record_user_message(&mut db, "run the tests".to_string(), 0)?;
+        app.compactions.push(CompactionMarker { after: 1, output_line: 1, reclaimed: 1000 });
+        let prompt = app.last_prompt().unwrap();
+        assert_eq!(prompt, "run the tests");
+        // Re-sending records a new turn with its own snapshot.
+        app.record_user_message(&mut db, prompt, 2)?;
+        let queued: Vec<i64> = jobs.try_iter().map(|job| job.message_idx).collect();
+        assert_eq!(queued, [1, 2]);
+        Ok(())
+    }
 
-        manager.restore(&commit1)?;
-        let contents = fs::read_to_string(&file)?;
-        assert_eq!(contents, "hello");
+    #[test]
+    fn idle_assistant_finalizes_the_turn() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut db = Database::new(&tmp.path().join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        let session = db.create_session(&ws)?;
+        let mut app = test_app();
+        app.session_id = session.clone();
+        let idle = app.config.assistant_idle;
+
+        app.record_user_message(&mut db, "fix it".to_string(), 0)?;
+        // No answer yet: nothing to finalize, however long we wait.
+        app.check_turn_idle(&mut db, Instant::now() + idle * 2)?;
+        assert!(app.turn_started.is_some());
+
+        app.handle_output(OutputChunk { text: "fixed\n".to_string(), stream: OutputStream::Stdout });
+        let output = app.last_output.unwrap();
+        app.check_turn_idle(&mut db, output + idle / 2)?;
+        assert!(app.turn_started.is_some());
+        app.check_turn_idle(&mut db, output + idle)?;
+        assert!(app.turn_started.is_none());
+
+        let turn = &db.load_transcript(&session)?.turns[0];
+        assert_eq!(turn.assistant, "fixed\n");
+        assert!(turn.duration_ms.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn transcript_includes_latest_snapshot_per_turn() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut db = Database::new(&tmp.path().join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        assert_eq!(db.latest_session(&ws)?, None);
+        let session = db.create_session(&ws)?;
+        let first = db.insert_message(&session, 1, "fix the build", 0)?;
+        db.insert_message(&session, 2, "now add tests", 0)?;
+        db.set_output_tokens(&first, 42)?;
+        db.set_assistant_text(&first, "done, tests pass")?;
+        db.insert_snapshot(&session, 1, "aaa")?;
+        assert_eq!(db.latest_session(&ws)?.as_deref(), Some(session.as_str()));
+
+        let transcript = db.load_transcript(&session)?;
+        assert_eq!(transcript.turns.len(), 2);
+        assert_eq!(transcript.turns[0].user, "fix the build");
+        assert_eq!(transcript.turns[0].output_tokens, 42);
+        assert_eq!(transcript.turns[0].assistant, "done, tests pass");
+        assert_eq!(transcript.turns[0].snapshot_commit.as_deref(), Some("aaa"));
+        assert_eq!(transcript.turns[1].snapshot_commit, None);
+        let json: serde_json::Value = serde_json::to_value(&transcript)?;
+        assert_eq!(json["turns"][1]["user"], "now add tests");
+        assert!(db.load_transcript("missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_listing_is_tab_separated() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut db = Database::new(&tmp.path().join("ccwb.sqlite"))?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        let other = db.ensure_workspace(&tmp.path().join("other"))?;
+        let first = db.create_session(&ws)?;
+        db.insert_snapshot(&first, 1, "aaa")?;
+        db.insert_snapshot(&first, 2, "gone")?;
+        let second = db.create_session(&ws)?;
+        db.insert_snapshot(&second, 1, "bbb")?;
+        let elsewhere = db.create_session(&other)?;
+        db.insert_snapshot(&elsewhere, 1, "ccc")?;
+
+        let rows = db.list_snapshots(&ws)?;
+        assert_eq!(rows.len(), 3);
+        let commits: HashSet<String> = ["aaa", "bbb", "ccc"].iter().map(|c| c.to_string()).collect();
+        let listing = snapshot_listing(&rows, &commits);
+        let lines: Vec<Vec<&str>> = listing.lines().map(|l| l.split('\t').collect()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!((lines[0][0], lines[0][1], lines[0][3]), ("aaa", "1", first.as_str()));
+        assert_eq!((lines[1][0], lines[1][1], lines[1][3]), ("bbb", "1", second.as_str()));
+        assert_eq!(lines[0][2], rows[0].created_at);
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_connections_do_not_lock() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("ccwb.sqlite");
+        let mut db = Database::new(&path)?;
+        let ws = db.ensure_workspace(tmp.path())?;
+        let session = db.create_session(&ws)?;
+        let journal: String = db.conn.query_row("PRAGMA journal_mode", [], |r| r.get(0))?;
+        assert_eq!(journal, "wal");
+
+        let handles: Vec<_> = (0..2)
+            .map(|worker| {
+                let path = path.clone();
+                let session = session.clone();
+                thread::spawn(move || -> Result<()> {
+                    let mut db = Database::new(&path)?;
+                    for i in 0..50 {
+                        db.insert_message(&session, worker * 100 + i, "concurrent", 0)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().expect("worker panicked")?;
+        }
+        let count: i64 = db.conn.query_row("SELECT COUNT(*) FROM messages", [], |r| r.get(0))?;
+        assert_eq!(count, 100);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_excludes_data_dir_inside_workspace() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        let data_dir = workspace.join("ccwb-data");
+        let workspace_data_dir = data_dir.join("workspaces").join("abc");
+        fs::create_dir_all(&workspace_data_dir)?;
+        fs::write(data_dir.join("ccwb.sqlite"), "db")?;
+        let mut manager = SnapshotManager::new(&workspace, &workspace_data_dir)?;
+        manager.exclude_path(&data_dir);
+        fs::write(workspace.join("main.txt"), "hello")?;
+        manager.snapshot(1)?;
+        let files = run_git(&workspace, &manager.git_dir, &["ls-tree", "-r", "--name-only", "HEAD"], None)?;
+        assert_eq!(files.trim(), "main.txt");
+        Ok(())
+    }
+
+    #[test]
+    fn applescript_strings_escape_quotes_and_drop_control_chars() {
+        assert_eq!(applescript_string(r#"say "hi" \ bye"#), r#""say \"hi\" \\ bye""#);
+        assert_eq!(applescript_string("a\u{1b}[1mb\nc"), "\"a [1mb c\"");
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let ws = Path::new("/work/proj");
+        assert_eq!(resolve_path(ws, "/abs/data"), PathBuf::from("/abs/data"));
+        assert_eq!(resolve_path(ws, "data"), PathBuf::from("/work/proj/data"));
+    }
+
+    #[test]
+    fn build_command_applies_config_env() {
+        env::set_var("CCWB_TEST_TOKEN", "secret");
+        let mut extra = HashMap::new();
+        extra.insert("ANTHROPIC_BASE_URL".to_string(), "http://localhost:8080".to_string());
+        extra.insert("AUTH".to_string(), "Bearer ${CCWB_TEST_TOKEN}".to_string());
+        extra.insert("UNSET".to_string(), "${CCWB_TEST_MISSING}x ${open".to_string());
+        let command = build_command("claude", &["--help".to_string()], &extra, Path::new("/work/proj"));
+        assert_eq!(
+            command.get_env("ANTHROPIC_BASE_URL").and_then(|v| v.to_str()),
+            Some("http://localhost:8080")
+        );
+        assert_eq!(command.get_env("AUTH").and_then(|v| v.to_str()), Some("Bearer secret"));
+        assert_eq!(command.get_env("UNSET").and_then(|v| v.to_str()), Some("x ${open"));
+        assert_eq!(command.get_argv().len(), 2);
+    }
+
+    #[test]
+    fn probe_claude_reports_broken_commands() {
+        let env = HashMap::new();
+        let timeout = Duration::from_secs(5);
+        let missing = probe_claude("ccwb-no-such-claude", &[], &env, timeout).unwrap_err();
+        assert!(missing.to_string().contains("not found"));
+        let failing = probe_claude("false", &[], &env, timeout).unwrap_err();
+        assert!(failing.to_string().contains("failed"));
+        // A wrapper's own arguments come before `--version`.
+        let prefix = ["-c".to_string(), "echo 2.1.0 \"$0\"".to_string()];
+        assert_eq!(probe_claude("sh", &prefix, &env, timeout).unwrap(), "2.1.0");
+    }
+
+    #[test]
+    fn debug_log_gate_and_key_redaction() {
+        assert!(!debug_log_requested(None, None));
+        assert!(debug_log_requested(Some("DEBUG"), None));
+        assert!(debug_log_requested(None, Some("cc_workbench=trace")));
+        // CCWB_LOG wins over RUST_LOG.
+        assert!(!debug_log_requested(Some("off"), Some("debug")));
+        assert!(!debug_log_requested(None, Some("warn")));
+
+        let key = |code, modifiers| key_summary(&KeyEvent::new(code, modifiers));
+        assert_eq!(key(KeyCode::Char('s'), KeyModifiers::NONE), "Char");
+        assert_eq!(key(KeyCode::Char('S'), KeyModifiers::SHIFT), "Char");
+        assert_eq!(key(KeyCode::Char('y'), KeyModifiers::CONTROL), "KeyModifiers(CONTROL)+Char('y')");
+        assert_eq!(key(KeyCode::F(5), KeyModifiers::NONE), "F(5)");
+    }
+
+    #[test]
+    fn separate_stderr_tags_chunks() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let args = vec!["-c".to_string(), "echo out; echo err >&2".to_string()];
+        let mut pty = PtyProcess::spawn("sh", &args, &HashMap::new(), Path::new("/"), OutputEncoding::Utf8Lossy, true, tx)?;
+        assert!(pty.stderr_separated);
+        pty.wait()?;
+        let mut out = String::new();
+        let mut err = String::new();
+        while let Ok(chunk) = rx.recv_timeout(Duration::from_secs(5)) {
+            match chunk.stream {
+                OutputStream::Stdout => out.push_str(&chunk.text),
+                OutputStream::Stderr => err.push_str(&chunk.text),
+            }
+            if out.contains("out") && err.contains("err") {
+                break;
+            }
+        }
+        assert_eq!((out.trim(), err.as_str()), ("out", "err\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn headless_stops_on_match_or_exit() -> Result<()> {
+        let run = |script: &str, until: Option<&str>| -> Result<(i32, String)> {
+            let (tx, rx) = mpsc::channel();
+            let args = vec!["-c".to_string(), script.to_string()];
+            let mut pty = PtyProcess::spawn("sh", &args, &HashMap::new(), Path::new("/"), OutputEncoding::Utf8Lossy, false, tx)?;
+            let until = until.map(Pattern::new).transpose()?;
+            let mut out = Vec::new();
+            let code = run_headless(&mut pty, &rx, None, until.as_ref(), Duration::from_secs(10), &mut out)?;
+            Ok((code, String::from_utf8(out)?))
+        };
+        let (code, out) = run("printf 'one\\ntwo\\n'; sleep 30", Some("^tw"))?;
+        assert_eq!(code, 0);
+        assert_eq!(out, "one\ntwo\n");
+        let (code, out) = run("echo hi; exit 3", None)?;
+        assert_eq!(code, 3);
+        assert_eq!(out.trim_end(), "hi");
+        let (code, _) = run("echo hi", Some("never"))?;
+        assert_eq!(code, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn build_command_runs_in_workspace() {
+        let command = build_command("claude", &[], &HashMap::new(), Path::new("/work/proj"));
+        assert_eq!(command.get_cwd().and_then(|v| v.to_str()), Some("/work/proj"));
+    }
+
+    #[test]
+    fn explicit_config_must_load() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("experiment.json");
+        fs::write(&path, r#"{"context_limit": 1234}"#)?;
+        assert_eq!(Config::load(tmp.path(), Some(&path))?.context_limit, 1234);
+        assert!(Config::load(tmp.path(), Some(&tmp.path().join("missing.json"))).is_err());
+        fs::write(&path, "{not json")?;
+        assert!(Config::load(tmp.path(), Some(&path)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn panels_config_drives_workbench_layout() -> Result<()> {
+        let panels = test_config_json(r#"{"panels": ["usage", "history", "usage"]}"#)?.panels;
+        assert_eq!(panels, vec![Panel::Usage, Panel::History]);
+        assert_eq!(panel_constraints(&panels), vec![Constraint::Length(7), Constraint::Min(5)]);
+        assert_eq!(
+            panel_constraints(&[Panel::Usage, Panel::Context]),
+            vec![Constraint::Length(7), Constraint::Min(7)]
+        );
+        assert!(test_config_json(r#"{"panels": []}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn workspace_config_overlays_home() -> Result<()> {
+        let home: ConfigFile = serde_json::from_str(
+            r#"{"context_limit": 100000, "usage_poll_seconds": 10,
+                "providers": [{"type": "manual", "name": "claude", "limit_tokens": 10, "used_tokens": 1}]}"#,
+        )?;
+        let local: ConfigFile = serde_json::from_str(
+            r#"{"context_limit": 50000,
+                "providers": [{"type": "local", "name": "ws"}]}"#,
+        )?;
+        let merged = home.overlay(local);
+        assert_eq!(merged.context_limit, Some(50000));
+        assert_eq!(merged.usage_poll_seconds, Some(10));
+        assert_eq!(merged.providers.map(|p| p.len()), Some(1));
+
+        let home: ConfigFile = serde_json::from_str(
+            r#"{"providers": [{"type": "manual", "name": "claude", "limit_tokens": 10, "used_tokens": 1}]}"#,
+        )?;
+        let local: ConfigFile = serde_json::from_str(
+            r#"{"providers_mode": "append", "providers": [{"type": "local", "name": "ws"}]}"#,
+        )?;
+        let providers = home.overlay(local).providers.unwrap_or_default();
+        assert_eq!(providers.len(), 2);
+        assert!(matches!(&providers[0], ProviderConfig::Manual { name, .. } if name == "claude"));
+
+        let home: ConfigFile = serde_json::from_str(r#"{"title": "home"}"#)?;
+        let merged = home.overlay(serde_json::from_str(r#"{"providers_mode": "append"}"#)?);
+        assert_eq!(merged.title.as_deref(), Some("home"));
+        assert!(merged.providers.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn local_provider_threshold_overrides_global() {
+        let parsed: ConfigFile = serde_json::from_str(
+            r#"{"compress_threshold": 0.85, "providers": [
+                {"type": "manual", "name": "m", "limit_tokens": 1, "used_tokens": 0},
+                {"type": "local", "compress_threshold": 0.6}
+            ]}"#,
+        )
+        .unwrap();
+        let mut config = test_config();
+        config.providers.clear();
+        config.compress_threshold = parsed.compress_threshold.unwrap();
+        assert_eq!(config.effective_compress_threshold(), 0.85);
+        config.providers = parsed.providers.unwrap();
+        assert_eq!(config.effective_compress_threshold(), 0.6);
+    }
+
+    #[test]
+    fn metrics_endpoint_serves_json_and_prometheus() -> Result<()> {
+        let shared = Arc::new(Mutex::new(MetricsSnapshot {
+            context_tokens: 42,
+            context_limit: 1000,
+            providers: vec![UsageEntry {
+                name: "glm \"pro\"".to_string(),
+                used: Some(5),
+                limit: Some(10),
+                status: None,
+                input_tokens: None,
+                output_tokens: None,
+                fetched_at: None,
+            }],
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        spawn_metrics_server(listener, Arc::clone(&shared));
+
+        let fetch = |path: &str| -> Result<String> {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        let prom = fetch("/metrics")?;
+        assert!(prom.starts_with("HTTP/1.1 200 OK"));
+        assert!(prom.contains("ccwb_context_tokens 42\n"));
+        assert!(prom.contains("ccwb_provider_used_tokens{provider=\"glm \\\"pro\\\"\"} 5\n"));
+
+        let json = fetch("/json")?;
+        let body = json.split("\r\n\r\n").nth(1).unwrap_or_default();
+        let parsed: serde_json::Value = serde_json::from_str(body)?;
+        assert_eq!(parsed["context_tokens"], 42);
+        assert_eq!(parsed["providers"][0]["limit"], 10);
+
+        assert!(fetch("/nope")?.starts_with("HTTP/1.1 404"));
+        Ok(())
+    }
+
+    #[test]
+    fn threshold_notifies_once_per_crossing() {
+        let entry = |used| UsageEntry {
+            name: "claude".to_string(),
+            used: Some(used),
+            limit: Some(100),
+            status: None,
+            input_tokens: None,
+            output_tokens: None,
+            fetched_at: None,
+        };
+        let mut notified = HashSet::new();
+        assert!(crossed_threshold(&[entry(50)], 0.9, &mut notified).is_empty());
+        assert_eq!(crossed_threshold(&[entry(95)], 0.9, &mut notified).len(), 1);
+        assert!(crossed_threshold(&[entry(99)], 0.9, &mut notified).is_empty());
+        assert!(crossed_threshold(&[entry(10)], 0.9, &mut notified).is_empty());
+        assert_eq!(crossed_threshold(&[entry(90)], 0.9, &mut notified).len(), 1);
+    }
+
+    #[test]
+    fn provider_health_reports_edges_once() {
+        let mut healthy = HashMap::new();
+        let poll = |state: Option<Result<(), String>>| vec![("glm".to_string(), state)];
+        let err = || Some(Err("HTTP 401".to_string()));
+        // Failing before the first success is not an edge.
+        assert!(health_transitions(poll(err()), &mut healthy).is_empty());
+        assert!(health_transitions(poll(Some(Ok(()))), &mut healthy).is_empty());
+        assert_eq!(
+            health_transitions(poll(err()), &mut healthy),
+            vec![ProviderHealth::Failing { name: "glm".to_string(), error: "HTTP 401".to_string() }]
+        );
+        assert!(health_transitions(poll(err()), &mut healthy).is_empty());
+        assert!(health_transitions(poll(None), &mut healthy).is_empty());
+        assert_eq!(
+            health_transitions(poll(Some(Ok(()))), &mut healthy),
+            vec![ProviderHealth::Recovered { name: "glm".to_string() }]
+        );
+    }
+
+    #[test]
+    fn offline_mode_skips_http_polling() -> Result<()> {
+        let mut manager = UsageManager::new(&test_config_json(
+            r#"{"offline": true, "providers": [
+                {"type": "manual", "name": "claude", "limit_tokens": 10, "used_tokens": 1},
+                {"type": "httpjson", "name": "glm", "url": "http://127.0.0.1:9/",
+                 "used_pointer": "/used", "limit_pointer": "/limit"}]}"#,
+        )?);
+        let statuses = |m: &UsageManager| m.entries(0, 0).into_iter().map(|e| e.status).collect::<Vec<_>>();
+        assert_eq!(statuses(&manager), [None, Some("offline".to_string())]);
+        assert!(!manager.toggle_offline());
+        assert_ne!(statuses(&manager)[1].as_deref(), Some("offline"));
+        assert!(manager.toggle_offline());
+        assert_eq!(statuses(&manager)[1].as_deref(), Some("offline"));
+        assert!(manager.health_changes().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_now_fetches_out_of_band() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        // Reports how many requests it has served as `used`.
+        thread::spawn(move || {
+            for (served, stream) in listener.incoming().enumerate() {
+                let Ok(mut stream) = stream else { continue };
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request);
+                let body = format!(r#"{{"used": {}, "limit": 100}}"#, served + 1);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        let mut manager = UsageManager::new(&test_config_json(&format!(
+            r#"{{"usage_poll_seconds": 3600, "providers": [{{"type": "httpjson", "name": "api",
+                "url": "http://{}/", "used_pointer": "/used", "limit_pointer": "/limit"}}]}}"#,
+            addr
+        ))?);
+        let wait_for = |manager: &UsageManager, used: u64| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while manager.entries(0, 0)[0].used != Some(used) || manager.is_refreshing() {
+                assert!(Instant::now() < deadline, "no fetch #{}", used);
+                thread::sleep(Duration::from_millis(20));
+            }
+        };
+        wait_for(&manager, 1);
+        assert!(!manager.is_refreshing());
+        assert!(manager.refresh_now());
+        wait_for(&manager, 2);
+        manager.toggle_offline();
+        assert!(!manager.refresh_now());
+        Ok(())
+    }
+
+    #[test]
+    fn file_provider_is_reread_by_its_poller() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let file = serde_json::from_str(
+            r#"{"providers": [{"type": "file", "name": "cron", "path": "usage.json",
+                "used_pointer": "/used", "limit_pointer": "/limit"}]}"#,
+        )?;
+        let manager = UsageManager::new(&Config::from_file(tmp.path(), Some(file))?);
+        let entry = || {
+            let entry = manager.entries(0, 0).remove(0);
+            (entry.used, entry.limit, entry.status)
+        };
+        assert_eq!(entry(), (None, None, Some("unavailable".to_string())));
+        let usage = tmp.path().join("usage.json");
+        // Only the poller reads the file; wake it and wait for the change.
+        let reread = |contents: &str| -> Result<(Option<u64>, Option<u64>, Option<String>)> {
+            let before = entry();
+            fs::write(&usage, contents)?;
+            manager.wake.notify();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while entry() == before {
+                assert!(Instant::now() < deadline, "{} was not re-read", usage.display());
+                thread::sleep(Duration::from_millis(20));
+            }
+            Ok(entry())
+        };
+        assert_eq!(reread(r#"{"used": 12, "limit": "100"}"#)?, (Some(12), Some(100), None));
+        assert!(reread(r#"{"used": 12"#)?.2.unwrap().starts_with("bad JSON"));
+        assert_eq!(reread(r#"{"used": 12}"#)?.2.as_deref(), Some("missing /limit"));
+        Ok(())
+    }
+
+    #[test]
+    fn primary_provider_is_listed_first() -> Result<()> {
+        let providers = r#""providers": [
+            {"type": "local", "name": "local"},
+            {"type": "manual", "name": "claude", "limit_tokens": 10, "used_tokens": 1},
+            {"type": "manual", "name": "glm", "limit_tokens": 10, "used_tokens": 1},
+            {"type": "manual", "name": "glm", "limit_tokens": 20, "used_tokens": 2}]"#;
+        let names = |primary: &str| -> Result<Vec<String>> {
+            let config = test_config_json(&format!(r#"{{"primary_provider": "{}", {}}}"#, primary, providers))?;
+            let manager = UsageManager::new(&config);
+            Ok(manager.entries(0, 0).into_iter().map(|e| e.name).collect())
+        };
+        assert_eq!(names("claude")?, ["claude", "local", "glm", "glm"]);
+        // Unknown or ambiguous names keep the configured order.
+        assert_eq!(names("missing")?, ["local", "claude", "glm", "glm"]);
+        assert_eq!(names("glm")?, ["local", "claude", "glm", "glm"]);
+        Ok(())
+    }
+
+    #[test]
+    fn auto_context_limit_follows_primary_provider() -> Result<()> {
+        let limit = |config: &str| -> Result<u64> {
+            let app = test_app_with(test_config_json(config)?);
+            Ok(app.context_limit(&app.usage_entries()))
+        };
+        let manual = r#""providers": [{"type": "manual", "name": "glm", "limit_tokens": 128000, "used_tokens": 1}]"#;
+        assert_eq!(limit(&format!(r#"{{"auto_context_limit": true, {}}}"#, manual))?, 128_000);
+        assert_eq!(limit(&format!(r#"{{"context_limit": 1000, {}}}"#, manual))?, 1000);
+        // A provider with nothing to report falls back to the config.
+        let file = r#""providers": [{"type": "file", "name": "f", "path": "missing.json",
+            "used_pointer": "/used", "limit_pointer": "/limit"}]"#;
+        assert_eq!(limit(&format!(r#"{{"auto_context_limit": true, "context_limit": 1000, {}}}"#, file))?, 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn usage_bar_fits_narrow_panels() -> Result<()> {
+        let mut app = test_app();
+        for width in 0..=3 {
+            let mut terminal = Terminal::new(TestBackend::new(width, 6))?;
+            terminal.draw(|f| draw_usage_panel(f, &mut app, f.size()))?;
+        }
+        let mut terminal = Terminal::new(TestBackend::new(12, 6))?;
+        terminal.draw(|f| draw_usage_panel(f, &mut app, f.size()))?;
+        // The bar row spans the inner width exactly, between the borders.
+        let buffer = terminal.backend().buffer();
+        let row: String = (0..12).map(|x| buffer.get(x, 2).symbol.clone()).collect();
+        assert_eq!(row, format!("│{}│", "░".repeat(10)));
+        Ok(())
+    }
+
+    #[test]
+    fn panel_padding_falls_back_when_it_does_not_fit() -> Result<()> {
+        let config = test_config_json(r#"{"workbench_padding": [2, 1]}"#)?;
+        assert_eq!(config.workbench_padding, Padding { left: 2, right: 2, top: 1, bottom: 1 });
+        assert_eq!(config.output_padding, Padding::zero());
+        assert_eq!(fit_padding(config.workbench_padding, Rect::new(0, 0, 7, 5)), config.workbench_padding);
+        assert_eq!(fit_padding(config.workbench_padding, Rect::new(0, 0, 6, 5)), Padding::zero());
+        assert_eq!(fit_padding(config.workbench_padding, Rect::new(0, 0, 7, 4)), Padding::zero());
+
+        let mut app = test_app_with(config);
+        let mut terminal = Terminal::new(TestBackend::new(12, 7))?;
+        terminal.draw(|f| draw_usage_panel(f, &mut app, f.size()))?;
+        let buffer = terminal.backend().buffer();
+        let row: String = (0..12).map(|x| buffer.get(x, 3).symbol.clone()).collect();
+        assert_eq!(row, format!("│  {}  │", "░".repeat(6)));
+        let mut terminal = Terminal::new(TestBackend::new(6, 7))?;
+        terminal.draw(|f| draw_usage_panel(f, &mut app, f.size()))?;
+        let buffer = terminal.backend().buffer();
+        let row: String = (0..6).map(|x| buffer.get(x, 2).symbol.clone()).collect();
+        assert_eq!(row, format!("│{}│", "░".repeat(4)));
+        Ok(())
+    }
+
+    #[test]
+    fn usage_view_reorders_and_formats() {
+        let entry = |name: &str| UsageEntry {
+            name: name.to_string(),
+            used: Some(50),
+            limit: Some(200),
+            status: None,
+            input_tokens: None,
+            output_tokens: None,
+            fetched_at: None,
+        };
+        let entries = vec![entry("local"), entry("glm"), entry("claude")];
+        let mut view = UsageView::default();
+        let names = |view: &UsageView| view.order(&entries).iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&view), ["local", "glm", "claude"]);
+        view.select_next(entries.len());
+        view.select_next(entries.len());
+        assert_eq!(names(&view), ["claude", "local", "glm"]);
+        view.select_next(entries.len());
+        assert_eq!(view.selected, 0);
+        // A provider list that shrank doesn't leave the selection dangling.
+        view.selected = 7;
+        assert_eq!(names(&view)[0], "claude");
+
+        assert_eq!(view.amount("glm", 50, 200), "glm: 50 / 200 tokens");
+        view.toggle_mode();
+        assert_eq!(view.amount("glm", 50, 200), "glm: 25.0%");
+    }
+
+    #[test]
+    fn usage_freshness_flags_stale_failures() {
+        let entry = |status: Option<&str>, fetched_at| UsageEntry {
+            name: "glm".to_string(),
+            used: Some(10),
+            limit: Some(100),
+            status: status.map(str::to_string),
+            input_tokens: None,
+            output_tokens: None,
+            fetched_at,
+        };
+        let ago = Instant::now() - Duration::from_secs(90);
+        assert_eq!(usage_freshness(&entry(None, Some(ago))).as_deref(), Some(" (1m ago)"));
+        assert_eq!(
+            usage_freshness(&entry(Some("HTTP 401"), Some(ago))).as_deref(),
+            Some(" (failing, last ok 1m ago)")
+        );
+        assert_eq!(usage_freshness(&entry(None, None)), None);
+    }
+
+    #[test]
+    fn input_echo_shows_the_typed_tail() {
+        let mut input = InputLine::default();
+        input.insert_str("fix\rthe tests");
+        assert_eq!(input.display(), "fix⏎the tests");
+        assert_eq!(input_line_text(&input.display(), 8), " tests");
+        assert_eq!(input_line_text("hi", 8), "hi");
+        assert_eq!(input_line_text("hi", 1), "");
+    }
+
+    #[test]
+    fn input_line_tracks_edits_and_pastes() {
+        let mut input = InputLine::default();
+        input.insert_str("helo");
+        input.move_left();
+        input.insert_str("l");
+        input.move_right();
+        input.insert_str(" world");
+        assert_eq!(input.take(), "hello world");
+
+        input.insert_str("abc");
+        input.backspace();
+        input.insert_str("line one\rline two");
+        assert_eq!(input.take(), "abline one\nline two");
+
+        input.insert_str("drop this keep");
+        for _ in 0..4 {
+            input.move_left();
+        }
+        input.kill_to_start();
+        assert_eq!(input.take(), "keep");
+
+        // A recalled prompt is unknown, not empty, until the line is reset.
+        input.recall();
+        assert!(input.chars.is_empty() && !input.is_empty());
+        input.clear();
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn submitted_line_strips_prompt_box() {
+        let lines = vec![
+            "previous".to_string(),
+            "│ > fix the tests   │".to_string(),
+            "   ".to_string(),
+        ];
+        assert_eq!(submitted_line_from_output(&lines).as_deref(), Some("fix the tests"));
+        assert_eq!(submitted_line_from_output(&[]), None);
+    }
+
+    #[test]
+    fn usage_color_bands() {
+        assert_eq!(usage_color(0.5, 0.85), Color::Green);
+        assert_eq!(usage_color(0.75, 0.85), Color::Yellow);
+        assert_eq!(usage_color(0.85, 0.85), Color::Red);
+        assert_eq!(usage_color(1.2, 0.85), Color::Red);
+    }
+
+    #[test]
+    fn usage_bar_changes_color_as_it_fills() {
+        let bar = |ratio| {
+            gradient_bar(20, ratio, 0.85)
+                .into_iter()
+                .map(|span| (span.content.chars().count(), span.style.fg))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bar(0.5), [(10, Some(Color::Green)), (10, Some(Color::DarkGray))]);
+        assert_eq!(
+            bar(1.3),
+            [(14, Some(Color::Green)), (3, Some(Color::Yellow)), (3, Some(Color::Red))]
+        );
+        assert_eq!(bar(0.0), [(20, Some(Color::DarkGray))]);
+    }
+
+    #[test]
+    fn scroll_indicator_formats_position() {
+        assert_eq!(scroll_indicator(10, 100, true), "[following]");
+        assert_eq!(scroll_indicator(41, 100, false), "[frozen 42/100 42%]");
+        assert_eq!(scroll_indicator(500, 100, false), "[frozen 100/100 100%]");
+    }
+
+    #[test]
+    fn scroll_target_clamps_to_pages() {
+        assert_eq!(scroll_target(99, -20, 20, 100), 79);
+        assert_eq!(scroll_target(25, -20, 20, 100), 19);
+        assert_eq!(scroll_target(19, isize::MIN, 20, 100), 19);
+        assert_eq!(scroll_target(90, 20, 20, 100), 99);
+        assert_eq!(scroll_target(0, isize::MAX, 20, 5), 4);
+        assert_eq!(scroll_target(0, -1, 20, 1), 0);
+    }
+
+    #[test]
+    fn palette_filters_actions() {
+        let mut palette = CommandPalette::new('x');
+        assert_eq!(palette.matches().len(), Action::ALL.len());
+        palette.query = "DIFF".to_string();
+        assert_eq!(palette.matches(), vec![Action::DiffSelected]);
+        palette.query = "f11".to_string();
+        assert_eq!(palette.matches(), vec![Action::ToggleFullscreen]);
+        // Quit is found under the configured key, not the default one.
+        palette.query = "ctrl+x".to_string();
+        assert_eq!(palette.matches(), vec![Action::Quit]);
+        palette.query = "ctrl+q".to_string();
+        assert!(palette.matches().is_empty());
+        palette.query = "nothing like this".to_string();
+        assert!(palette.matches().is_empty());
+    }
+
+    #[test]
+    fn workspace_lock_blocks_live_holders_only() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("ccwb.lock");
+        let lock = WorkspaceLock::acquire(&path)?;
+        assert_eq!(fs::read_to_string(&path)?, std::process::id().to_string());
+        let err = WorkspaceLock::acquire(&path).err().unwrap();
+        assert!(err.to_string().contains(&format!("pid {}", std::process::id())));
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path)?, "");
+        // A leftover pid without a lock on the file doesn't block anyone.
+        fs::write(&path, "999999999")?;
+        let _lock = WorkspaceLock::acquire(&path)?;
+        assert_eq!(fs::read_to_string(&path)?, std::process::id().to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn lock_comes_before_the_database() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let data = tmp.path();
+        assert_eq!(workspace_lock_path(data, Path::new("/a"), false), data.join("ccwb.lock"));
+        let shared = workspace_lock_path(data, Path::new("/a"), true);
+        assert!(shared.starts_with(data.join("locks")));
+        assert_eq!(shared, workspace_lock_path(data, Path::new("/a"), true));
+        assert_ne!(shared, workspace_lock_path(data, Path::new("/b"), true));
+
+        // Without the lock the database is only opened, never created.
+        let path = data.join("ccwb.sqlite");
+        assert!(Database::open_existing(&path).is_err());
+        assert!(!path.exists());
+        let id = Database::new(&path)?.ensure_workspace(Path::new("/a"))?;
+        let db = Database::open_existing(&path)?;
+        assert_eq!(db.find_workspace(Path::new("/a"))?, Some(id));
+        assert_eq!(db.find_workspace(Path::new("/b"))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn ui_state_round_trips_and_clamps() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("ui_state.json");
+        assert_eq!(UiState::load(&path), UiState::default());
+
+        let state = UiState {
+            fullscreen_output: true,
+            split_percent: 50,
+        };
+        state.save(&path)?;
+        assert_eq!(UiState::load(&path), state);
+
+        fs::write(&path, r#"{"split_percent": 1000}"#)?;
+        assert_eq!(UiState::load(&path).split_percent, 90);
+        fs::write(&path, "not json")?;
+        assert_eq!(UiState::load(&path), UiState::default());
+        Ok(())
+    }
+
+    #[test]
+    fn markdown_spans_style_common_cases() {
+        let text = |spans: &[Span]| spans.iter().map(|s| s.content.to_string()).collect::<Vec<_>>();
+        let heading = markdown_spans("## Plan");
+        assert_eq!(text(&heading), vec!["Plan"]);
+        assert!(heading[0].style.add_modifier.contains(Modifier::BOLD));
+
+        let bold = markdown_spans("run **cargo test** now **");
+        assert_eq!(text(&bold), vec!["run ", "cargo test", " now **"]);
+        assert!(bold[1].style.add_modifier.contains(Modifier::BOLD));
+
+        assert_eq!(text(&markdown_spans("#hashtag")), vec!["#hashtag"]);
+
+        let mut in_code = false;
+        assert!(code_block_spans("```rust", &mut in_code, 0).is_some());
+        assert!(in_code);
+        let inside = code_block_spans("# not a heading", &mut in_code, 20).unwrap();
+        assert_eq!(text(&inside), vec!["  # not a heading   "]);
+        assert_eq!(inside[0].style.bg, Some(Color::Indexed(236)));
+        code_block_spans("```", &mut in_code, 0);
+        assert!(!in_code);
+        assert!(code_block_spans("plain", &mut in_code, 20).is_none());
+    }
+
+    #[test]
+    fn prune_backups_keeps_newest() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let workspace = tmp.path().join("ws");
+        fs::create_dir_all(&workspace)?;
+        let manager = SnapshotManager::detached(&workspace, &tmp.path().join("data"));
+        assert_eq!(manager.prune_backups(2), (0, vec![]));
+        for name in ["20240101T000000", "20240102T000000", "20240103T000000", "20240104T000000"] {
+            fs::create_dir_all(manager.backup_dir.join(name).join("src"))?;
+        }
+        assert_eq!(manager.prune_backups(2), (2, vec![]));
+        let mut left: Vec<String> = fs::read_dir(&manager.backup_dir)?
+            .map(|e| Ok(e?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<_>>()?;
+        left.sort();
+        assert_eq!(left, vec!["20240103T000000", "20240104T000000"]);
+        assert_eq!(manager.prune_backups(0), (1, vec![]));
+        assert!(manager.backup_dir.join("20240104T000000").exists());
+        // The config can't ask for that: 0 is rejected rather than read as 1.
+        let err = test_config_json(r#"{"backup_retention": 0}"#).err().unwrap();
+        assert!(err.to_string().contains("at least 1"));
         Ok(())
     }
 